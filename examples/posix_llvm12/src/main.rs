@@ -7,11 +7,11 @@ use std::{
 };
 
 use inkwellkit::{
-    get_ctx, impl_fn_hdr, load_vm_common_ty,
+    dynvec::DynVec,
     module::{Linkage, Module},
     ret_as_bv,
     targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
-    AddressSpace, OptimizationLevel, VMMod,
+    OptimizationLevel, VMMod,
     // types::{ RetTypeEnum }
 };
 
@@ -23,8 +23,8 @@ fn action() -> Result<(), Box<dyn Error>> {
     VMMod::include_unistd(&vmmod.module);
     VMMod::include_fcntl(&vmmod.module);
 
-    let builder = VMMod::get_builder();
-    load_vm_common_ty!(get_ctx());
+    let builder = vmmod.builder();
+    let tys = vmmod.tys;
 
     // begin main
     let blk_main = vmmod.append_main();
@@ -33,13 +33,13 @@ fn action() -> Result<(), Box<dyn Error>> {
     // open it
     let fn_open = vmmod.module.get_function("open").unwrap();
     let (fns, _) = vmmod.build_local_str(&builder, "hello.txt");
-    let flags = i32_t.const_int(
+    let flags = tys.i32_t.const_int(
         (libc::O_CREAT | libc::O_WRONLY | libc::O_APPEND)
             .try_into()
             .unwrap(),
         true,
     );
-    let mode = i32_t.const_int(0o6666, false);
+    let mode = tys.i32_t.const_int(0o6666, false);
 
     let fd = ret_as_bv!(builder.build_call(fn_open, &[fns.into(), flags.into(), mode.into()], ""));
 
@@ -56,67 +56,24 @@ fn action() -> Result<(), Box<dyn Error>> {
 
     let fn_main = vmmod.module.get_function("main").unwrap();
 
-    let struct_vec_t = get_ctx().opaque_struct_type("dynvec");
-    let vec_t = struct_vec_t.ptr_type(AddressSpace::Generic);
-    // let vec_t = struct_vec_t;
-
-
     let module = &vmmod.module;
 
-    impl_fn_hdr![module|
-        vec_new_i32(i32) -> vec;
-        vec_push_i32(vec, i32) -> i32;
-        vec_get_i32(vec, i32) -> i32;
-    ];
-
-    // let fn_vec_new_i32_t = vec_t.fn_type(&[i32_t.into()], false);
-
-    // let fn_vec_push_i32_t = i32_t.fn_type(&[vec_t.into(), i32_t.into()], false);
-
-    // let fn_vec_get_i32_t = i32_t.fn_type(&[vec_t.into(), i32_t.into()], false);
-
-    // let fn_vec_new_i32 =
-    //     vmmod
-    //         .module
-    //         .add_function("vec_new_i32", fn_vec_new_i32_t, None);
-    // let fn_vec_push_i32 =
-    //     vmmod
-    //         .module
-    //         .add_function("vec_push_i32", fn_vec_push_i32_t, None);
-    // let fn_vec_get_i32 =
-    //     vmmod
-    //         .module
-    //         .add_function("vec_get_i32", fn_vec_get_i32_t, None);
-
-    let v = ret_as_bv!(builder.build_call(
-        vmmod.get_unchecked_fn("vec_new_i32"),
-        &[vmmod.i32(20).into()],
-        ""
-    ));
-    builder.build_call(
-        vmmod.get_unchecked_fn("vec_push_i32"),
-        &[v.into(), vmmod.i32(1).into()],
-        "",
-    );
-    builder.build_call(
-        vmmod.get_unchecked_fn("vec_push_i32"),
-        &[v.into(), vmmod.i32(4).into()],
-        "",
-    );
-    builder.build_call(
-        vmmod.get_unchecked_fn("vec_push_i32"),
-        &[v.into(), vmmod.i32(9).into()],
-        "",
-    );
+    let dynvec = DynVec::define(module, tys.i32_t.into(), true);
 
-    let get_0 = ret_as_bv!(builder.build_call(
-        vmmod.get_unchecked_fn("vec_get_i32"),
-        &[v.into(), vmmod.i32(0).into()],
-        ""
-    ));
+    let v = dynvec.build_new(module, &builder, vmmod.usize(20));
+    dynvec.build_push(module, &builder, v, vmmod.i32(1).into());
+    dynvec.build_push(module, &builder, v, vmmod.i32(4).into());
+    dynvec.build_push(module, &builder, v, vmmod.i32(9).into());
+
+    let get_0 = dynvec.build_get(module, &builder, v, vmmod.usize(0));
 
     vmmod.build_call_printf(&builder, "get0: %d\n", &[get_0.into()]);
 
+    let len = dynvec.build_len(module, &builder, v);
+    vmmod.build_call_printf(&builder, "len: %zu\n", &[len.into()]);
+
+    dynvec.build_free(module, &builder, v);
+
     // // TEST IF ELSE
     // // compare int res > 5
     // let write_ok_cast =
@@ -144,13 +101,13 @@ fn action() -> Result<(), Box<dyn Error>> {
     // close it
 
     // end main
-    builder.build_return(Some(i64_t.const_zero()));
+    builder.build_return(Some(tys.i64_t.const_zero()));
     fn_main.verify(true);
 
     print_obj(&vmmod.module, OptimizationLevel::None)?;
     println!("->: {}", module_name);
     let bin_output = module_name.to_owned() + ".out";
-    link(&bin_output, &["./output.o", "libbas.a"])?;
+    link(&bin_output, &["./output.o"])?;
     run_bin(&bin_output)
 }
 
@@ -0,0 +1,662 @@
+//! Tokenize/check quasi-quoted LLVM IR for `impl_ir`
+//!
+//! Newlines are dropped by Rust tokenizer, so each piece of IR is emitted on its own line
+//! together with its location in the invocation, which makes LLVM parse errors map back to it.
+//!
+//! A `;` comment runs to the end of its source line (it takes span locations, rustc 1.88+),
+//! Rust still lexes it, so a lone `'` doesn't fit there.
+//! Rust lexes the string literals first, so escapes of plain strings are Rust ones
+//! (translated to `\XX`), LLVM escapes go into raw strings as is, e.g. `cr"\5C\00"`.
+
+use std::collections::BTreeSet;
+
+use proc_macro2::{Delimiter, Literal, Span, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, quote_spanned};
+use syn::{Error, Result};
+
+
+enum Tok {
+    Ident(String, Span),
+    Lit(String, Span),
+    Punct(char, Span),
+    Open(char, Span),
+    Close(char, Span),
+    /// `#ident` | `#(expr)`
+    Interp(TokenStream2, Span),
+}
+
+impl Tok {
+    fn span(&self) -> Span {
+        match self {
+            Self::Ident(_, span)
+            | Self::Lit(_, span)
+            | Self::Punct(_, span)
+            | Self::Open(_, span)
+            | Self::Close(_, span)
+            | Self::Interp(_, span) => *span,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Self::Ident(s, _) | Self::Lit(s, _) => s.clone(),
+            Self::Punct(c, _) | Self::Open(c, _) | Self::Close(c, _) => {
+                c.to_string()
+            }
+            Self::Interp(..) => unreachable!(),
+        }
+    }
+
+    fn is_word(&self) -> bool {
+        matches!(self, Self::Ident(..) | Self::Lit(..))
+    }
+
+    fn is_punct(&self, ch: char) -> bool {
+        matches!(self, Self::Punct(c, _) if *c == ch)
+    }
+}
+
+/// `\XX` escaped LLVM string of `bytes`
+fn llvm_escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+
+    for &b in bytes {
+        if (b' '..=b'~').contains(&b) && b != b'"' && b != b'\\' {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("\\{:02X}", b));
+        }
+    }
+
+    escaped
+}
+
+/// Content of a plain Rust string literal (between the quotes) to bytes
+fn unescape_rust_str(content: &str, span: Span) -> Result<Vec<u8>> {
+    let err = || Error::new(span, "invalid escape in string literal");
+
+    let mut bytes = vec![];
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next().ok_or_else(err)? {
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            '0' => bytes.push(0),
+            '\\' => bytes.push(b'\\'),
+            '\'' => bytes.push(b'\''),
+            '"' => bytes.push(b'"'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                bytes.push(u8::from_str_radix(&hex, 16).map_err(|_| err())?);
+            }
+            'u' => {
+                let hex: String = chars
+                    .by_ref()
+                    .skip_while(|c| *c == '{')
+                    .take_while(|c| *c != '}')
+                    .collect();
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(err)?;
+
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            // line continuation
+            '\n' => {
+                while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            _ => return Err(err()),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Rust string literal to LLVM one, others are kept as is.
+///
+/// `"..."`, `c"..."`: Rust escapes are translated,
+/// `r"..."`, `cr#"..."#`: taken verbatim, so LLVM escapes pass through.
+fn llvm_lit(lit: &Literal) -> Result<String> {
+    let repr = lit.to_string();
+
+    let (prefix, rest) = match repr.strip_prefix('c') {
+        Some(rest) if rest.starts_with(['"', 'r']) => ("c", rest),
+        _ => ("", repr.as_str()),
+    };
+
+    if let Some(raw) = rest.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let content = &raw[hashes + 1..raw.len() - hashes - 1];
+
+        return Ok(format!("{}\"{}\"", prefix, content.replace('"', "\\22")));
+    }
+
+    if rest.starts_with('"') {
+        let bytes = unescape_rust_str(&rest[1..rest.len() - 1], lit.span())?;
+
+        return Ok(format!("{}\"{}\"", prefix, llvm_escape(&bytes)));
+    }
+
+    Ok(repr)
+}
+
+fn flatten(ts: TokenStream2, toks: &mut Vec<Tok>) -> Result<()> {
+    let mut iter = ts.into_iter().peekable();
+    // source line of the `;` comment being skipped
+    let mut comment_line = None;
+
+    while let Some(tt) = iter.next() {
+        let line = tt.span().start().line;
+
+        if comment_line == Some(line) {
+            continue;
+        }
+
+        match tt {
+            TokenTree::Punct(punct) if punct.as_char() == ';' => {
+                if line == 0 {
+                    return Err(Error::new(
+                        punct.span(),
+                        "`;` comment needs span locations (rustc 1.88+)",
+                    ));
+                }
+
+                comment_line = Some(line);
+            }
+            TokenTree::Ident(ident) => {
+                toks.push(Tok::Ident(ident.to_string(), ident.span()))
+            }
+            TokenTree::Literal(lit) => {
+                toks.push(Tok::Lit(llvm_lit(&lit)?, lit.span()))
+            }
+            TokenTree::Punct(punct) if punct.as_char() == '#' => {
+                match iter.peek() {
+                    Some(TokenTree::Ident(ident)) => {
+                        let ident = ident.clone();
+                        iter.next();
+
+                        toks.push(Tok::Interp(quote! { #ident }, ident.span()));
+                    }
+                    Some(TokenTree::Group(group))
+                        if group.delimiter() == Delimiter::Parenthesis =>
+                    {
+                        let expr = group.stream();
+                        let span = group.span();
+                        iter.next();
+
+                        toks.push(Tok::Interp(quote! { (#expr) }, span));
+                    }
+                    // attribute group `#0`
+                    Some(TokenTree::Literal(_)) => {
+                        toks.push(Tok::Punct('#', punct.span()))
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            punct.span(),
+                            "expect `#ident` or `#(expr)` to interpolate",
+                        ))
+                    }
+                }
+            }
+            TokenTree::Punct(punct) => {
+                toks.push(Tok::Punct(punct.as_char(), punct.span()))
+            }
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ('(', ')'),
+                    Delimiter::Brace => ('{', '}'),
+                    Delimiter::Bracket => ('[', ']'),
+                    Delimiter::None => {
+                        flatten(group.stream(), toks)?;
+                        continue;
+                    }
+                };
+
+                toks.push(Tok::Open(open, group.span_open()));
+                flatten(group.stream(), toks)?;
+                toks.push(Tok::Close(close, group.span_close()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+pub enum Piece {
+    Text(String, Span),
+    Interp(TokenStream2, Span),
+}
+
+impl Piece {
+    fn span(&self) -> Span {
+        match self {
+            Self::Text(_, span) | Self::Interp(_, span) => *span,
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text, _) => Some(text),
+            Self::Interp(..) => None,
+        }
+    }
+
+    fn is(&self, text: &str) -> bool {
+        self.text() == Some(text)
+    }
+
+    fn sigil_name(&self, sigil: char) -> Option<&str> {
+        self.text().and_then(|text| text.strip_prefix(sigil))
+    }
+
+    /// `%name` (not the numbered `%0`)
+    fn local_name(&self) -> Option<&str> {
+        self.sigil_name('%')
+            .filter(|name| !name.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    fn label_def(&self) -> Option<&str> {
+        self.text()
+            .and_then(|text| text.strip_suffix(':'))
+            .filter(|name| !name.is_empty() && !name.starts_with('%'))
+    }
+}
+
+/// Join tokens which mustn't be separated by whitespace in IR
+/// (`%x.addr`, `@llvm.memcpy.p0i8.p0i8.i64`, `-1`, `entry:`, `...`, `#0`)
+fn join_pieces(toks: Vec<Tok>) -> Vec<Piece> {
+    let mut pieces = vec![];
+    let mut cur: Option<(String, Span)> = None;
+    let mut prev: Option<Tok> = None;
+
+    for tok in toks {
+        if let Tok::Interp(expr, span) = tok {
+            if let Some((text, span)) = cur.take() {
+                pieces.push(Piece::Text(text, span));
+            }
+            pieces.push(Piece::Interp(expr, span));
+            prev = None;
+            continue;
+        }
+
+        let join = match (&prev, &cur) {
+            (Some(prev), Some((text, _))) => {
+                let sigil_piece = text.starts_with(['%', '@', '!', '$', '#']);
+
+                let after_sigil = ['%', '@', '!', '$', '#', '-']
+                    .iter()
+                    .any(|c| prev.is_punct(*c));
+
+                (after_sigil && tok.is_word())
+                    || (prev.is_punct('.') && (tok.is_word() || tok.is_punct('.')))
+                    || (prev.is_word() && tok.is_punct('.'))
+                    || (prev.is_word() && tok.is_punct(':'))
+                    || (sigil_piece && prev.is_word() && tok.is_punct('-'))
+                    // `c"..."` lexed apart before edition 2021
+                    || (matches!(prev, Tok::Ident(c, _) if c == "c")
+                        && matches!(&tok, Tok::Lit(lit, _) if lit.starts_with('"')))
+            }
+            _ => false,
+        };
+
+        if join {
+            cur.as_mut().unwrap().0.push_str(&tok.text());
+        } else {
+            if let Some((text, span)) = cur.take() {
+                pieces.push(Piece::Text(text, span));
+            }
+            cur = Some((tok.text(), tok.span()));
+        }
+
+        prev = Some(tok);
+    }
+
+    if let Some((text, span)) = cur.take() {
+        pieces.push(Piece::Text(text, span));
+    }
+
+    pieces
+}
+
+
+const ITEM_KEYWORDS: [&str; 5] =
+    ["define", "declare", "attributes", "source_filename", "target"];
+
+fn is_item_start(pieces: &[Piece], i: usize) -> bool {
+    let piece = &pieces[i];
+    let followed_by_eq = pieces.get(i + 1).map(|p| p.is("=")).unwrap_or(false);
+
+    ITEM_KEYWORDS.iter().any(|kw| piece.is(kw))
+        || (followed_by_eq
+            && (matches!(piece, Piece::Interp(..))
+                || piece
+                    .text()
+                    .map(|text| text.starts_with(['@', '%', '!']))
+                    .unwrap_or(false)))
+}
+
+/// Checked IR with what should be resolved from the host module
+pub struct IrChecked {
+    pub pieces: Vec<Piece>,
+    /// `@name` referenced but not defined in IR
+    pub extern_symbols: BTreeSet<String>,
+    /// `%Name` referenced as type but not defined in IR
+    pub named_types: BTreeSet<String>,
+}
+
+fn check_fn_body(
+    pieces: &[Piece],
+    params: &BTreeSet<String>,
+    named_types: &mut BTreeSet<String>,
+) -> Result<()> {
+    let mut locals = params.clone();
+    let mut labels = BTreeSet::new();
+
+    for (i, piece) in pieces.iter().enumerate() {
+        if let Some(name) = piece.local_name() {
+            if pieces.get(i + 1).map(|p| p.is("=")).unwrap_or(false)
+                && !locals.insert(name.to_owned())
+            {
+                return Err(Error::new(
+                    piece.span(),
+                    format!("multiple definition of local value `%{}`", name),
+                ));
+            }
+        }
+
+        if let Some(label) = piece.label_def() {
+            if !labels.insert(label.to_owned()) {
+                return Err(Error::new(
+                    piece.span(),
+                    format!("redefinition of label `{}`", label),
+                ));
+            }
+        }
+    }
+
+    for (i, piece) in pieces.iter().enumerate() {
+        let name = match piece.local_name() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if i > 0 && pieces[i - 1].is("label") {
+            if !labels.contains(name) {
+                return Err(Error::new(
+                    piece.span(),
+                    format!("use of undefined label `%{}`", name),
+                ));
+            }
+        } else if !locals.contains(name) && !labels.contains(name) {
+            named_types.insert(name.to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_ir(ts: TokenStream2) -> Result<IrChecked> {
+    let mut toks = vec![];
+    flatten(ts, &mut toks)?;
+
+    let pieces = join_pieces(toks);
+
+    let mut defined_symbols = BTreeSet::new();
+    let mut used_symbols = vec![];
+    let mut defined_types = BTreeSet::new();
+    let mut named_types = BTreeSet::new();
+
+    if let Some(first) = pieces.first() {
+        if !is_item_start(&pieces, 0) {
+            return Err(Error::new(
+                first.span(),
+                "expect `define`, `declare` or a global definition",
+            ));
+        }
+    }
+
+    let mut i = 0;
+    while i < pieces.len() {
+        let start = i;
+        let mut depth = 0i32;
+
+        // find item end
+        i += 1;
+        while i < pieces.len() {
+            if depth == 0 && is_item_start(&pieces, i) {
+                break;
+            }
+
+            match pieces[i].text() {
+                Some("(" | "{" | "[") => depth += 1,
+                Some(")" | "}" | "]") => depth -= 1,
+                _ => (),
+            }
+            i += 1;
+        }
+        let item = &pieces[start..i];
+
+        for piece in item.iter() {
+            if let Some(name) = piece.sigil_name('@') {
+                used_symbols.push(name.to_owned());
+            }
+        }
+
+        if item[0].is("define") || item[0].is("declare") {
+            // `@name` or `#fname(`
+            let name_pos = item.iter().enumerate().position(|(j, p)| {
+                p.sigil_name('@').is_some()
+                    || (matches!(p, Piece::Interp(..))
+                        && item.get(j + 1).map(|p| p.is("(")).unwrap_or(false))
+            });
+            let name_pos = match name_pos {
+                Some(pos) => pos,
+                None => {
+                    return Err(Error::new(item[0].span(), "function without a name"))
+                }
+            };
+            if let Some(name) = item[name_pos].sigil_name('@') {
+                defined_symbols.insert(name.to_owned());
+            }
+
+            if item[0].is("declare") {
+                continue;
+            }
+
+            let mut params = BTreeSet::new();
+            let mut body_start = None;
+            let mut depth = 0;
+            for (j, piece) in item.iter().enumerate().skip(name_pos + 1) {
+                match piece.text() {
+                    Some("(") => depth += 1,
+                    Some(")") => depth -= 1,
+                    Some("{") if depth == 0 => {
+                        body_start = Some(j);
+                        break;
+                    }
+                    _ => {
+                        // `T %name,` is a param, others are named types
+                        if let Some(name) = piece.local_name() {
+                            let next = item.get(j + 1);
+
+                            if next.map(|p| p.is(",") || p.is(")")).unwrap_or(false) {
+                                params.insert(name.to_owned());
+                            } else {
+                                named_types.insert(name.to_owned());
+                            }
+                        }
+                    }
+                }
+            }
+
+            let body_start = match body_start {
+                Some(pos) => pos,
+                None => {
+                    return Err(Error::new(
+                        item[name_pos].span(),
+                        "`define` without a body",
+                    ))
+                }
+            };
+
+            check_fn_body(&item[body_start..], &params, &mut named_types)?;
+        } else if let Some(name) = item[0].sigil_name('@') {
+            defined_symbols.insert(name.to_owned());
+        } else if let Some(name) = item[0].local_name() {
+            defined_types.insert(name.to_owned());
+        }
+    }
+
+    let extern_symbols = used_symbols
+        .into_iter()
+        .filter(|name| !defined_symbols.contains(name))
+        .collect();
+    let named_types = named_types
+        .into_iter()
+        .filter(|name| !defined_types.contains(name))
+        .collect();
+
+    Ok(IrChecked {
+        pieces,
+        extern_symbols,
+        named_types,
+    })
+}
+
+/// (line, column) of `span` in the invocation, 1-based (`(0, 0)` if unknown)
+fn span_loc(span: Span) -> (u32, u32) {
+    let start = span.start();
+
+    if start.line == 0 {
+        (0, 0)
+    } else {
+        (start.line as u32, start.column as u32 + 1)
+    }
+}
+
+/// Statements feeding `__ir: inkwellkit::ir::IrBuilder`
+pub fn gen_ir_builder(checked: &IrChecked) -> TokenStream2 {
+    let mut ts = quote! {};
+
+    for piece in checked.pieces.iter() {
+        let (line, column) = span_loc(piece.span());
+
+        ts.extend(match piece {
+            Piece::Text(text, _) => quote! { __ir.text(#text, #line, #column); },
+            Piece::Interp(expr, span) => quote_spanned! { *span=>
+                __ir.interp(&#expr, #line, #column);
+            },
+        });
+    }
+
+    for name in checked.extern_symbols.iter() {
+        ts.extend(quote! { __ir.extern_symbol(#name); });
+    }
+
+    for name in checked.named_types.iter() {
+        ts.extend(quote! { __ir.named_type(#name); });
+    }
+
+    ts
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(ir: &str) -> Result<IrChecked> {
+        check_ir(ir.parse().unwrap())
+    }
+
+    fn texts(checked: &IrChecked) -> Vec<&str> {
+        checked.pieces.iter().filter_map(|piece| piece.text()).collect()
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let checked = check(r##"
+            @raw = constant [2 x i8] cr"\5C\00"
+            @cooked = constant [3 x i8] c"a\n\\"
+            @quote = constant [1 x i8] cr#"""#
+        "##)
+        .unwrap();
+        let texts = texts(&checked);
+
+        assert!(texts.contains(&r#"c"\5C\00""#));
+        assert!(texts.contains(&r#"c"a\0A\5C""#));
+        assert!(texts.contains(&r#"c"\22""#));
+    }
+
+    #[test]
+    fn test_comments() {
+        let checked = check("
+            ; leading comment
+            define i32 @f(i32 %x) {
+            entry: ; the only block
+                %y = add i32 %x, 1 ; plus one (unused)
+                ret i32 %y
+            }
+        ")
+        .unwrap();
+        let texts = texts(&checked);
+
+        assert_eq!(texts.first(), Some(&"define"));
+        assert!(texts.contains(&"entry:"));
+        assert!(texts.contains(&"ret"));
+        for word in ["leading", "comment", "the", "only", "plus", "one", "unused"] {
+            assert!(!texts.contains(&word), "`{}` isn't commented out", word);
+        }
+    }
+
+    #[test]
+    fn test_externs_and_types() {
+        let checked = check("
+            define void @f(%T* %p) {
+            entry:
+                call void @g(%T* %p)
+                ret void
+            }
+        ")
+        .unwrap();
+
+        assert_eq!(checked.extern_symbols.iter().collect::<Vec<_>>(), ["g"]);
+        assert_eq!(checked.named_types.iter().collect::<Vec<_>>(), ["T"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(check("
+            define void @f() {
+            entry:
+                %x = add i32 1, 2
+                %x = add i32 1, 2
+                ret void
+            }
+        ")
+        .is_err());
+
+        assert!(check("
+            define void @f() {
+            entry:
+                br label %nowhere
+            }
+        ")
+        .is_err());
+
+        assert!(check("ret void").is_err());
+    }
+}
@@ -1,12 +1,18 @@
+// `////` marks section headers throughout the crate
+#![allow(clippy::four_forward_slashes)]
+
 extern crate proc_macro;
 
+mod ir;
+mod llvm_type;
+
 use proc_macro::TokenStream;
 use proc_macro2::{TokenStream as TokenStream2, Span};
 use quote::quote;
-use syn::parenthesized;
+use syn::{braced, bracketed, parenthesized};
 use syn::parse::{Parse, ParseStream, Result};
 use syn::punctuated::Punctuated;
-use syn::{parse_macro_input, Expr, Ident, Token};
+use syn::{parse_macro_input, Block, DeriveInput, Expr, Ident, LitInt, LitStr, Token};
 
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -29,145 +35,517 @@ pub fn load_vm_common_ty(input: TokenStream) -> TokenStream {
         parse_macro_input!(input as LoadVMCommonType);
 
     TokenStream::from(quote! {
-        // use inkwell::AddressSpace;
+        #[allow(unused_variables)]
+        let inkwellkit::CommonTypes {
+            i1_t, i8_t, i16_t, i32_t, i64_t, i128_t, size_t,
+            void_t,
+            f32_t, f64_t,
+            ptr_t, i8ptr_t, i16ptr_t, i32ptr_t, i64ptr_t, i128ptr_t,
+            sizeptr_t, i8ptr2_t, f32ptr_t, f64ptr_t,
+        } = inkwellkit::CommonTypes::get(#ctx);
+        #[allow(unused_variables)]
+        let bool_t = i1_t;
+    })
+}
 
-        // Int type
-        let i8_t = #ctx.i8_type();
-        let i32_t = #ctx.i32_type();
-        let i64_t = #ctx.i64_type();
-        let i128_t = #ctx.i128_type();
 
-        #[cfg(target_pointer_width = "64")]
-        let size_t = #ctx.i64_type();
 
-        #[cfg(target_pointer_width = "32")]
-        let size_t = #ctx.i32_type();
+////////////////////////////////////////////////////////////////////////////////
+//// Add VM Function Header (External)
 
-        let sizeptr_t = size_t.ptr_type(AddressSpace::Generic);
 
-        // Ret Void Type
-        let void_t = #ctx.void_type();
+/// Integer extension of parameter/return under C ABI
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExtAttr {
+    None,
+    ZeroExt,
+    SignExt,
+}
 
-        // Ptr Type
-        let i8ptr_t = i8_t.ptr_type(AddressSpace::Generic);
-        let i32ptr_t = i32_t.ptr_type(AddressSpace::Generic);
-        let i64ptr_t = i64_t.ptr_type(AddressSpace::Generic);
-        let i128ptr_t = i128_t.ptr_type(AddressSpace::Generic);
+impl ExtAttr {
+    fn attr_name(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::ZeroExt => Some("zeroext"),
+            Self::SignExt => Some("signext"),
+        }
+    }
+}
+
+/// Which `BasicValueEnum` variant a value of the type is
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TyKind {
+    Int,
+    Float,
+    Ptr,
+    Struct,
+    Array,
+    Void,
+    /// `{ expr }`, unknown until runtime
+    Any,
+}
+
+impl TyKind {
+    fn value_method(self) -> Option<Ident> {
+        let method = match self {
+            Self::Int => "into_int_value",
+            Self::Float => "into_float_value",
+            Self::Ptr => "into_pointer_value",
+            Self::Struct => "into_struct_value",
+            Self::Array => "into_array_value",
+            Self::Void | Self::Any => return None,
+        };
 
-        let i8ptr2_t = i8ptr_t.ptr_type(AddressSpace::Generic);
+        Some(Ident::new(method, Span::call_site()))
+    }
+}
 
-        // Float Type
-        let f64_t = #ctx.f64_type();
+struct VMTy {
+    ts: TokenStream2,
+    ext: ExtAttr,
+    kind: TyKind,
+    /// Unsigned integer or pointer to it, for `inkwellkit::export::record_unsigned`
+    unsigned: bool,
+}
 
+enum VMPriTy {
+    TS(VMTy),
+    Ellipsis,
+}
+
+const C_TY_KEYWORDS: [&str; 6] =
+    ["unsigned", "signed", "char", "short", "int", "long"];
+
+/// Canonicalize multi-word C integer type, e.g. `unsigned long long` => `u64`
+///
+/// None for `long`, which is 64 bits on LP64 (Linux, macOS) but 32 bits on LLP64 (Windows).
+fn canonical_c_int_ty(words: &[String]) -> Option<&'static str> {
+    let unsigned = words.iter().any(|w| w == "unsigned");
+    let longs = words.iter().filter(|w| *w == "long").count();
+
+    let (signed_ty, unsigned_ty) = if words.iter().any(|w| w == "char") {
+        ("i8", "u8")
+    } else if words.iter().any(|w| w == "short") {
+        ("i16", "u16")
+    } else if longs == 1 {
+        return None;
+    } else if longs >= 2 {
+        ("i64", "u64")
+    } else {
+        ("i32", "u32")
+    };
+
+    Some(if unsigned { unsigned_ty } else { signed_ty })
+}
+
+/// (common type name, bits, signed)
+fn scalar_ty(name: &str) -> Option<(&'static str, Option<(u32, bool)>)> {
+    Some(match name {
+        "bool" | "_Bool" => ("i1_t", Some((1, false))),
+        "i8" | "int8_t" => ("i8_t", Some((8, true))),
+        "u8" | "uint8_t" => ("i8_t", Some((8, false))),
+        "i16" | "int16_t" => ("i16_t", Some((16, true))),
+        "u16" | "uint16_t" => ("i16_t", Some((16, false))),
+        "i32" | "int32_t" => ("i32_t", Some((32, true))),
+        "u32" | "uint32_t" => ("i32_t", Some((32, false))),
+        "i64" | "int64_t" => ("i64_t", Some((64, true))),
+        "u64" | "uint64_t" => ("i64_t", Some((64, false))),
+        "i128" => ("i128_t", Some((128, true))),
+        "u128" => ("i128_t", Some((128, false))),
+        "isize" | "ssize_t" | "intptr_t" | "ptrdiff_t" => ("size_t", Some((64, true))),
+        "usize" | "size_t" | "uintptr_t" => ("size_t", Some((64, false))),
+        "f32" | "float" => ("f32_t", None),
+        "f64" | "double" => ("f64_t", None),
+        "void" => ("void_t", None),
+        _ => return None,
     })
 }
 
+fn ptr_ts(mut ts: TokenStream2, ptrlv: u8) -> TokenStream2 {
+    for _ in 0..ptrlv {
+        ts.extend(quote! {
+            .ptr_type(inkwellkit::AddressSpace::Generic)
+        })
+    }
 
+    ts
+}
 
-////////////////////////////////////////////////////////////////////////////////
-//// Add VM Function Header (External)
+/// `struct name` | `struct name { T, ... }`
+fn parse_struct_ty(input: ParseStream) -> Result<TokenStream2> {
+    input.parse::<Token![struct]>()?;
+    let name = input.parse::<Ident>()?.to_string();
 
+    let fields_ts = if input.peek(syn::token::Brace) {
+        let fields_buf;
+        braced!(fields_buf in input);
 
-enum VMPriTy {
-    TS(TokenStream2),
-    Ellipsis,
+        let fields = fields_buf.parse_terminated::<VMTy, Token![,]>(VMTy::parse)?;
+        let fields_ts = fields.iter().map(|field| &field.ts);
+
+        quote! { Some(&[#(#fields_ts.into()),*]) }
+    } else {
+        quote! { None }
+    };
+
+    Ok(quote! {
+        inkwellkit::get_or_create_struct_type(__ctx, __module, #name, #fields_ts)
+            .unwrap_or_else(|err| panic!("{}", err))
+    })
 }
 
-impl Parse for VMPriTy {
-    fn parse(input: ParseStream) -> Result<Self> {
-        if input.peek(Token![...]) {
-            input.parse::<Token![...]>()?;
+/// `[T; N]`
+fn parse_array_ty(input: ParseStream) -> Result<TokenStream2> {
+    let arr_buf;
+    bracketed!(arr_buf in input);
 
-            return Ok(Self::Ellipsis);
+    let elem = arr_buf.parse::<VMTy>()?;
+    arr_buf.parse::<Token![;]>()?;
+    let len = arr_buf.parse::<LitInt>()?.base10_parse::<u32>()?;
+
+    let elem_ts = elem.ts;
+
+    Ok(quote! { #elem_ts.array_type(#len) })
+}
+
+/// `(T, ..., ...) -> R` => (args, ret, is_var)
+fn parse_fn_sig(input: ParseStream) -> Result<(Vec<VMTy>, VMTy, bool)> {
+    let args_buf;
+    parenthesized!(args_buf in input);
+
+    let args_prity
+        = args_buf.parse_terminated::<VMPriTy, Token![,]>(VMPriTy::parse)?;
+
+    let mut args = vec![];
+    let mut is_var = false;
+    for arg in args_prity {
+        match arg {
+            VMPriTy::TS(ty) => args.push(ty),
+            VMPriTy::Ellipsis => {
+                is_var = true;
+                break;
+            }
+        }
+    }
+
+    let ret = if input.peek(Token![->]) {
+        input.parse::<Token![->]>()?;
+        input.parse::<VMTy>()?
+    } else {
+        VMTy {
+            ts: quote! { __tys.void_t },
+            ext: ExtAttr::None,
+            kind: TyKind::Void,
+            unsigned: false,
         }
+    };
+
+    Ok((args, ret, is_var))
+}
+
+/// `fn(T, ...) -> R`, a function pointer
+fn parse_fn_ptr_ty(input: ParseStream) -> Result<TokenStream2> {
+    input.parse::<Token![fn]>()?;
+
+    let (args, ret, is_var) = parse_fn_sig(input)?;
+    let args_ts = args.iter().map(|arg| &arg.ts);
+    let ret_ts = ret.ts;
+
+    Ok(quote! {
+        #ret_ts
+            .fn_type(&[#(#args_ts.into()),*], #is_var)
+            .ptr_type(inkwellkit::AddressSpace::Generic)
+    })
+}
 
+impl Parse for VMTy {
+    fn parse(input: ParseStream) -> Result<Self> {
         let mut ptrlv = 0u8;
         while input.peek(Token![*]) {
             input.parse::<Token![*]>()?;
             ptrlv += 1;
         }
 
+        let compound = if input.peek(syn::token::Brace) {
+            // `{ expr }`: type value from Rust side
+            let ty_buf;
+            braced!(ty_buf in input);
+            let expr = ty_buf.parse::<Expr>()?;
+
+            Some((quote! { (#expr) }, TyKind::Any))
+        } else if input.peek(Token![struct]) {
+            Some((parse_struct_ty(input)?, TyKind::Struct))
+        } else if input.peek(syn::token::Bracket) {
+            Some((parse_array_ty(input)?, TyKind::Array))
+        } else if input.peek(Token![fn]) {
+            Some((parse_fn_ptr_ty(input)?, TyKind::Ptr))
+        } else {
+            None
+        };
+
+        if let Some((compound_ts, kind)) = compound {
+            return Ok(Self {
+                ts: ptr_ts(compound_ts, ptrlv),
+                ext: ExtAttr::None,
+                kind: if ptrlv > 0 { TyKind::Ptr } else { kind },
+                unsigned: false,
+            });
+        }
+
         let ty = input.parse::<Ident>()?;
+        let mut name = ty.to_string();
 
-        let mut ty_ts = match ty.to_string().as_str() {
-            "i8" | "u8" => quote! {
-                i8_t
-            },
-            "usize" | "isize" => quote! {
-                size_t
-            },
-            "i32" | "u32" => quote! {
-                i32_t
-            },
-            "i64" | "u64" => quote! {
-                i64_t
-            },
-            "i128" => quote! {
-                i128_t
-            },
-            "void" => quote! {
-                void_t
-            },
-            other => {
-                let other = Ident::new(&format!("{}_t", other), Span::call_site());
-                quote! {
-                    #other
+        if C_TY_KEYWORDS.contains(&name.as_str()) {
+            let mut words = vec![name];
+
+            while input.peek(Ident) {
+                let fork = input.fork();
+                let word = fork.parse::<Ident>()?.to_string();
+
+                if !C_TY_KEYWORDS.contains(&word.as_str()) {
+                    break;
                 }
-            },
+
+                input.parse::<Ident>()?;
+                words.push(word);
+            }
+
+            name = match canonical_c_int_ty(&words) {
+                Some(canonical) => canonical.to_owned(),
+                None => {
+                    return Err(syn::Error::new(
+                        ty.span(),
+                        "width of `long` depends on the target, \
+                        use a fixed-width type (`i32`, `i64`, `isize`, ..) or `long long`",
+                    ))
+                }
+            };
+        }
+
+        let (ty_name, int_info) = match scalar_ty(&name) {
+            Some(info) => info,
+            None => {
+                return Err(syn::Error::new(
+                    ty.span(),
+                    format!(
+                        "unknown type `{}`, use `{{ expr }}` for a type from Rust side",
+                        name
+                    ),
+                ))
+            }
         };
-        for _ in 0..ptrlv {
-            ty_ts.extend(quote! {
-                .ptr_type(AddressSpace::Generic)
-            })
+
+        // `*void` is `i8*` in LLVM
+        let ty_name = if ty_name == "void_t" && ptrlv > 0 {
+            "i8_t"
+        } else {
+            ty_name
+        };
+
+        let ty_ident = Ident::new(ty_name, Span::call_site());
+        let ts = ptr_ts(quote! { __tys.#ty_ident }, ptrlv);
+
+        // Only integer narrower than `int` requires extension (like clang on SysV)
+        let ext = match int_info {
+            Some((bits, signed)) if ptrlv == 0 && bits < 32 => {
+                if signed {
+                    ExtAttr::SignExt
+                } else {
+                    ExtAttr::ZeroExt
+                }
+            }
+            _ => ExtAttr::None,
+        };
+
+        let kind = if ptrlv > 0 {
+            TyKind::Ptr
+        } else if int_info.is_some() {
+            TyKind::Int
+        } else if ty_name == "void_t" {
+            TyKind::Void
+        } else {
+            TyKind::Float
+        };
+
+        let unsigned = matches!(int_info, Some((bits, false)) if bits > 1);
+
+        Ok(Self { ts, ext, kind, unsigned })
+    }
+}
+
+impl Parse for VMPriTy {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(Token![...]) {
+            input.parse::<Token![...]>()?;
+
+            return Ok(Self::Ellipsis);
         }
 
-        Ok(Self::TS(ty_ts))
+        Ok(Self::TS(input.parse()?))
     }
 }
 
 
-struct FunHdr {
-    name: Ident,
-    args: Vec<TokenStream2>,
-    ret: TokenStream2,
-    is_var: bool,
+/// `#[noreturn, nounwind, ret(noalias), cc = fast, linkage = extern_weak]`,
+/// `#[link_name = "sym"]`
+#[derive(Default)]
+struct FnAnno {
+    fn_attrs: Vec<String>,
+    ret_attrs: Vec<String>,
+    cc: Option<u32>,
+    linkage: Option<Ident>,
+    link_name: Option<String>,
 }
 
-impl Parse for FunHdr {
-    fn parse(input: ParseStream) -> Result<Self> {
-        let name = input.parse::<Ident>()?;
+const FN_ATTRS: [&str; 20] = [
+    "alwaysinline",
+    "argmemonly",
+    "cold",
+    "hot",
+    "inlinehint",
+    "minsize",
+    "noduplicate",
+    "nofree",
+    "noinline",
+    "norecurse",
+    "noreturn",
+    "nosync",
+    "nounwind",
+    "optsize",
+    "readnone",
+    "readonly",
+    "returns_twice",
+    "speculatable",
+    "willreturn",
+    "writeonly",
+];
+
+const RET_ATTRS: [&str; 5] = ["noalias", "nonnull", "noundef", "signext", "zeroext"];
+
+fn calling_conv(cc: &Ident) -> Result<u32> {
+    Ok(match cc.to_string().as_str() {
+        "c" | "ccc" => 0,
+        "fast" | "fastcc" => 8,
+        "cold" | "coldcc" => 9,
+        "ghc" => 10,
+        "preserve_most" => 14,
+        "preserve_all" => 15,
+        "swift" => 16,
+        "x86_stdcall" => 64,
+        "x86_fastcall" => 65,
+        "x86_64_sysv" => 78,
+        "win64" => 79,
+        _ => return Err(syn::Error::new(cc.span(), "unknown calling convention")),
+    })
+}
 
-        let args_buf;
-        parenthesized!(args_buf in input);
+fn linkage_variant(linkage: &Ident) -> Result<Ident> {
+    let variant = match linkage.to_string().as_str() {
+        "external" => "External",
+        "extern_weak" => "ExternalWeak",
+        "available_externally" => "AvailableExternally",
+        "linkonce" => "LinkOnceAny",
+        "linkonce_odr" => "LinkOnceODR",
+        "weak" => "WeakAny",
+        "weak_odr" => "WeakODR",
+        "common" => "Common",
+        "internal" => "Internal",
+        "private" => "Private",
+        _ => return Err(syn::Error::new(linkage.span(), "unknown linkage")),
+    };
 
-        let args_prity
-            = args_buf.parse_terminated::<VMPriTy, Token![,]>(VMPriTy::parse)?;
+    Ok(Ident::new(variant, linkage.span()))
+}
 
-        let mut args = vec![];
-        let mut is_var = false;
-        for arg in args_prity {
-            match arg {
-                VMPriTy::TS(ts) => args.push(ts),
-                VMPriTy::Ellipsis => {
-                    is_var = true;
+impl Parse for FnAnno {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut anno = Self::default();
+
+        while input.peek(Token![#]) {
+            input.parse::<Token![#]>()?;
+
+            let anno_buf;
+            bracketed!(anno_buf in input);
+
+            while !anno_buf.is_empty() {
+                let key = anno_buf.parse::<Ident>()?;
+
+                match key.to_string().as_str() {
+                    "ret" => {
+                        let ret_buf;
+                        parenthesized!(ret_buf in anno_buf);
+
+                        let attrs = ret_buf
+                            .parse_terminated::<Ident, Token![,]>(Ident::parse)?;
+                        for attr in attrs {
+                            if !RET_ATTRS.contains(&attr.to_string().as_str()) {
+                                return Err(syn::Error::new(
+                                    attr.span(),
+                                    "unknown return attribute",
+                                ));
+                            }
+
+                            anno.ret_attrs.push(attr.to_string());
+                        }
+                    }
+                    "cc" => {
+                        anno_buf.parse::<Token![=]>()?;
+
+                        anno.cc = Some(if anno_buf.peek(LitInt) {
+                            anno_buf.parse::<LitInt>()?.base10_parse()?
+                        } else {
+                            calling_conv(&anno_buf.parse::<Ident>()?)?
+                        });
+                    }
+                    "linkage" => {
+                        anno_buf.parse::<Token![=]>()?;
+                        let linkage = anno_buf.parse::<Ident>()?;
+                        anno.linkage = Some(linkage_variant(&linkage)?);
+                    }
+                    "link_name" => {
+                        anno_buf.parse::<Token![=]>()?;
+                        anno.link_name = Some(anno_buf.parse::<LitStr>()?.value());
+                    }
+                    attr if FN_ATTRS.contains(&attr) => {
+                        anno.fn_attrs.push(attr.to_owned());
+                    }
+                    _ => {
+                        return Err(syn::Error::new(
+                            key.span(),
+                            "unknown function annotation",
+                        ))
+                    }
+                }
+
+                if anno_buf.is_empty() {
                     break;
                 }
+                anno_buf.parse::<Token![,]>()?;
             }
         }
 
-        let ret;
-        if input.peek(Token![-]) {
-            input.parse::<Token![->]>()?;
-            ret = if let VMPriTy::TS(ts) = input.parse::<VMPriTy>()? {
-                ts
-            } else {
-                unreachable!()
-            };
-        } else {
-            ret = quote! { void_t };
-        }
+        Ok(anno)
+    }
+}
+
+
+struct FunHdr {
+    anno: FnAnno,
+    name: Ident,
+    args: Vec<VMTy>,
+    ret: VMTy,
+    is_var: bool,
+}
+
+impl Parse for FunHdr {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let anno = input.parse::<FnAnno>()?;
+        let name = input.parse::<Ident>()?;
+
+        let (args, ret, is_var) = parse_fn_sig(input)?;
 
         Ok(Self {
+            anno,
             name,
             args,
             ret,
@@ -176,6 +554,99 @@ impl Parse for FunHdr {
     }
 }
 
+impl FunHdr {
+    fn symbol(&self) -> String {
+        match self.anno.link_name {
+            Some(ref link_name) => link_name.clone(),
+            None => self.name.to_string(),
+        }
+    }
+
+    /// Expression of the `FunctionValue` added into `__module`,
+    /// requires `__ctx` and `__tys` in scope.
+    fn gen_add_fn(&self) -> TokenStream2 {
+        let symbol = self.symbol();
+        let ret_ts = &self.ret.ts;
+        let args_ts = self.args.iter().map(|arg| &arg.ts);
+        let is_var = self.is_var;
+
+        let linkage = match self.anno.linkage {
+            Some(ref linkage) => linkage.clone(),
+            None => Ident::new("External", Span::call_site()),
+        };
+
+        let ext_attrs = self
+            .args
+            .iter()
+            .enumerate()
+            .filter_map(|(i, arg)| {
+                let i = i as u32;
+
+                arg.ext
+                    .attr_name()
+                    .map(|attr_name| (quote! { Param(#i) }, attr_name.to_owned()))
+            })
+            .chain(
+                self.ret
+                    .ext
+                    .attr_name()
+                    .map(|attr_name| (quote! { Return }, attr_name.to_owned())),
+            );
+        let fn_attrs = self
+            .anno
+            .fn_attrs
+            .iter()
+            .map(|attr_name| (quote! { Function }, attr_name.clone()));
+        let ret_attrs = self
+            .anno
+            .ret_attrs
+            .iter()
+            .map(|attr_name| (quote! { Return }, attr_name.clone()));
+
+        let mut attrs_ts = quote! {};
+        for (loc, attr_name) in ext_attrs.chain(fn_attrs).chain(ret_attrs) {
+            attrs_ts.extend(quote! {
+                fn_val.add_attribute(
+                    inkwellkit::attributes::AttributeLoc::#loc,
+                    __ctx.create_enum_attribute(
+                        inkwellkit::attributes::Attribute
+                            ::get_named_enum_kind_id(#attr_name),
+                        0
+                    )
+                );
+            });
+        }
+
+        if let Some(cc) = self.anno.cc {
+            attrs_ts.extend(quote! {
+                fn_val.set_call_conventions(#cc);
+            });
+        }
+
+        let unsigned = std::iter::once(&self.ret)
+            .chain(self.args.iter())
+            .map(|ty| ty.unsigned)
+            .collect::<Vec<_>>();
+        if unsigned.contains(&true) {
+            attrs_ts.extend(quote! {
+                inkwellkit::export::record_unsigned(__module, #symbol, &[#(#unsigned),*]);
+            });
+        }
+
+        quote! {{
+            let fn_val = __module.add_function(
+                #symbol,
+                #ret_ts
+                    .fn_type(&[#(#args_ts.into()),*], #is_var),
+                Some(inkwellkit::module::Linkage::#linkage)
+            );
+            #attrs_ts
+
+            fn_val
+        }}
+    }
+}
+
 
 struct ImplFunHdr {
     module: Ident,
@@ -193,37 +664,294 @@ impl Parse for ImplFunHdr {
     }
 }
 
-/// Cooperate with load_vm_common_ty
+/// Declare external functions in the module's own context.
+///
+/// Each declaration also binds a local `FunctionValue` named after it,
+/// `#[link_name = "sym"]` makes the symbol differ from the binding name.
+/// Symbols already in the module are reused, so including twice is fine.
 #[proc_macro]
 pub fn impl_fn_hdr(input: TokenStream) -> TokenStream {
     let ImplFunHdr { module, funhdrs } =
         parse_macro_input!(input as ImplFunHdr);
 
+    // Load types from the module's own context, which may be a scoped one
     let mut ts = quote! {
-        load_vm_common_ty!(get_ctx());
+        let __module = &#module;
+        let __module_ctx = __module.get_context();
+        let __ctx = unsafe { __module_ctx.get() };
+        let __tys = inkwellkit::CommonTypes::get(__ctx);
     };
 
     for funhdr in funhdrs {
-        let fname = funhdr.name;
-        let ret_ts = funhdr.ret;
+        let fname = &funhdr.name;
+        let symbol = funhdr.symbol();
+        let add_fn_ts = funhdr.gen_add_fn();
+
+        ts.extend(quote! {
+            #[allow(unused_variables)]
+            let #fname = match __module.get_function(#symbol) {
+                Some(fn_val) => fn_val,
+                None => #add_fn_ts,
+            };
+        });
+    }
+
+    TokenStream::from(ts)
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Add VM Function Definition
+
+/// `name(a: T, ...) -> R { body }`
+struct FunDef {
+    hdr: FunHdr,
+    params: Vec<Ident>,
+    body: Block,
+}
+
+impl Parse for FunDef {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let anno = input.parse::<FnAnno>()?;
+        let name = input.parse::<Ident>()?;
+
+        let params_buf;
+        parenthesized!(params_buf in input);
+
+        let mut params = vec![];
+        let mut args = vec![];
+        while !params_buf.is_empty() {
+            params.push(params_buf.parse::<Ident>()?);
+            params_buf.parse::<Token![:]>()?;
+            args.push(params_buf.parse::<VMTy>()?);
+
+            if params_buf.is_empty() {
+                break;
+            }
+            params_buf.parse::<Token![,]>()?;
+        }
+
+        let ret = if input.peek(Token![->]) {
+            input.parse::<Token![->]>()?;
+            input.parse::<VMTy>()?
+        } else {
+            VMTy {
+                ts: quote! { __tys.void_t },
+                ext: ExtAttr::None,
+                kind: TyKind::Void,
+                unsigned: false,
+            }
+        };
+
+        let body = input.parse::<Block>()?;
+
+        Ok(Self {
+            hdr: FunHdr {
+                anno,
+                name,
+                args,
+                ret,
+                is_var: false,
+            },
+            params,
+            body,
+        })
+    }
+}
 
-        let mut args_ts = quote! {};
-        for arg in funhdr.args {
-            args_ts.extend(quote! {
-                #arg.into(),
+
+struct ImplFun {
+    module: Ident,
+    fundefs: Vec<FunDef>,
+}
+
+impl Parse for ImplFun {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let module = input.parse::<Ident>()?;
+        input.parse::<Token![|]>()?;
+
+        let mut fundefs = vec![];
+        while !input.is_empty() {
+            fundefs.push(input.parse::<FunDef>()?);
+        }
+
+        Ok(Self { module, fundefs })
+    }
+}
+
+/// Define functions with the same signature grammar as `impl_fn_hdr`.
+///
+/// The body gets `builder` positioned at the entry block, each parameter bound
+/// to its typed value and the functions defined before it, its value is discarded.
+///
+/// Each function is checked by `inkwellkit::check_fn` after its body,
+/// it evaluates to `CompileResult<()>` of the first failure.
+#[proc_macro]
+pub fn impl_fn(input: TokenStream) -> TokenStream {
+    let ImplFun { module, fundefs } = parse_macro_input!(input as ImplFun);
+
+    let mut ts = quote! {};
+
+    for fundef in fundefs {
+        let fname = &fundef.hdr.name;
+        let add_fn_ts = fundef.hdr.gen_add_fn();
+        let body = &fundef.body;
+
+        let mut params_ts = quote! {};
+        for (i, (param, arg)) in
+            fundef.params.iter().zip(fundef.hdr.args.iter()).enumerate()
+        {
+            let i = i as u32;
+            let param_name = param.to_string();
+            let into_value = match arg.kind.value_method() {
+                Some(method) => quote! { .#method() },
+                None => quote! {},
+            };
+
+            params_ts.extend(quote! {
+                let #param = #fname.get_nth_param(#i).unwrap();
+                #param.set_name(#param_name);
+                #[allow(unused_variables)]
+                let #param = #param#into_value;
             });
         }
-        let is_var = funhdr.is_var;
 
         ts.extend(quote! {
-            #module.add_function(
-                stringify!(#fname),
-                #ret_ts
-                    .fn_type(&[#args_ts], #is_var),
-                Some(Linkage::External)
-            );
+            #[allow(unused_variables)]
+            let #fname = #add_fn_ts;
+
+            {
+                let builder = __ctx.create_builder();
+                builder.position_at_end(__ctx.append_basic_block(#fname, "entry"));
+
+                #params_ts
+
+                #[allow(clippy::let_unit_value)]
+                let _ = #body;
+            }
+
+            inkwellkit::check_fn(#fname)?;
         });
     }
 
-    TokenStream::from(ts)
+    // `?` of the checks stops in the closure
+    TokenStream::from(quote! {{
+        let __module = &#module;
+        let __module_ctx = __module.get_context();
+        let __ctx = unsafe { __module_ctx.get() };
+        let __tys = inkwellkit::CommonTypes::get(__ctx);
+
+        let __impl_fn = || -> inkwellkit::compiler::CompileResult<()> {
+            #ts
+
+            Ok(())
+        };
+        __impl_fn()
+    }})
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Quasi-quoted LLVM IR
+
+struct ImplIR {
+    module: Ident,
+    ir: TokenStream2,
+}
+
+impl Parse for ImplIR {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let module = input.parse::<Ident>()?;
+        input.parse::<Token![|]>()?;
+
+        let ir = input.parse::<TokenStream2>()?;
+
+        Ok(Self { module, ir })
+    }
+}
+
+/// Splice textual LLVM IR into module, interpolate Rust-side constants,
+/// globals and types by `#var` or `#(expr)`.
+///
+/// Item structure, labels and local redefinitions are checked at compile time,
+/// referenced external symbols and named struct types are declared from the module.
+///
+/// It evaluates to `CompileResult<()>`, LLVM errors point into the invocation.
+#[proc_macro]
+pub fn impl_ir(input: TokenStream) -> TokenStream {
+    let ImplIR { module, ir } = parse_macro_input!(input as ImplIR);
+
+    let checked = match ir::check_ir(ir) {
+        Ok(checked) => checked,
+        Err(err) => return TokenStream::from(err.to_compile_error()),
+    };
+    let stmts = ir::gen_ir_builder(&checked);
+
+    TokenStream::from(quote! {{
+        let mut __ir = inkwellkit::ir::IrBuilder::new(file!(), line!(), column!());
+        #stmts
+        __ir.splice(&#module)
+    }})
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Derive LLVM Struct Type
+
+/// Mirror a `#[repr(C)]` struct as named LLVM struct type (`#[llvm(name = "..")]`
+/// overrides the name), with constant builder, `IDX_<FIELD>` constants for GEPs
+/// and a test checking field offsets against `TargetData`.
+#[proc_macro_derive(LlvmType, attributes(llvm))]
+pub fn derive_llvm_type(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match llvm_type::derive(input) {
+        Ok(ts) => TokenStream::from(ts),
+        Err(err) => TokenStream::from(err.to_compile_error()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical_c_int_ty, scalar_ty};
+
+    fn canonical(ty: &str) -> Option<&'static str> {
+        let words = ty.split_whitespace().map(str::to_owned).collect::<Vec<_>>();
+
+        canonical_c_int_ty(&words)
+    }
+
+    #[test]
+    fn test_canonical_c_int_ty() {
+        for (ty, canonical_ty) in [
+            ("char", "i8"),
+            ("signed char", "i8"),
+            ("unsigned char", "u8"),
+            ("short", "i16"),
+            ("short int", "i16"),
+            ("unsigned short int", "u16"),
+            ("int", "i32"),
+            ("signed", "i32"),
+            ("unsigned", "u32"),
+            ("unsigned int", "u32"),
+            ("long long", "i64"),
+            ("long long int", "i64"),
+            ("unsigned long long", "u64"),
+            ("long unsigned long", "u64"),
+        ] {
+            assert_eq!(canonical(ty), Some(canonical_ty), "{}", ty);
+
+            // every canonical name is a known scalar
+            assert!(scalar_ty(canonical_ty).is_some());
+        }
+
+        // LP64 or LLP64 by target
+        for ty in ["long", "long int", "unsigned long", "signed long int"] {
+            assert_eq!(canonical(ty), None, "{}", ty);
+        }
+    }
 }
@@ -0,0 +1,172 @@
+//! `#[derive(LlvmType)]` for `#[repr(C)]` structs
+
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Error, Fields, Lit, Meta, NestedMeta, Result,
+};
+
+
+fn is_repr_c(input: &DeriveInput) -> bool {
+    input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .filter_map(|attr| attr.parse_meta().ok())
+        .any(|meta| match meta {
+            Meta::List(list) => list.nested.iter().any(|nested| {
+                matches!(nested, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("C"))
+            }),
+            _ => false,
+        })
+}
+
+/// `#[llvm(name = "..")]`
+fn llvm_name(input: &DeriveInput) -> Result<String> {
+    let mut name = input.ident.to_string();
+
+    for attr in input.attrs.iter().filter(|attr| attr.path.is_ident("llvm")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new_spanned(meta, "expect `llvm(name = \"..\")`")),
+        };
+
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                    match nv.lit {
+                        Lit::Str(s) => name = s.value(),
+                        lit => return Err(Error::new_spanned(lit, "expect string literal")),
+                    }
+                }
+                other => {
+                    return Err(Error::new_spanned(other, "unknown llvm attribute"))
+                }
+            }
+        }
+    }
+
+    Ok(name)
+}
+
+pub fn derive(input: DeriveInput) -> Result<TokenStream2> {
+    let ident = &input.ident;
+
+    if !is_repr_c(&input) {
+        return Err(Error::new_spanned(
+            ident,
+            "LlvmType requires `#[repr(C)]` for a stable layout",
+        ));
+    }
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "LlvmType doesn't support generic struct",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => named.named.iter().collect::<Vec<_>>(),
+            Fields::Unit => vec![],
+            Fields::Unnamed(_) => {
+                return Err(Error::new_spanned(
+                    ident,
+                    "LlvmType requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(ident, "LlvmType only supports struct"))
+        }
+    };
+
+    let name = llvm_name(&input)?;
+
+    let field_idents = fields
+        .iter()
+        .map(|field| field.ident.clone().unwrap())
+        .collect::<Vec<_>>();
+    let field_tys = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+    let field_names = field_idents
+        .iter()
+        .map(|field| field.to_string().trim_start_matches("r#").to_owned())
+        .collect::<Vec<_>>();
+
+    let idx_consts = field_names.iter().enumerate().map(|(i, field)| {
+        let idx_ident = format_ident!("IDX_{}", field.to_uppercase());
+        let i = i as u32;
+
+        quote! {
+            pub const #idx_ident: u32 = #i;
+        }
+    });
+
+    let test_ident = format_ident!("__llvm_type_layout_{}", ident);
+
+    Ok(quote! {
+        impl #ident {
+            #(#idx_consts)*
+        }
+
+        impl inkwellkit::repr::LlvmField for #ident {
+            fn llvm_field_type<'ctx>(
+                module: &inkwellkit::module::Module<'ctx>,
+            ) -> inkwellkit::types::BasicTypeEnum<'ctx> {
+                <Self as inkwellkit::repr::LlvmType>::llvm_type(module).into()
+            }
+
+            fn const_field<'ctx>(
+                &self,
+                module: &inkwellkit::module::Module<'ctx>,
+            ) -> inkwellkit::values::BasicValueEnum<'ctx> {
+                inkwellkit::repr::LlvmType::const_value(self, module).into()
+            }
+        }
+
+        impl inkwellkit::repr::LlvmType for #ident {
+            const LLVM_NAME: &'static str = #name;
+
+            const FIELD_NAMES: &'static [&'static str] = &[#(#field_names),*];
+
+            fn llvm_type<'ctx>(
+                module: &inkwellkit::module::Module<'ctx>,
+            ) -> inkwellkit::types::StructType<'ctx> {
+                let fields: Vec<inkwellkit::types::BasicTypeEnum<'ctx>> = vec![
+                    #(<#field_tys as inkwellkit::repr::LlvmField>::llvm_field_type(module)),*
+                ];
+
+                let module_ctx = module.get_context();
+
+                inkwellkit::get_or_create_struct_type(
+                    unsafe { module_ctx.get() },
+                    module,
+                    #name,
+                    Some(&fields),
+                )
+                .unwrap_or_else(|err| panic!("{}", err))
+            }
+
+            fn const_value<'ctx>(
+                &self,
+                module: &inkwellkit::module::Module<'ctx>,
+            ) -> inkwellkit::values::StructValue<'ctx> {
+                let values: Vec<inkwellkit::values::BasicValueEnum<'ctx>> = vec![
+                    #(inkwellkit::repr::LlvmField::const_field(&self.#field_idents, module)),*
+                ];
+
+                Self::llvm_type(module).const_named_struct(&values)
+            }
+        }
+
+        #[cfg(test)]
+        #[test]
+        #[allow(non_snake_case)]
+        fn #test_ident() {
+            inkwellkit::repr::assert_layout::<#ident>(
+                &[#(::core::mem::offset_of!(#ident, #field_idents)),*],
+                ::core::mem::size_of::<#ident>(),
+            );
+        }
+    })
+}
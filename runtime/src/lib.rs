@@ -0,0 +1,600 @@
+//! C-ABI runtime of the generated programs, linked as `libinkwellkit_rt.a`
+//! (the tests of `inkwellkit` use it as an rlib to back the JIT)
+//!
+//! Declarations for IR side are in `inkwellkit::VMMod::include_runtime`,
+//! keep them in sync.
+//!
+//! All the handles and buffers are trusted to be valid, they come from the generated code.
+// `////` marks section headers throughout the crate
+#![allow(clippy::missing_safety_doc, clippy::four_forward_slashes)]
+
+use std::{
+    alloc::{self, Layout},
+    collections::HashMap,
+    ffi::c_char,
+    io::{self, Write},
+    mem, process, ptr, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Panic
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(ptr, len)
+    }
+}
+
+/// Print `panic: <msg>` to stderr and abort
+#[no_mangle]
+pub unsafe extern "C" fn rt_panic(msg: *const u8, len: usize) -> ! {
+    let mut stderr = io::stderr().lock();
+
+    stderr.write_all(b"panic: ").ok();
+    stderr.write_all(bytes(msg, len)).ok();
+    stderr.write_all(b"\n").ok();
+    stderr.flush().ok();
+
+    process::abort()
+}
+
+#[no_mangle]
+pub extern "C" fn rt_abort() -> ! {
+    process::abort()
+}
+
+fn panic_fmt(msg: String) -> ! {
+    unsafe { rt_panic(msg.as_ptr(), msg.len()) }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Vector
+
+/// Vector of elements of `elem_size` bytes
+pub struct RtVec {
+    elem_size: usize,
+    /// count of zero-sized elements, which occupy no `data`
+    zst_len: usize,
+    data: Vec<u8>,
+}
+
+impl RtVec {
+    fn len(&self) -> usize {
+        self.data.len().checked_div(self.elem_size).unwrap_or(self.zst_len)
+    }
+
+    fn slot(&mut self, idx: usize) -> *mut u8 {
+        let len = self.len();
+        if idx >= len {
+            panic_fmt(format!("index {} out of bounds for length {}", idx, len));
+        }
+
+        unsafe { self.data.as_mut_ptr().add(idx * self.elem_size) }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rt_vec_new(elem_size: usize, cap: usize) -> *mut RtVec {
+    let bytes = elem_size.checked_mul(cap).unwrap_or_else(|| {
+        panic_fmt(format!("capacity overflow: {} elements of {} bytes", cap, elem_size))
+    });
+
+    Box::into_raw(Box::new(RtVec {
+        elem_size,
+        zst_len: 0,
+        data: Vec::with_capacity(bytes),
+    }))
+}
+
+/// Copy `elem_size` bytes from `elem` to the end
+#[no_mangle]
+pub unsafe extern "C" fn rt_vec_push(vec: *mut RtVec, elem: *const u8) {
+    let vec = &mut *vec;
+
+    if vec.elem_size == 0 {
+        vec.zst_len += 1;
+    } else {
+        vec.data.extend_from_slice(bytes(elem, vec.elem_size));
+    }
+}
+
+/// Move the last element into `out`, return false if it's empty.
+#[no_mangle]
+pub unsafe extern "C" fn rt_vec_pop(vec: *mut RtVec, out: *mut u8) -> bool {
+    let vec = &mut *vec;
+    let len = vec.len();
+
+    if len == 0 {
+        return false;
+    }
+
+    if vec.elem_size == 0 {
+        vec.zst_len -= 1;
+        return true;
+    }
+
+    let start = (len - 1) * vec.elem_size;
+    ptr::copy_nonoverlapping(vec.data.as_ptr().add(start), out, vec.elem_size);
+    vec.data.truncate(start);
+
+    true
+}
+
+/// Pointer to the `idx`th element, panic if out of bounds.
+#[no_mangle]
+pub unsafe extern "C" fn rt_vec_get(vec: *mut RtVec, idx: usize) -> *mut u8 {
+    (*vec).slot(idx)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_vec_len(vec: *const RtVec) -> usize {
+    (*vec).len()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_vec_free(vec: *mut RtVec) {
+    if !vec.is_null() {
+        drop(Box::from_raw(vec));
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// String
+
+/// Growable byte string, always NUL-terminated after the content
+pub struct RtStr {
+    buf: Vec<u8>,
+}
+
+#[no_mangle]
+pub extern "C" fn rt_str_new() -> *mut RtStr {
+    Box::into_raw(Box::new(RtStr { buf: vec![0] }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_str_push(s: *mut RtStr, ptr: *const u8, len: usize) {
+    let s = &mut *s;
+
+    s.buf.pop();
+    s.buf.extend_from_slice(bytes(ptr, len));
+    s.buf.push(0);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_str_push_i64(s: *mut RtStr, value: i64) {
+    let repr = value.to_string();
+
+    rt_str_push(s, repr.as_ptr(), repr.len());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_str_len(s: *const RtStr) -> usize {
+    (*s).buf.len() - 1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_str_ptr(s: *const RtStr) -> *const c_char {
+    (*s).buf.as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_str_eq(
+    s: *const RtStr,
+    ptr: *const u8,
+    len: usize,
+) -> bool {
+    let buf = &(*s).buf;
+
+    buf[..buf.len() - 1] == *bytes(ptr, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_str_free(s: *mut RtStr) {
+    if !s.is_null() {
+        drop(Box::from_raw(s));
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Hash Map
+
+/// Map from byte string to `i64`
+pub struct RtMap {
+    map: HashMap<Vec<u8>, i64>,
+}
+
+#[no_mangle]
+pub extern "C" fn rt_map_new() -> *mut RtMap {
+    Box::into_raw(Box::new(RtMap {
+        map: HashMap::new(),
+    }))
+}
+
+/// Return true if `key` is new
+#[no_mangle]
+pub unsafe extern "C" fn rt_map_insert(
+    map: *mut RtMap,
+    key: *const u8,
+    key_len: usize,
+    value: i64,
+) -> bool {
+    (*map).map.insert(bytes(key, key_len).to_vec(), value).is_none()
+}
+
+/// Write value of `key` into `out`, return false if there's none.
+#[no_mangle]
+pub unsafe extern "C" fn rt_map_get(
+    map: *const RtMap,
+    key: *const u8,
+    key_len: usize,
+    out: *mut i64,
+) -> bool {
+    match (*map).map.get(bytes(key, key_len)) {
+        Some(value) => {
+            *out = *value;
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_map_remove(
+    map: *mut RtMap,
+    key: *const u8,
+    key_len: usize,
+) -> bool {
+    (*map).map.remove(bytes(key, key_len)).is_some()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_map_len(map: *const RtMap) -> usize {
+    (*map).map.len()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_map_free(map: *mut RtMap) {
+    if !map.is_null() {
+        drop(Box::from_raw(map));
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Garbage Collection
+
+/// Frame layout of LLVM's `shadow-stack` strategy
+#[repr(C)]
+pub struct FrameMap {
+    num_roots: i32,
+    num_meta: i32,
+}
+
+/// Frame of a function with `gc "shadow-stack"`, the roots follow it.
+#[repr(C)]
+pub struct StackEntry {
+    next: *mut StackEntry,
+    map: *const FrameMap,
+}
+
+/// Head of the shadow stack, pushed/popped by the lowered functions
+///
+/// LLVM defines it `linkonce` in every module with shadow-stack functions,
+/// this definition wins the link.
+#[no_mangle]
+pub static mut llvm_gc_root_chain: *mut StackEntry = ptr::null_mut();
+
+/// Type descriptor emitted by `inkwellkit::gc::GcType`
+#[repr(C)]
+pub struct GcTypeDesc {
+    size: usize,
+    num_ptrs: usize,
+    /// offsets of the fields pointing to GC objects
+    ptr_offsets: *const usize,
+}
+
+#[repr(C)]
+struct GcHeader {
+    desc: *const GcTypeDesc,
+    next: *mut GcHeader,
+    marked: bool,
+}
+
+/// Objects are 16-byte aligned, as `malloc` does.
+const GC_ALIGN: usize = 16;
+const GC_HDR_SIZE: usize = mem::size_of::<GcHeader>().next_multiple_of(GC_ALIGN);
+const GC_INIT_THRESHOLD: usize = 1 << 20;
+
+/// The shadow stack `llvm_gc_root_chain` is process-global, so is the heap.
+///
+/// Neither is synchronized, the first thread using the GC owns them, others panic.
+struct GcHeap {
+    objects: *mut GcHeader,
+    allocated: usize,
+    threshold: usize,
+}
+
+static mut GC_HEAP: GcHeap = GcHeap {
+    objects: ptr::null_mut(),
+    allocated: 0,
+    threshold: GC_INIT_THRESHOLD,
+};
+
+static GC_OWNER: AtomicUsize = AtomicUsize::new(0);
+static THREAD_SEQ: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static THREAD_NO: usize = THREAD_SEQ.fetch_add(1, Ordering::Relaxed);
+}
+
+fn gc_check_owner() {
+    let me = THREAD_NO.with(|no| *no);
+
+    match GC_OWNER.compare_exchange(0, me, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => (),
+        Err(owner) if owner == me => (),
+        Err(_) => panic_fmt("GC is single-threaded, it's used by another thread".to_owned()),
+    }
+}
+
+unsafe fn gc_layout(desc: *const GcTypeDesc) -> Layout {
+    Layout::from_size_align(GC_HDR_SIZE + (*desc).size, GC_ALIGN).unwrap()
+}
+
+unsafe fn gc_header(obj: *mut u8) -> *mut GcHeader {
+    obj.sub(GC_HDR_SIZE) as *mut GcHeader
+}
+
+unsafe fn gc_object(hdr: *mut GcHeader) -> *mut u8 {
+    (hdr as *mut u8).add(GC_HDR_SIZE)
+}
+
+/// Zeroed object of `desc`, it may collect first.
+#[no_mangle]
+pub unsafe extern "C" fn rt_gc_alloc(desc: *const GcTypeDesc) -> *mut u8 {
+    gc_check_owner();
+
+    if desc.is_null() {
+        panic_fmt("rt_gc_alloc: null type descriptor".to_owned());
+    }
+
+    let heap = &mut *ptr::addr_of_mut!(GC_HEAP);
+
+    if heap.allocated >= heap.threshold {
+        rt_gc_collect();
+        heap.threshold = (heap.allocated * 2).max(GC_INIT_THRESHOLD);
+    }
+
+    let layout = gc_layout(desc);
+    let hdr = alloc::alloc_zeroed(layout) as *mut GcHeader;
+    if hdr.is_null() {
+        alloc::handle_alloc_error(layout);
+    }
+
+    hdr.write(GcHeader {
+        desc,
+        next: heap.objects,
+        marked: false,
+    });
+    heap.objects = hdr;
+    heap.allocated += layout.size();
+
+    gc_object(hdr)
+}
+
+/// Mark from the roots on the shadow stack, then sweep the unreachable.
+#[no_mangle]
+pub unsafe extern "C" fn rt_gc_collect() {
+    gc_check_owner();
+
+    let heap = &mut *ptr::addr_of_mut!(GC_HEAP);
+
+    // mark, null roots and fields are skipped
+    let mut pending = vec![];
+    let mut entry = *ptr::addr_of!(llvm_gc_root_chain);
+
+    while !entry.is_null() {
+        let roots = entry.add(1) as *const *mut u8;
+        let num_roots = match (*entry).map.as_ref() {
+            Some(map) => map.num_roots.max(0) as usize,
+            None => 0,
+        };
+
+        for i in 0..num_roots {
+            pending.push(*roots.add(i));
+        }
+
+        entry = (*entry).next;
+    }
+
+    while let Some(obj) = pending.pop() {
+        if obj.is_null() {
+            continue;
+        }
+
+        let hdr = gc_header(obj);
+        if (*hdr).marked {
+            continue;
+        }
+        (*hdr).marked = true;
+
+        let desc = match (*hdr).desc.as_ref() {
+            Some(desc) if !desc.ptr_offsets.is_null() => desc,
+            _ => continue,
+        };
+        for i in 0..desc.num_ptrs {
+            let field = obj.add(*desc.ptr_offsets.add(i)) as *const *mut u8;
+            pending.push(*field);
+        }
+    }
+
+    // sweep
+    let mut link = &mut heap.objects as *mut *mut GcHeader;
+
+    while !(*link).is_null() {
+        let hdr = *link;
+
+        if (*hdr).marked {
+            (*hdr).marked = false;
+            link = &mut (*hdr).next;
+        } else {
+            let layout = gc_layout((*hdr).desc);
+
+            *link = (*hdr).next;
+            heap.allocated -= layout.size();
+            alloc::dealloc(hdr as *mut u8, layout);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn test_vec() {
+        unsafe {
+            let vec = rt_vec_new(8, 2);
+
+            for i in 0..5u64 {
+                rt_vec_push(vec, i.to_ne_bytes().as_ptr());
+            }
+            assert_eq!(rt_vec_len(vec), 5);
+            assert_eq!(*(rt_vec_get(vec, 3) as *const u64), 3);
+
+            let mut out = 0u64;
+            assert!(rt_vec_pop(vec, &mut out as *mut u64 as *mut u8));
+            assert_eq!(out, 4);
+            assert_eq!(rt_vec_len(vec), 4);
+
+            rt_vec_free(vec);
+        }
+    }
+
+    #[test]
+    fn test_vec_zst() {
+        unsafe {
+            let vec = rt_vec_new(0, usize::MAX);
+
+            rt_vec_push(vec, ptr::null());
+            rt_vec_push(vec, ptr::null());
+            assert_eq!(rt_vec_len(vec), 2);
+            rt_vec_get(vec, 1);
+
+            assert!(rt_vec_pop(vec, ptr::null_mut()));
+            assert!(rt_vec_pop(vec, ptr::null_mut()));
+            assert!(!rt_vec_pop(vec, ptr::null_mut()));
+            assert_eq!(rt_vec_len(vec), 0);
+
+            rt_vec_free(vec);
+        }
+    }
+
+    #[test]
+    fn test_str() {
+        unsafe {
+            let s = rt_str_new();
+            assert_eq!(rt_str_len(s), 0);
+
+            rt_str_push(s, b"n=".as_ptr(), 2);
+            rt_str_push(s, ptr::null(), 0);
+            rt_str_push_i64(s, -42);
+
+            assert_eq!(rt_str_len(s), 5);
+            assert!(rt_str_eq(s, b"n=-42".as_ptr(), 5));
+            assert!(!rt_str_eq(s, b"n=-4".as_ptr(), 4));
+            assert_eq!(std::ffi::CStr::from_ptr(rt_str_ptr(s)).to_bytes(), b"n=-42");
+
+            rt_str_free(s);
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        unsafe {
+            let map = rt_map_new();
+            let mut out = 0;
+
+            assert!(rt_map_insert(map, b"a".as_ptr(), 1, 1));
+            assert!(rt_map_insert(map, b"".as_ptr(), 0, 2));
+            assert!(!rt_map_insert(map, b"a".as_ptr(), 1, 3));
+            assert_eq!(rt_map_len(map), 2);
+
+            assert!(rt_map_get(map, b"a".as_ptr(), 1, &mut out));
+            assert_eq!(out, 3);
+            assert!(!rt_map_get(map, b"b".as_ptr(), 1, &mut out));
+
+            assert!(rt_map_remove(map, ptr::null(), 0));
+            assert!(!rt_map_remove(map, ptr::null(), 0));
+            assert_eq!(rt_map_len(map), 1);
+
+            rt_map_free(map);
+        }
+    }
+
+    /// The only test using GC, the heap is owned by the thread running it.
+    #[test]
+    fn test_gc() {
+        #[repr(C)]
+        struct Node {
+            next: *mut Node,
+            value: u64,
+        }
+
+        #[repr(C)]
+        struct Frame {
+            entry: StackEntry,
+            roots: [*mut u8; 2],
+        }
+
+        let offsets = [0usize];
+        let desc = GcTypeDesc {
+            size: mem::size_of::<Node>(),
+            num_ptrs: 1,
+            ptr_offsets: offsets.as_ptr(),
+        };
+        let map = FrameMap { num_roots: 2, num_meta: 0 };
+
+        unsafe {
+            let heap = &*ptr::addr_of!(GC_HEAP);
+            let obj_size = gc_layout(&desc).size();
+            let base = heap.allocated;
+
+            // root -> a -> b, c is garbage
+            let a = rt_gc_alloc(&desc) as *mut Node;
+            let b = rt_gc_alloc(&desc) as *mut Node;
+            rt_gc_alloc(&desc);
+            (*a).next = b;
+            (*b).value = 7;
+
+            // the other root is null
+            let mut frame = Frame {
+                entry: StackEntry { next: ptr::null_mut(), map: &map },
+                roots: [a as *mut u8, ptr::null_mut()],
+            };
+            let entry = ptr::addr_of_mut!(frame.entry);
+            llvm_gc_root_chain = entry;
+
+            rt_gc_collect();
+            assert_eq!(heap.allocated, base + 2 * obj_size);
+            assert_eq!((*(*a).next).value, 7);
+
+            // frame without map has no roots
+            (*entry).map = ptr::null();
+            rt_gc_collect();
+            assert_eq!(heap.allocated, base);
+
+            llvm_gc_root_chain = ptr::null_mut();
+        }
+    }
+}
@@ -0,0 +1,563 @@
+//! Arena (bump) allocator generated in IR, over chunks of `malloc` or `mmap`
+//!
+//! `%arena = { chunk*, i8* cur, i8* end, size_t chunk_size }` is a handle on heap,
+//! `%arena.chunk = { chunk* prev, size_t size }` heads each chunk, followed by the data.
+//! The helpers are internal functions emitted once per module and backing:
+//!
+//! - `create(chunk_size) -> arena*`
+//! - `alloc(arena*, size, align) -> i8*`
+//! - `reset(arena*)`, keep the newest chunk and free the others
+//! - `destroy(arena*)`
+//!
+//! Out of memory and overflow of the size abort.
+
+use inkwell::{
+    builder::Builder,
+    module::{Linkage, Module},
+    targets::TargetMachine,
+    types::{BasicType, FunctionType, PointerType, StructType},
+    values::{BasicMetadataValueEnum, BasicValueEnum, FunctionValue, IntValue, PointerValue},
+    AddressSpace, IntPredicate,
+};
+
+use crate::{
+    build_checked_add, get_or_create_struct_type, let_module_ctx, ret_as_bv, CommonTypes, VMMod,
+};
+
+
+const PROT_READ_WRITE: u64 = 0x1 | 0x2;
+
+/// `MAP_PRIVATE | MAP_ANONYMOUS` of target `triple`, BSDs and macOS share the value
+fn map_private_anon(triple: &str) -> u64 {
+    if !triple.contains("linux") {
+        0x02 | 0x1000
+    }
+    else if triple.starts_with("mips") {
+        0x02 | 0x800
+    }
+    else {
+        0x02 | 0x20
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArenaBacking {
+    Malloc,
+    /// Anonymous private mapping, no libc allocator is involved
+    Mmap,
+}
+
+pub struct Arena<'ctx> {
+    pub backing: ArenaBacking,
+    pub arena_t: StructType<'ctx>,
+    pub chunk_t: StructType<'ctx>,
+    prefix: String,
+}
+
+impl<'ctx> Arena<'ctx> {
+    /// Define arena of `backing` in `module`, it's fine to define it again.
+    pub fn define(module: &Module<'ctx>, backing: ArenaBacking) -> Self {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+
+        let chunk_t = module
+            .get_struct_type("arena.chunk")
+            .unwrap_or_else(|| ctx.opaque_struct_type("arena.chunk"));
+        if chunk_t.is_opaque() {
+            chunk_t.set_body(
+                &[chunk_t.ptr_type(AddressSpace::Generic).into(), tys.size_t.into()],
+                false,
+            );
+        }
+
+        let arena_t = get_or_create_struct_type(
+            ctx,
+            module,
+            "arena",
+            Some(&[
+                chunk_t.ptr_type(AddressSpace::Generic).into(),
+                tys.i8ptr_t.into(),
+                tys.i8ptr_t.into(),
+                tys.size_t.into(),
+            ]),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let prefix = match backing {
+            ArenaBacking::Malloc => "arena.malloc",
+            ArenaBacking::Mmap => "arena.mmap",
+        }
+        .to_owned();
+
+        let arena = Self {
+            backing,
+            arena_t,
+            chunk_t,
+            prefix,
+        };
+
+        if module.get_function(&arena.fn_name("create")).is_none() {
+            arena.gen_fns(module);
+        }
+
+        arena
+    }
+
+    fn fn_name(&self, op: &str) -> String {
+        format!("{}.{}", self.prefix, op)
+    }
+
+    fn arena_ptr_t(&self) -> PointerType<'ctx> {
+        self.arena_t.ptr_type(AddressSpace::Generic)
+    }
+
+    fn chunk_ptr_t(&self) -> PointerType<'ctx> {
+        self.chunk_t.ptr_type(AddressSpace::Generic)
+    }
+
+    fn add_fn(
+        &self,
+        module: &Module<'ctx>,
+        op: &str,
+        fn_t: FunctionType<'ctx>,
+    ) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+        let_module_ctx!(ctx = module);
+
+        let fn_val = module.add_function(&self.fn_name(op), fn_t, Some(Linkage::Internal));
+        let builder = ctx.create_builder();
+        builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+        (fn_val, builder)
+    }
+
+    fn build_field(
+        &self,
+        builder: &Builder<'ctx>,
+        arena: PointerValue<'ctx>,
+        idx: u32,
+    ) -> (PointerValue<'ctx>, BasicValueEnum<'ctx>) {
+        let name = ["head", "cur", "end", "chunk_size"][idx as usize];
+        let ptr = builder.build_struct_gep(arena, idx, &format!("{}_ptr", name)).unwrap();
+
+        (ptr, builder.build_load(ptr, name))
+    }
+
+    /// Data right after the chunk header
+    fn build_chunk_data(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        chunk: PointerValue<'ctx>,
+    ) -> PointerValue<'ctx> {
+        let tys = CommonTypes::of_module(module);
+        let data = unsafe { builder.build_in_bounds_gep(chunk, &[tys.i32_t.const_int(1, false)], "") };
+
+        builder.build_bitcast(data, tys.i8ptr_t, "data").into_pointer_value()
+    }
+
+    ///////////////////////////////////
+    //// Generate
+
+    fn gen_fns(&self, module: &Module<'ctx>) {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+
+        let fn_map = self.gen_map(module);
+        let fn_unmap = self.gen_unmap(module);
+        let arena_size = self.arena_t.size_of().unwrap().const_cast(tys.size_t, false);
+
+        ///////////////////////////////////
+        //// create
+
+        let (fn_create, builder) = self.add_fn(
+            module,
+            "create",
+            self.arena_ptr_t().fn_type(&[tys.size_t.into()], false),
+        );
+        let chunk_size = fn_create.get_nth_param(0).unwrap();
+        let raw = ret_as_bv!(builder.build_call(fn_map, &[arena_size.into()], ""));
+        let arena = builder
+            .build_bitcast(raw, self.arena_ptr_t(), "arena")
+            .into_pointer_value();
+        builder.build_store(self.build_field(&builder, arena, 0).0, self.chunk_ptr_t().const_null());
+        builder.build_store(self.build_field(&builder, arena, 1).0, tys.i8ptr_t.const_null());
+        builder.build_store(self.build_field(&builder, arena, 2).0, tys.i8ptr_t.const_null());
+        builder.build_store(self.build_field(&builder, arena, 3).0, chunk_size);
+        builder.build_return(Some(&arena));
+
+        ///////////////////////////////////
+        //// alloc
+
+        let (fn_alloc, builder) = self.add_fn(
+            module,
+            "alloc",
+            tys.i8ptr_t.fn_type(
+                &[self.arena_ptr_t().into(), tys.size_t.into(), tys.size_t.into()],
+                false,
+            ),
+        );
+        let arena = fn_alloc.get_nth_param(0).unwrap().into_pointer_value();
+        let size = fn_alloc.get_nth_param(1).unwrap().into_int_value();
+        let align = fn_alloc.get_nth_param(2).unwrap().into_int_value();
+
+        let blk_fast = ctx.append_basic_block(fn_alloc, "fast");
+        let blk_slow = ctx.append_basic_block(fn_alloc, "slow");
+
+        let (head_ptr, head) = self.build_field(&builder, arena, 0);
+        let (cur_ptr, cur) = self.build_field(&builder, arena, 1);
+        let (end_ptr, end) = self.build_field(&builder, arena, 2);
+        let (_, chunk_size) = self.build_field(&builder, arena, 3);
+
+        // (`addr` aligned up, next `cur`), abort if a huge `size` wraps around
+        let build_bump = |builder: &Builder<'ctx>, cur: PointerValue<'ctx>| {
+            let addr = builder.build_ptr_to_int(cur, tys.size_t, "");
+            let mask = builder.build_int_sub(align, tys.size_t.const_int(1, false), "");
+            let aligned = builder.build_and(
+                build_checked_add(module, builder, addr, mask),
+                builder.build_not(mask, ""),
+                "aligned",
+            );
+
+            (aligned, build_checked_add(module, builder, aligned, size))
+        };
+
+        let (aligned, nxt) = build_bump(&builder, cur.into_pointer_value());
+        let end_addr = builder.build_ptr_to_int(end.into_pointer_value(), tys.size_t, "");
+        let fits = builder.build_and(
+            builder.build_is_not_null(cur.into_pointer_value(), ""),
+            builder.build_int_compare(IntPredicate::ULE, nxt, end_addr, ""),
+            "fits",
+        );
+        builder.build_conditional_branch(fits, blk_fast, blk_slow);
+
+        builder.position_at_end(blk_fast);
+        builder.build_store(cur_ptr, builder.build_int_to_ptr(nxt, tys.i8ptr_t, ""));
+        builder.build_return(Some(&builder.build_int_to_ptr(aligned, tys.i8ptr_t, "")));
+
+        // new chunk of `max(chunk_size, size + align)` data
+        builder.position_at_end(blk_slow);
+        let chunk_size = chunk_size.into_int_value();
+        let least = build_checked_add(module, &builder, size, align);
+        let is_large = builder.build_int_compare(IntPredicate::UGT, least, chunk_size, "");
+        let data_size = builder.build_select(is_large, least, chunk_size, "").into_int_value();
+        let hdr_size = self.chunk_t.size_of().unwrap().const_cast(tys.size_t, false);
+        let total = build_checked_add(module, &builder, data_size, hdr_size);
+
+        let raw = ret_as_bv!(builder.build_call(fn_map, &[total.into()], "")).into_pointer_value();
+        let chunk = builder
+            .build_bitcast(raw, self.chunk_ptr_t(), "chunk")
+            .into_pointer_value();
+        builder.build_store(builder.build_struct_gep(chunk, 0, "").unwrap(), head);
+        builder.build_store(builder.build_struct_gep(chunk, 1, "").unwrap(), total);
+        builder.build_store(head_ptr, chunk);
+        builder.build_store(end_ptr, unsafe { builder.build_in_bounds_gep(raw, &[total], "end") });
+
+        let (aligned, nxt) = build_bump(&builder, self.build_chunk_data(module, &builder, chunk));
+        builder.build_store(cur_ptr, builder.build_int_to_ptr(nxt, tys.i8ptr_t, ""));
+        builder.build_return(Some(&builder.build_int_to_ptr(aligned, tys.i8ptr_t, "")));
+
+        ///////////////////////////////////
+        //// release chains of chunks
+
+        let (fn_release, builder) = self.add_fn(
+            module,
+            "release",
+            tys.void_t.fn_type(&[self.chunk_ptr_t().into()], false),
+        );
+        let first = fn_release.get_nth_param(0).unwrap().into_pointer_value();
+
+        let blk_entry = builder.get_insert_block().unwrap();
+        let blk_loop = ctx.append_basic_block(fn_release, "loop");
+        let blk_free = ctx.append_basic_block(fn_release, "free");
+        let blk_done = ctx.append_basic_block(fn_release, "done");
+        builder.build_unconditional_branch(blk_loop);
+
+        builder.position_at_end(blk_loop);
+        let chunk_phi = builder.build_phi(self.chunk_ptr_t(), "chunk");
+        let chunk = chunk_phi.as_basic_value().into_pointer_value();
+        builder.build_conditional_branch(builder.build_is_null(chunk, ""), blk_done, blk_free);
+
+        builder.position_at_end(blk_free);
+        let prev = builder.build_load(builder.build_struct_gep(chunk, 0, "").unwrap(), "prev");
+        let total = builder.build_load(builder.build_struct_gep(chunk, 1, "").unwrap(), "size");
+        let raw = builder.build_bitcast(chunk, tys.i8ptr_t, "");
+        builder.build_call(fn_unmap, &[raw.into(), total.into()], "");
+        builder.build_unconditional_branch(blk_loop);
+
+        chunk_phi.add_incoming(&[(&first, blk_entry), (&prev, blk_free)]);
+
+        builder.position_at_end(blk_done);
+        builder.build_return(None);
+
+        ///////////////////////////////////
+        //// reset
+
+        let (fn_reset, builder) = self.add_fn(
+            module,
+            "reset",
+            tys.void_t.fn_type(&[self.arena_ptr_t().into()], false),
+        );
+        let arena = fn_reset.get_nth_param(0).unwrap().into_pointer_value();
+
+        let blk_keep = ctx.append_basic_block(fn_reset, "keep");
+        let blk_done = ctx.append_basic_block(fn_reset, "done");
+
+        let (_, head) = self.build_field(&builder, arena, 0);
+        let head = head.into_pointer_value();
+        builder.build_conditional_branch(builder.build_is_null(head, ""), blk_done, blk_keep);
+
+        builder.position_at_end(blk_keep);
+        let prev_ptr = builder.build_struct_gep(head, 0, "prev_ptr").unwrap();
+        let prev = builder.build_load(prev_ptr, "prev");
+        builder.build_call(fn_release, &[prev.into()], "");
+        builder.build_store(prev_ptr, self.chunk_ptr_t().const_null());
+        let data = self.build_chunk_data(module, &builder, head);
+        builder.build_store(self.build_field(&builder, arena, 1).0, data);
+        builder.build_unconditional_branch(blk_done);
+
+        builder.position_at_end(blk_done);
+        builder.build_return(None);
+
+        ///////////////////////////////////
+        //// destroy
+
+        let (fn_destroy, builder) = self.add_fn(
+            module,
+            "destroy",
+            tys.void_t.fn_type(&[self.arena_ptr_t().into()], false),
+        );
+        let arena = fn_destroy.get_nth_param(0).unwrap().into_pointer_value();
+        let (_, head) = self.build_field(&builder, arena, 0);
+        builder.build_call(fn_release, &[head.into()], "");
+        let raw = builder.build_bitcast(arena, tys.i8ptr_t, "");
+        builder.build_call(fn_unmap, &[raw.into(), arena_size.into()], "");
+        builder.build_return(None);
+    }
+
+    /// `map(size) -> i8*`, abort on failure
+    fn gen_map(&self, module: &Module<'ctx>) -> FunctionValue<'ctx> {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+
+        VMMod::include_stdlib(module);
+        let fn_abort = module.get_function("abort").unwrap();
+
+        let (fn_map, builder) = self.add_fn(module, "map", tys.i8ptr_t.fn_type(&[tys.size_t.into()], false));
+        let size = fn_map.get_nth_param(0).unwrap();
+
+        let (raw, failed) = match self.backing {
+            ArenaBacking::Malloc => {
+                let fn_malloc = module.get_function("malloc").unwrap();
+                let raw = ret_as_bv!(builder.build_call(fn_malloc, &[size.into()], "")).into_pointer_value();
+
+                (raw, builder.build_is_null(raw, "failed"))
+            }
+            ArenaBacking::Mmap => {
+                VMMod::include_mman(module);
+                let fn_mmap = module.get_function("mmap").unwrap();
+
+                // the host's unless the triple is set before
+                let triple = module.get_triple();
+                let triple = match triple.as_str().to_str() {
+                    Ok("") | Err(_) => TargetMachine::get_default_triple().as_str().to_string_lossy().into_owned(),
+                    Ok(triple) => triple.to_owned(),
+                };
+
+                let raw = ret_as_bv!(builder.build_call(
+                    fn_mmap,
+                    &[
+                        tys.i8ptr_t.const_null().into(),
+                        size.into(),
+                        tys.i32_t.const_int(PROT_READ_WRITE, false).into(),
+                        tys.i32_t.const_int(map_private_anon(&triple), false).into(),
+                        tys.i32_t.const_int(-1i64 as u64, true).into(),
+                        tys.i64_t.const_zero().into(),
+                    ],
+                    "",
+                ))
+                .into_pointer_value();
+
+                // `MAP_FAILED`
+                let addr = builder.build_ptr_to_int(raw, tys.size_t, "");
+                let failed = builder.build_int_compare(IntPredicate::EQ, addr, tys.size_t.const_all_ones(), "failed");
+
+                (raw, failed)
+            }
+        };
+
+        let blk_oom = ctx.append_basic_block(fn_map, "oom");
+        let blk_ok = ctx.append_basic_block(fn_map, "ok");
+        builder.build_conditional_branch(failed, blk_oom, blk_ok);
+
+        builder.position_at_end(blk_oom);
+        builder.build_call(fn_abort, &[], "");
+        builder.build_unreachable();
+
+        builder.position_at_end(blk_ok);
+        builder.build_return(Some(&raw));
+
+        fn_map
+    }
+
+    /// `unmap(i8*, size)`
+    fn gen_unmap(&self, module: &Module<'ctx>) -> FunctionValue<'ctx> {
+        let tys = CommonTypes::of_module(module);
+
+        let (fn_unmap, builder) = self.add_fn(
+            module,
+            "unmap",
+            tys.void_t.fn_type(&[tys.i8ptr_t.into(), tys.size_t.into()], false),
+        );
+        let raw = fn_unmap.get_nth_param(0).unwrap();
+        let size = fn_unmap.get_nth_param(1).unwrap();
+
+        match self.backing {
+            ArenaBacking::Malloc => {
+                let fn_free = module.get_function("free").unwrap();
+                builder.build_call(fn_free, &[raw.into()], "");
+            }
+            ArenaBacking::Mmap => {
+                let fn_munmap = module.get_function("munmap").unwrap();
+                builder.build_call(fn_munmap, &[raw.into(), size.into()], "");
+            }
+        }
+        builder.build_return(None);
+
+        fn_unmap
+    }
+
+    ///////////////////////////////////
+    //// Call
+
+    fn build_call_op(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        op: &str,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> Option<BasicValueEnum<'ctx>> {
+        let fn_val = module
+            .get_function(&self.fn_name(op))
+            .unwrap_or_else(|| panic!("{} isn't defined in module", self.fn_name(op)));
+
+        builder.build_call(fn_val, args, "").try_as_basic_value().left()
+    }
+
+    /// New arena allocating chunks of `chunk_size` bytes (larger one for large value)
+    pub fn build_create(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        chunk_size: IntValue<'ctx>,
+    ) -> PointerValue<'ctx> {
+        self.build_call_op(module, builder, "create", &[chunk_size.into()])
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    /// `size` bytes aligned to `align` (power of two), return `i8*`.
+    pub fn build_alloc(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        arena: PointerValue<'ctx>,
+        size: IntValue<'ctx>,
+        align: IntValue<'ctx>,
+    ) -> PointerValue<'ctx> {
+        self.build_call_op(module, builder, "alloc", &[arena.into(), size.into(), align.into()])
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    /// Invalidate all the allocations, and keep the newest chunk for reuse.
+    pub fn build_reset(&self, module: &Module<'ctx>, builder: &Builder<'ctx>, arena: PointerValue<'ctx>) {
+        self.build_call_op(module, builder, "reset", &[arena.into()]);
+    }
+
+    pub fn build_destroy(&self, module: &Module<'ctx>, builder: &Builder<'ctx>, arena: PointerValue<'ctx>) {
+        self.build_call_op(module, builder, "destroy", &[arena.into()]);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, AddressSpace};
+
+    use super::{map_private_anon, Arena, ArenaBacking};
+    use crate::{
+        compiler::host_target_data,
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_arena() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_arena");
+        let module = &vmmod.module;
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+        let i64ptr_t = tys.i64_t.ptr_type(AddressSpace::Generic);
+
+        // (addrs*) -> value stored through the typed allocation
+        for (name, backing) in [("arena_malloc", ArenaBacking::Malloc), ("arena_mmap", ArenaBacking::Mmap)] {
+            let arena_def = Arena::define(module, backing);
+            Arena::define(module, backing);
+
+            let (fn_val, builder) = test_fn(&vmmod, name, tys.i64_t.fn_type(&[i64ptr_t.into()], false));
+            let addrs = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+            let store_addr = |i: usize, ptr| {
+                let slot = unsafe { builder.build_in_bounds_gep(addrs, &[vmmod.usize(i)], "") };
+                builder.build_store(slot, builder.build_ptr_to_int(ptr, tys.i64_t, ""));
+            };
+
+            let arena = arena_def.build_create(module, &builder, vmmod.usize(64));
+
+            // the third is larger than a chunk
+            for (i, (size, align)) in [(3, 1), (8, 8), (100, 16)].into_iter().enumerate() {
+                let ptr = arena_def.build_alloc(module, &builder, arena, vmmod.usize(size), vmmod.usize(align));
+                store_addr(i, ptr);
+            }
+
+            let elems = vmmod.build_arena_alloc(&builder, &target_data, &arena_def, arena, tys.i64_t.into(), vmmod.i32(4));
+            store_addr(3, elems);
+            let last = unsafe { builder.build_in_bounds_gep(elems, &[vmmod.usize(3)], "") };
+            builder.build_store(last, tys.i64_t.const_int(7, false));
+            let value = builder.build_load(last, "");
+
+            arena_def.build_reset(module, &builder, arena);
+            let ptr = arena_def.build_alloc(module, &builder, arena, vmmod.usize(8), vmmod.usize(8));
+            store_addr(4, ptr);
+
+            arena_def.build_destroy(module, &builder, arena);
+            builder.build_return(Some(&value));
+        }
+
+        let ee = jit(&vmmod);
+
+        for name in ["arena_malloc", "arena_mmap"] {
+            let f = unsafe { ee.get_function::<unsafe extern "C" fn(*mut u64) -> i64>(name) }.unwrap();
+            let mut addrs = [0u64; 5];
+
+            assert_eq!(unsafe { f.call(addrs.as_mut_ptr()) }, 7, "{}", name);
+
+            let [a, b, c, d, e] = addrs;
+            assert!(addrs.iter().all(|addr| *addr != 0), "{}", name);
+            assert!(b % 8 == 0 && c % 16 == 0 && d % 8 == 0 && e % 8 == 0, "{}", name);
+            // no overlap
+            assert!(a + 3 <= b || b + 8 <= a, "{}", name);
+            assert!(c + 100 <= d || d + 32 <= c, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_map_private_anon() {
+        assert_eq!(map_private_anon("x86_64-unknown-linux-gnu"), 0x22);
+        assert_eq!(map_private_anon("aarch64-unknown-linux-musl"), 0x22);
+        assert_eq!(map_private_anon("mipsel-unknown-linux-gnu"), 0x802);
+        assert_eq!(map_private_anon("x86_64-apple-darwin"), 0x1002);
+        assert_eq!(map_private_anon("x86_64-unknown-freebsd"), 0x1002);
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+use inkwell::{module::Module, targets::TargetMachine};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compiler::{reloc_mode, CODE_MODEL},
+    config::CompilerConfig,
+};
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Content Hash
+
+const DIGEST_LEN: usize = 32;
+
+/// SHA-256 of the fields, which is stable across processes and toolchains
+/// (unlike `DefaultHasher`)
+struct KeyHasher {
+    sha: Sha256,
+}
+
+impl KeyHasher {
+    fn new() -> Self {
+        Self { sha: Sha256::new() }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // length prefix keeps field boundaries distinct
+        self.sha.update((bytes.len() as u64).to_le_bytes());
+        self.sha.update(bytes);
+    }
+
+    fn finish(self) -> String {
+        hex(&self.sha.finalize())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Object Cache
+
+static TMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// On-disk content-addressed cache of emitted modules.
+///
+/// Entries are published by atomic rename, so it's safe to be shared by concurrent processes.
+/// Each entry ends with SHA-256 of its content, corrupted ones are dropped as misses.
+/// When total size exceeds `max_size`, least recently used entries are evicted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ObjCache {
+    pub dir: PathBuf,
+    /// bytes
+    pub max_size: u64,
+}
+
+impl ObjCache {
+    pub fn new<P: AsRef<Path>>(dir: P, max_size: u64) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+            max_size,
+        }
+    }
+
+    /// Hash of module bitcode plus the codegen related config and target machine
+    pub fn key<'ctx>(
+        &self,
+        config: &CompilerConfig,
+        machine: &TargetMachine,
+        module: &Module<'ctx>,
+    ) -> String {
+        let mut hasher = KeyHasher::new();
+
+        hasher.write(module.write_bitcode_to_memory().as_slice());
+        hasher.write(machine.get_triple().as_str().to_bytes());
+        hasher.write(machine.get_cpu().to_bytes());
+        hasher.write(machine.get_feature_string().to_bytes());
+        hasher.write(format!("{:?}", reloc_mode(config)).as_bytes());
+        hasher.write(format!("{:?}", CODE_MODEL).as_bytes());
+        hasher.write(format!("{:?}", config.optlv).as_bytes());
+        hasher.write(format!("{:?}", config.target_type).as_bytes());
+        hasher.write(format!("{:?}", config.emit_type).as_bytes());
+
+        hasher.finish()
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Copy cached entry to `dst`, return false if cache miss.
+    pub fn fetch(&self, key: &str, dst: &Path) -> io::Result<bool> {
+        let entry = self.entry_path(key);
+
+        let bytes = match fs::read(&entry) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        let content = match bytes.len().checked_sub(DIGEST_LEN) {
+            Some(len) if Sha256::digest(&bytes[..len])[..] == bytes[len..] => &bytes[..len],
+            // truncated or corrupted, it'd be replaced by the next store
+            _ => {
+                fs::remove_file(&entry).ok();
+                return Ok(false);
+            }
+        };
+
+        fs::write(dst, content)?;
+
+        // mark recently used, it's fine to lose the race with eviction
+        if let Ok(file) = File::options().append(true).open(&entry) {
+            file.set_modified(SystemTime::now()).ok();
+        }
+
+        Ok(true)
+    }
+
+    /// Publish `src` as the entry of `key`
+    pub fn store(&self, key: &str, src: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let tmp = self.dir.join(format!(
+            ".tmp-{}-{}-{}",
+            key,
+            process::id(),
+            TMP_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut bytes = fs::read(src)?;
+        let digest = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&digest);
+
+        fs::write(&tmp, bytes)?;
+
+        if let Err(err) = fs::rename(&tmp, self.entry_path(key)) {
+            fs::remove_file(&tmp).ok();
+            return Err(err);
+        }
+
+        self.evict()
+    }
+
+    /// Remove least recently used entries until total size fits `max_size`
+    pub fn evict(&self) -> io::Result<()> {
+        let mut entries = vec![];
+        let mut total = 0;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            // entry may be removed by other process
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+
+            total += meta.len();
+            entries.push((
+                meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                meta.len(),
+                entry.path(),
+            ));
+        }
+
+        if total <= self.max_size {
+            return Ok(());
+        }
+
+        entries.sort();
+
+        for (_, len, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+
+            match fs::remove_file(&path) {
+                Ok(()) => total -= len,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => total -= len,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{KeyHasher, ObjCache, DIGEST_LEN};
+    use crate::tests::test_dir;
+
+    fn hash(fields: &[&[u8]]) -> String {
+        let mut hasher = KeyHasher::new();
+        for field in fields {
+            hasher.write(field);
+        }
+        hasher.finish()
+    }
+
+    fn set_mtime(cache: &ObjCache, key: &str, secs: u64) {
+        File::options()
+            .append(true)
+            .open(cache.entry_path(key))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_key_hasher() {
+        let key = hash(&[b"abc", b"def"]);
+
+        assert_eq!(key.len(), DIGEST_LEN * 2);
+        assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(key, hash(&[b"abc", b"def"]));
+
+        // field boundaries and order matter
+        assert_ne!(key, hash(&[b"abcdef"]));
+        assert_ne!(key, hash(&[b"ab", b"cdef"]));
+        assert_ne!(key, hash(&[b"def", b"abc"]));
+        assert_ne!(hash(&[]), hash(&[b""]));
+    }
+
+    #[test]
+    fn test_store_fetch() {
+        let dir = test_dir("cache-roundtrip");
+        let cache = ObjCache::new(dir.join("cache"), 1 << 20);
+        let src = dir.join("src.o");
+        let dst = dir.join("dst.o");
+
+        assert!(!cache.fetch("k0", &dst).unwrap());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&src, b"object code").unwrap();
+        cache.store("k0", &src).unwrap();
+
+        assert!(cache.fetch("k0", &dst).unwrap());
+        assert_eq!(fs::read(&dst).unwrap(), b"object code");
+
+        // corrupted entry is a miss and gets removed
+        let entry = cache.entry_path("k0");
+        let mut bytes = fs::read(&entry).unwrap();
+        bytes[0] ^= 1;
+        fs::write(&entry, bytes).unwrap();
+
+        assert!(!cache.fetch("k0", &dst).unwrap());
+        assert!(!entry.exists());
+
+        // truncated below the digest
+        fs::write(&entry, b"short").unwrap();
+        assert!(!cache.fetch("k0", &dst).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_evict_lru() {
+        let dir = test_dir("cache-lru");
+        let src = dir.join("src.o");
+        let entry_len = 100 + DIGEST_LEN as u64;
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&src, [0u8; 100]).unwrap();
+
+        let cache = ObjCache::new(dir.join("cache"), entry_len * 3);
+        cache.store("a", &src).unwrap();
+        cache.store("b", &src).unwrap();
+        set_mtime(&cache, "a", 100);
+        set_mtime(&cache, "b", 200);
+
+        // a becomes the most recently used
+        assert!(cache.fetch("a", &dir.join("dst.o")).unwrap());
+
+        let cache = ObjCache::new(dir.join("cache"), entry_len * 2);
+        cache.store("c", &src).unwrap();
+
+        assert!(cache.entry_path("a").exists());
+        assert!(!cache.entry_path("b").exists());
+        assert!(cache.entry_path("c").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
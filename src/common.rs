@@ -0,0 +1,199 @@
+use std::{cell::RefCell, mem};
+
+use inkwell::{
+    context::Context,
+    module::Module,
+    types::{AsTypeRef, FloatType, IntType, PointerType, VoidType},
+    AddressSpace,
+};
+
+
+thread_local! {
+    /// Types of the contexts whose lifetime is known here (the thread-local `CTX`
+    /// and scoped ones), keyed by their `i8` type which is unique per context.
+    ///
+    /// An entry is removed before its context is dropped,
+    /// so a context reusing the address never sees stale types.
+    static CACHE: RefCell<Vec<(usize, CommonTypes<'static>)>> = RefCell::new(vec![]);
+}
+
+pub(crate) fn cache_key(ctx: &Context) -> usize {
+    ctx.i8_type().as_type_ref() as usize
+}
+
+#[cfg(test)]
+pub(crate) fn is_cached(key: usize) -> bool {
+    CACHE.with(|cache| cache.borrow().iter().any(|(k, _)| *k == key))
+}
+
+/// Frequently used types of one context, cheap to copy around.
+///
+/// `load_vm_common_ty!` destructures it,
+/// so the field names are also the names of its local bindings.
+#[derive(Clone, Copy)]
+pub struct CommonTypes<'ctx> {
+    pub i1_t: IntType<'ctx>,
+    pub i8_t: IntType<'ctx>,
+    pub i16_t: IntType<'ctx>,
+    pub i32_t: IntType<'ctx>,
+    pub i64_t: IntType<'ctx>,
+    pub i128_t: IntType<'ctx>,
+    /// same width as the host `usize`
+    pub size_t: IntType<'ctx>,
+
+    pub void_t: VoidType<'ctx>,
+
+    pub f32_t: FloatType<'ctx>,
+    pub f64_t: FloatType<'ctx>,
+
+    /// pointer to any, that is `i8*`
+    pub ptr_t: PointerType<'ctx>,
+    pub i8ptr_t: PointerType<'ctx>,
+    pub i16ptr_t: PointerType<'ctx>,
+    pub i32ptr_t: PointerType<'ctx>,
+    pub i64ptr_t: PointerType<'ctx>,
+    pub i128ptr_t: PointerType<'ctx>,
+    pub sizeptr_t: PointerType<'ctx>,
+    pub i8ptr2_t: PointerType<'ctx>,
+    pub f32ptr_t: PointerType<'ctx>,
+    pub f64ptr_t: PointerType<'ctx>,
+}
+
+impl<'ctx> CommonTypes<'ctx> {
+    /// Types of `ctx`, cached ones if it's registered.
+    pub fn get(ctx: &'ctx Context) -> Self {
+        let key = cache_key(ctx);
+
+        let cached = CACHE.with(|cache| {
+            cache
+                .borrow()
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, tys)| *tys)
+        });
+
+        match cached {
+            // SAFETY: registered context outlives its entry
+            Some(tys) => unsafe { mem::transmute::<CommonTypes<'static>, Self>(tys) },
+            None => Self::new(ctx),
+        }
+    }
+
+    /// Types of the context of `module` (which may be a scoped one)
+    pub fn of_module(module: &Module<'ctx>) -> Self {
+        let ctx_ref = module.get_context();
+
+        // The reference doesn't escape `ctx_ref`, types don't borrow it.
+        Self::get(unsafe { ctx_ref.get() })
+    }
+
+    /// Build the types once for `ctx` and serve `get` from them until `unregister`.
+    ///
+    /// `ctx` must be unregistered before it's dropped.
+    pub(crate) fn register(ctx: &'ctx Context) {
+        let key = cache_key(ctx);
+        let tys = unsafe { mem::transmute::<Self, CommonTypes<'static>>(Self::new(ctx)) };
+
+        CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+
+            if !cache.iter().any(|(k, _)| *k == key) {
+                cache.push((key, tys));
+            }
+        });
+    }
+
+    pub(crate) fn unregister(ctx: &'ctx Context) {
+        let key = cache_key(ctx);
+
+        CACHE.with(|cache| cache.borrow_mut().retain(|(k, _)| *k != key));
+    }
+
+    /// Build the types without looking at the cache
+    pub fn new(ctx: &'ctx Context) -> Self {
+        let i8_t = ctx.i8_type();
+        let i16_t = ctx.i16_type();
+        let i32_t = ctx.i32_type();
+        let i64_t = ctx.i64_type();
+        let i128_t = ctx.i128_type();
+
+        #[cfg(target_pointer_width = "64")]
+        let size_t = i64_t;
+        #[cfg(target_pointer_width = "32")]
+        let size_t = i32_t;
+
+        let f32_t = ctx.f32_type();
+        let f64_t = ctx.f64_type();
+
+        let i8ptr_t = i8_t.ptr_type(AddressSpace::Generic);
+
+        Self {
+            i1_t: ctx.bool_type(),
+            i8_t,
+            i16_t,
+            i32_t,
+            i64_t,
+            i128_t,
+            size_t,
+            void_t: ctx.void_type(),
+            f32_t,
+            f64_t,
+            ptr_t: i8ptr_t,
+            i8ptr_t,
+            i16ptr_t: i16_t.ptr_type(AddressSpace::Generic),
+            i32ptr_t: i32_t.ptr_type(AddressSpace::Generic),
+            i64ptr_t: i64_t.ptr_type(AddressSpace::Generic),
+            i128ptr_t: i128_t.ptr_type(AddressSpace::Generic),
+            sizeptr_t: size_t.ptr_type(AddressSpace::Generic),
+            i8ptr2_t: i8ptr_t.ptr_type(AddressSpace::Generic),
+            f32ptr_t: f32_t.ptr_type(AddressSpace::Generic),
+            f64ptr_t: f64_t.ptr_type(AddressSpace::Generic),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, AddressSpace};
+
+    use super::{cache_key, is_cached, CommonTypes};
+    use crate::ScopedCtx;
+
+    #[test]
+    fn test_common_types_of_context() {
+        let a = ScopedCtx::new();
+        let b = ScopedCtx::new();
+        let plain = Context::create();
+
+        for ctx in [a.ctx(), b.ctx(), &plain] {
+            let tys = CommonTypes::get(ctx);
+
+            assert!(tys.i64_t == ctx.i64_type());
+            assert!(tys.ptr_t == ctx.i8_type().ptr_type(AddressSpace::Generic));
+            assert!(CommonTypes::of_module(&ctx.create_module("m")).f64_t == ctx.f64_type());
+        }
+
+        assert!(CommonTypes::get(a.ctx()).i8_t != CommonTypes::get(b.ctx()).i8_t);
+        assert!(!is_cached(cache_key(&plain)));
+    }
+
+    #[test]
+    fn test_common_types_after_drop() {
+        let scoped = ScopedCtx::new();
+        let key = cache_key(scoped.ctx());
+        assert!(is_cached(key));
+
+        drop(scoped);
+        assert!(!is_cached(key));
+
+        // a context at the same address doesn't get the dropped one's types
+        for _ in 0..4 {
+            let ctx = Context::create();
+            let tys = CommonTypes::get(&ctx);
+
+            assert!(tys.i8_t == ctx.i8_type());
+            assert!(tys.i32ptr_t == ctx.i32_type().ptr_type(AddressSpace::Generic));
+        }
+    }
+}
@@ -0,0 +1,765 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+};
+
+use inkwell::{
+    context::Context,
+    memory_buffer::MemoryBuffer,
+    module::{Linkage, Module},
+    passes::{PassManager, PassManagerBuilder},
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target,
+        TargetData, TargetMachine,
+    },
+    values::BasicValue,
+    OptimizationLevel,
+};
+
+use crate::{
+    config::{CompilerConfig, EmitType, OptLv, TargetType},
+    export::{apply_exports, c_decls, check_exports_defined, gen_c_header, gen_version_script},
+    VMMod,
+};
+
+
+pub type CompileResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Target Machine
+
+pub(crate) const CODE_MODEL: CodeModel = CodeModel::Default;
+
+pub(crate) fn reloc_mode(config: &CompilerConfig) -> RelocMode {
+    match config.target_type {
+        TargetType::DyLib => RelocMode::PIC,
+        _ => RelocMode::Default,
+    }
+}
+
+pub fn init_target_machine(
+    config: &CompilerConfig,
+) -> CompileResult<TargetMachine> {
+    Target::initialize_native(&InitializationConfig::default())?;
+
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|err| err.to_string())?;
+
+    target
+        .create_target_machine(
+            &triple,
+            "generic",
+            "",
+            config.optlv.into(),
+            reloc_mode(config),
+            CODE_MODEL,
+        )
+        .ok_or_else(|| {
+            format!("create target machine failed: {}", triple).into()
+        })
+}
+
+/// Data layout of the native target, for size and offset queries
+pub fn host_target_data() -> CompileResult<TargetData> {
+    Target::initialize_native(&InitializationConfig::default())?;
+
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|err| err.to_string())?;
+
+    let machine = target
+        .create_target_machine(
+            &triple,
+            "generic",
+            "",
+            OptimizationLevel::None,
+            RelocMode::Default,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| format!("create target machine failed: {}", triple))?;
+
+    Ok(machine.get_target_data())
+}
+
+pub fn emit_ext(emit_type: EmitType) -> &'static str {
+    match emit_type {
+        EmitType::LLVMIR => "ll",
+        EmitType::Bitcode => "bc",
+        EmitType::Asm => "s",
+        EmitType::Obj => "o",
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Emit
+
+pub fn set_target<'ctx>(machine: &TargetMachine, module: &Module<'ctx>) {
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+}
+
+/// Set triple/data layout of `module` to `machine`, verify and write it to `path`
+pub fn emit_module<'ctx>(
+    config: &CompilerConfig,
+    machine: &TargetMachine,
+    module: &Module<'ctx>,
+    path: &Path,
+) -> CompileResult<()> {
+    set_target(machine, module);
+
+    module.verify().map_err(|err| err.to_string())?;
+
+    match config.emit_type {
+        EmitType::LLVMIR => {
+            module.print_to_file(path).map_err(|err| err.to_string())?
+        }
+        EmitType::Bitcode => {
+            if !module.write_bitcode_to_path(path) {
+                return Err(format!("write bitcode to {:?} failed", path).into());
+            }
+        }
+        EmitType::Asm => machine
+            .write_to_file(module, FileType::Assembly, path)
+            .map_err(|err| err.to_string())?,
+        EmitType::Obj => machine
+            .write_to_file(module, FileType::Object, path)
+            .map_err(|err| err.to_string())?,
+    }
+
+    Ok(())
+}
+
+
+/// Same as `emit_module`, but return the cached output when `config.cache` is set.
+pub fn emit_module_cached<'ctx>(
+    config: &CompilerConfig,
+    machine: &TargetMachine,
+    module: &Module<'ctx>,
+    path: &Path,
+) -> CompileResult<()> {
+    let cache = match config.cache {
+        Some(ref cache) => cache,
+        None => return emit_module(config, machine, module, path),
+    };
+
+    set_target(machine, module);
+    let key = cache.key(config, machine, module);
+
+    if cache.fetch(&key, path)? {
+        return Ok(());
+    }
+
+    emit_module(config, machine, module, path)?;
+    cache.store(&key, path)?;
+
+    Ok(())
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Link
+
+pub fn link(
+    target_type: TargetType,
+    output: &Path,
+    input_list: &[PathBuf],
+    args: &[String],
+) -> CompileResult<()> {
+    let mut cmd = match target_type {
+        TargetType::Bin => Command::new("gcc"),
+        TargetType::DyLib => {
+            let mut cmd = Command::new("gcc");
+            cmd.arg("-shared");
+            cmd
+        }
+        TargetType::ReLoc => {
+            let mut cmd = Command::new("ld");
+            cmd.arg("-r");
+            cmd
+        }
+    };
+
+    let status = cmd
+        .args(input_list)
+        .args(args)
+        .arg("-o")
+        .arg(output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::inherit())
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("link {:?} failed: {}", output, status).into());
+    }
+
+    Ok(())
+}
+
+
+/// Static library of `inkwellkit_rt`: `config.runtime_lib`, else `$INKWELLKIT_RT`.
+pub fn runtime_lib(config: &CompilerConfig) -> CompileResult<Option<PathBuf>> {
+    let path = match config.runtime_lib {
+        Some(ref path) => path.clone(),
+        None => match env::var_os("INKWELLKIT_RT") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        },
+    };
+
+    if !path.exists() {
+        return Err(format!("runtime library {:?} not found", path).into());
+    }
+
+    Ok(Some(path))
+}
+
+/// If any `rt_*` of the runtime library is called
+pub fn uses_runtime<'ctx>(module: &Module<'ctx>) -> bool {
+    let mut fn_cur = module.get_first_function();
+    while let Some(f) = fn_cur {
+        if f.count_basic_blocks() == 0
+            && f.get_name().to_bytes().starts_with(b"rt_")
+            && f.as_global_value().as_pointer_value().get_first_use().is_some()
+        {
+            return true;
+        }
+
+        fn_cur = f.get_next_function();
+    }
+
+    false
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Parallel Compile
+
+pub type ModBuildFn =
+    Box<dyn for<'ctx> FnOnce(&VMMod<'ctx>) -> CompileResult<()> + Send>;
+
+/// A module to be constructed on a worker thread (with that thread's `CTX`)
+pub struct ModBuildJob {
+    pub name: String,
+    pub build: ModBuildFn,
+}
+
+impl ModBuildJob {
+    pub fn new<F>(name: &str, build: F) -> Self
+    where
+        F: for<'ctx> FnOnce(&VMMod<'ctx>) -> CompileResult<()> + Send + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            build: Box::new(build),
+        }
+    }
+}
+
+/// Shared library exports and their C declarations
+struct ExportDecls {
+    /// exports found defined
+    defined: BTreeSet<String>,
+    structs: BTreeSet<String>,
+    decls: Vec<String>,
+}
+
+impl ExportDecls {
+    fn new() -> Self {
+        Self {
+            defined: BTreeSet::new(),
+            structs: BTreeSet::new(),
+            decls: vec![],
+        }
+    }
+
+    fn extend(&mut self, other: Self) {
+        self.defined.extend(other.defined);
+        self.structs.extend(other.structs);
+        self.decls.extend(other.decls);
+    }
+}
+
+fn exports_enabled(config: &CompilerConfig) -> bool {
+    config.target_type == TargetType::DyLib && !config.exports.is_empty()
+}
+
+fn prepare_exports<'ctx>(
+    config: &CompilerConfig,
+    module: &Module<'ctx>,
+) -> CompileResult<ExportDecls> {
+    let mut export_decls = ExportDecls::new();
+
+    if !exports_enabled(config) {
+        return Ok(export_decls);
+    }
+
+    export_decls.defined = apply_exports(module, &config.exports);
+
+    if config.c_header.is_some() {
+        export_decls.decls =
+            c_decls(module, &config.exports, &mut export_decls.structs)?;
+    }
+
+    Ok(export_decls)
+}
+
+fn build_and_emit(
+    config: &CompilerConfig,
+    job: ModBuildJob,
+    outdir: &Path,
+) -> CompileResult<(PathBuf, ExportDecls, bool)> {
+    let machine = init_target_machine(config)?;
+
+    let mut vmmod = VMMod::new(&job.name);
+    vmmod.bounds_check = config.bounds_check;
+    (job.build)(&vmmod)?;
+
+    let export_decls = prepare_exports(config, &vmmod.module)?;
+
+    let path =
+        outdir.join(format!("{}.{}", job.name, emit_ext(config.emit_type)));
+    emit_module_cached(config, &machine, &vmmod.module, &path)?;
+
+    Ok((path, export_decls, uses_runtime(&vmmod.module)))
+}
+
+/// (name, bitcode)
+fn build_bitcode(
+    config: &CompilerConfig,
+    job: ModBuildJob,
+) -> CompileResult<(String, Vec<u8>)> {
+    let mut vmmod = VMMod::new(&job.name);
+    vmmod.bounds_check = config.bounds_check;
+    (job.build)(&vmmod)?;
+
+    let bitcode = vmmod.module.write_bitcode_to_memory().as_slice().to_vec();
+
+    Ok((job.name, bitcode))
+}
+
+/// Run `f` over `jobs` on a thread pool, return outputs in the order of `jobs`.
+fn run_pool<T, F>(jobs: Vec<ModBuildJob>, f: F) -> CompileResult<Vec<T>>
+where
+    T: Send,
+    F: Fn(ModBuildJob) -> CompileResult<T> + Sync,
+{
+    let njobs = jobs.len();
+    let nthreads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(njobs)
+        .max(1);
+
+    let queue = Mutex::new(jobs.into_iter().enumerate().collect::<VecDeque<_>>());
+
+    let mut outputs = thread::scope(|s| {
+        let workers = (0..nthreads)
+            .map(|_| {
+                s.spawn(|| -> CompileResult<Vec<(usize, T)>> {
+                    let mut outputs = vec![];
+
+                    loop {
+                        let job = queue.lock().unwrap().pop_front();
+
+                        match job {
+                            Some((i, job)) => outputs.push((i, f(job)?)),
+                            None => break,
+                        }
+                    }
+
+                    Ok(outputs)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut outputs = vec![];
+        for worker in workers {
+            outputs.extend(
+                worker.join().map_err(|_| "compile worker panicked")??,
+            );
+        }
+
+        CompileResult::Ok(outputs)
+    })?;
+
+    outputs.sort_by_key(|(i, _)| *i);
+
+    Ok(outputs.into_iter().map(|(_, output)| output).collect())
+}
+
+/// Fan out module construction to a thread pool, each thread emits into `outdir`.
+///
+/// Return emitted file paths in the order of `jobs`.
+pub fn compile_parallel(
+    config: &CompilerConfig,
+    jobs: Vec<ModBuildJob>,
+    outdir: &Path,
+) -> CompileResult<Vec<PathBuf>> {
+    let (emitted, export_decls, _) = compile_parallel_with_exports(config, jobs, outdir)?;
+
+    finish_exports(config, export_decls)?;
+
+    Ok(emitted)
+}
+
+fn compile_parallel_with_exports(
+    config: &CompilerConfig,
+    jobs: Vec<ModBuildJob>,
+    outdir: &Path,
+) -> CompileResult<(Vec<PathBuf>, ExportDecls, bool)> {
+    fs::create_dir_all(outdir)?;
+
+    let mut emitted = vec![];
+    let mut export_decls = ExportDecls::new();
+    let mut rt_used = false;
+
+    for (path, decls, rt) in run_pool(jobs, |job| build_and_emit(config, job, outdir))? {
+        emitted.push(path);
+        export_decls.extend(decls);
+        rt_used |= rt;
+    }
+
+    Ok((emitted, export_decls, rt_used))
+}
+
+/// Check that all exports are defined, then write the C header if required
+fn finish_exports(
+    config: &CompilerConfig,
+    export_decls: ExportDecls,
+) -> CompileResult<()> {
+    if exports_enabled(config) {
+        check_exports_defined(&config.exports, &export_decls.defined)?;
+    }
+
+    if let Some(ref path) = config.c_header {
+        if config.target_type == TargetType::DyLib {
+            let guard = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            fs::write(
+                path,
+                gen_c_header(&guard, &export_decls.structs, &export_decls.decls),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Link Time Optimization
+
+/// Internalize all definitions except for `main` and `exports`
+pub fn internalize<'ctx>(module: &Module<'ctx>, exports: &[String]) {
+    let is_exported = |name: &str| {
+        name == "main" || exports.iter().any(|export| export == name)
+    };
+
+    let mut fn_cur = module.get_first_function();
+    while let Some(f) = fn_cur {
+        if f.count_basic_blocks() > 0
+            && !is_exported(&f.get_name().to_string_lossy())
+        {
+            f.set_linkage(Linkage::Internal);
+        }
+
+        fn_cur = f.get_next_function();
+    }
+
+    let mut gv_cur = module.get_first_global();
+    while let Some(gv) = gv_cur {
+        if gv.get_initializer().is_some()
+            && !is_exported(&gv.get_name().to_string_lossy())
+        {
+            gv.set_linkage(Linkage::Internal);
+        }
+
+        gv_cur = gv.get_next_global();
+    }
+}
+
+/// Threshold of the inliner as clang sets it for `-O<optlv>` (`computeThresholdFromOptLevels`),
+/// `-O1` and below only inline `alwaysinline`.
+pub fn inline_threshold(optlv: OptLv) -> Option<u32> {
+    match optlv {
+        OptLv::Debug | OptLv::Opt1 => None,
+        OptLv::Opt2 => Some(225),
+        OptLv::Opt3 => Some(250),
+    }
+}
+
+pub fn run_opt_pipeline<'ctx>(optlv: OptLv, module: &Module<'ctx>) {
+    let pmb = PassManagerBuilder::create();
+    pmb.set_optimization_level(optlv.into());
+
+    if let Some(threshold) = inline_threshold(optlv) {
+        pmb.set_inliner_with_threshold(threshold);
+    }
+
+    let pm = PassManager::create(());
+    pmb.populate_module_pass_manager(&pm);
+    pm.run_on(module);
+}
+
+/// Build modules in parallel as bitcode, then merge, internalize and optimize them
+/// as a single module, emit it to `outdir/<name>.<ext>`.
+pub fn compile_lto(
+    config: &CompilerConfig,
+    name: &str,
+    jobs: Vec<ModBuildJob>,
+    outdir: &Path,
+) -> CompileResult<PathBuf> {
+    Ok(compile_lto_with_rt(config, name, jobs, outdir)?.0)
+}
+
+/// Same as `compile_lto`, plus if the merged module uses the runtime
+fn compile_lto_with_rt(
+    config: &CompilerConfig,
+    name: &str,
+    jobs: Vec<ModBuildJob>,
+    outdir: &Path,
+) -> CompileResult<(PathBuf, bool)> {
+    fs::create_dir_all(outdir)?;
+
+    let bitcodes = run_pool(jobs, |job| build_bitcode(config, job))?;
+
+    let ctx = Context::create();
+    let merged = ctx.create_module(name);
+
+    for (mod_name, bitcode) in bitcodes {
+        let buf =
+            MemoryBuffer::create_from_memory_range_copy(&bitcode, &mod_name);
+        let module = Module::parse_bitcode_from_buffer(&buf, &ctx)
+            .map_err(|err| err.to_string())?;
+
+        merged
+            .link_in_module(module)
+            .map_err(|err| format!("link {}: {}", mod_name, err))?;
+    }
+
+    let machine = init_target_machine(config)?;
+    set_target(&machine, &merged);
+
+    internalize(&merged, &config.exports);
+    run_opt_pipeline(config.optlv, &merged);
+
+    let export_decls = prepare_exports(config, &merged)?;
+    finish_exports(config, export_decls)?;
+
+    let path = outdir.join(format!("{}.{}", name, emit_ext(config.emit_type)));
+    emit_module_cached(config, &machine, &merged, &path)?;
+
+    Ok((path, uses_runtime(&merged)))
+}
+
+fn name_of(output: &Path) -> String {
+    output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "a".to_owned())
+}
+
+/// Compile `jobs` in parallel (merged as one module if `config.lto`),
+/// and link the objects into `output` when emit object.
+pub fn compile_and_link(
+    config: &CompilerConfig,
+    jobs: Vec<ModBuildJob>,
+    outdir: &Path,
+    output: &Path,
+) -> CompileResult<Vec<PathBuf>> {
+    let (emitted, rt_used) = if config.lto {
+        let (path, rt_used) = compile_lto_with_rt(config, &name_of(output), jobs, outdir)?;
+        (vec![path], rt_used)
+    }
+    else {
+        let (emitted, export_decls, rt_used) =
+            compile_parallel_with_exports(config, jobs, outdir)?;
+        finish_exports(config, export_decls)?;
+        (emitted, rt_used)
+    };
+
+    if config.emit_type == EmitType::Obj {
+        let mut inputs = emitted.clone();
+        let mut args = vec![];
+
+        // archive members are pulled in only if referenced
+        if config.target_type != TargetType::ReLoc {
+            match runtime_lib(config)? {
+                Some(rt) => {
+                    inputs.push(rt);
+                    args.extend(["-lpthread", "-ldl", "-lm"].iter().map(|arg| arg.to_string()));
+                }
+                None if rt_used => {
+                    return Err("modules call `rt_*`, but runtime library isn't found \
+                        (set `CompilerConfig::runtime_lib` or $INKWELLKIT_RT)"
+                        .into())
+                }
+                None => (),
+            }
+        }
+
+        if config.target_type == TargetType::DyLib && !config.exports.is_empty() {
+            let script = outdir.join(format!("{}.map", name_of(output)));
+            fs::write(
+                &script,
+                gen_version_script(config.version_node.as_deref(), &config.exports),
+            )?;
+
+            args.push(format!("-Wl,--version-script={}", script.display()));
+        }
+
+        link(config.target_type, output, &inputs, &args)?;
+    }
+
+    Ok(emitted)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use inkwell::{context::Context, module::Module};
+
+    use super::{compile_lto, compile_parallel, inline_threshold, uses_runtime, ModBuildJob};
+    use crate::{
+        config::{EmitType, OptLv, TargetType},
+        tests::{test_config, test_dir, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_uses_runtime() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_uses_runtime");
+
+        // declarations alone don't pull in the runtime
+        VMMod::include_runtime(&vmmod.module);
+        assert!(!uses_runtime(&vmmod.module));
+
+        let (_, builder) = test_fn(&vmmod, "test_rt", vmmod.tys.void_t.fn_type(&[], false));
+        let fn_vec_new = vmmod.module.get_function("rt_vec_new").unwrap();
+        builder.build_call(fn_vec_new, &[vmmod.usize(8).into(), vmmod.usize(0).into()], "");
+        builder.build_return(None);
+
+        assert!(uses_runtime(&vmmod.module));
+    }
+
+    /// `m<i>` defines `f<i>() -> i<i>`
+    fn numbered_jobs(n: u64) -> Vec<ModBuildJob> {
+        (0..n)
+            .map(|i| {
+                ModBuildJob::new(&format!("m{}", i), move |vmmod| {
+                    let i64_t = vmmod.tys.i64_t;
+                    let (_, builder) = test_fn(vmmod, &format!("f{}", i), i64_t.fn_type(&[], false));
+                    builder.build_return(Some(&i64_t.const_int(i, false)));
+
+                    Ok(())
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compile_parallel() {
+        let outdir = test_dir("parallel");
+
+        let config = test_config(TargetType::ReLoc, EmitType::Obj);
+        let objs = compile_parallel(&config, numbered_jobs(8), &outdir.join("obj")).unwrap();
+
+        assert_eq!(objs.len(), 8);
+        for (i, path) in objs.iter().enumerate() {
+            assert_eq!(path.file_name().unwrap().to_str(), Some(format!("m{}.o", i).as_str()));
+            assert!(fs::read(path).unwrap().starts_with(b"\x7fELF"), "{:?} isn't ELF", path);
+        }
+
+        let config = test_config(TargetType::ReLoc, EmitType::Bitcode);
+        let bitcodes = compile_parallel(&config, numbered_jobs(8), &outdir.join("bc")).unwrap();
+
+        let ctx = Context::create();
+        assert_eq!(bitcodes.len(), 8);
+        for (i, path) in bitcodes.iter().enumerate() {
+            assert_eq!(path.file_name().unwrap().to_str(), Some(format!("m{}.bc", i).as_str()));
+            assert!(fs::read(path).unwrap().starts_with(b"BC\xC0\xDE"), "{:?} isn't bitcode", path);
+
+            let module = Module::parse_bitcode_from_path(path, &ctx).unwrap();
+            assert!(module.get_function(&format!("f{}", i)).is_some());
+        }
+
+        // a failed job fails the whole
+        let mut jobs = numbered_jobs(3);
+        jobs.push(ModBuildJob::new("bad", |_| Err("bad job".into())));
+        let err = compile_parallel(&config, jobs, &outdir.join("bad")).err().unwrap();
+        assert_eq!(err.to_string(), "bad job");
+
+        fs::remove_dir_all(outdir).unwrap();
+    }
+
+    #[test]
+    fn test_compile_lto() {
+        let outdir = test_dir("lto");
+
+        let callee = ModBuildJob::new("callee", |vmmod| {
+            let i64_t = vmmod.tys.i64_t;
+            let (fn_val, builder) = test_fn(vmmod, "triple", i64_t.fn_type(&[i64_t.into()], false));
+            let x = fn_val.get_nth_param(0).unwrap().into_int_value();
+            builder.build_return(Some(&builder.build_int_mul(x, i64_t.const_int(3, false), "")));
+
+            Ok(())
+        });
+        let caller = ModBuildJob::new("caller", |vmmod| {
+            let i64_t = vmmod.tys.i64_t;
+            let fn_t = i64_t.fn_type(&[i64_t.into()], false);
+            let fn_triple = vmmod.module.add_function("triple", fn_t, None);
+
+            let (fn_val, builder) = test_fn(vmmod, "entry", fn_t);
+            let x = fn_val.get_nth_param(0).unwrap();
+            let y = builder
+                .build_call(fn_triple, &[x.into()], "")
+                .try_as_basic_value()
+                .left()
+                .unwrap();
+            builder.build_return(Some(&y));
+
+            Ok(())
+        });
+
+        let mut config = test_config(TargetType::ReLoc, EmitType::LLVMIR);
+        config.optlv = OptLv::Opt2;
+        config.exports = vec!["entry".to_owned()];
+
+        let path = compile_lto(&config, "merged", vec![callee, caller], &outdir).unwrap();
+        let ir = fs::read_to_string(&path).unwrap();
+
+        // the callee across modules is internalized, then inlined and dropped
+        assert!(ir.lines().any(|line| line.starts_with("define i64 @entry(")), "{}", ir);
+        assert!(!ir.contains("call i64 @triple"), "{}", ir);
+        assert!(
+            ir.lines()
+                .filter(|line| line.contains("@triple("))
+                .all(|line| line.starts_with("define internal")),
+            "{}",
+            ir
+        );
+
+        fs::remove_dir_all(outdir).unwrap();
+    }
+
+    #[test]
+    fn test_inline_threshold() {
+        assert_eq!(inline_threshold(OptLv::Debug), None);
+        assert_eq!(inline_threshold(OptLv::Opt1), None);
+        assert_eq!(inline_threshold(OptLv::Opt2), Some(225));
+        assert_eq!(inline_threshold(OptLv::Opt3), Some(250));
+    }
+}
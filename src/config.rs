@@ -5,6 +5,8 @@ use std::{
 use clap::{ArgEnum, PossibleValue};
 use inkwell::OptimizationLevel;
 
+use crate::cache::ObjCache;
+
 
 ///////////////////////////////////////////////////////////////////////////
 //// Compiler Config
@@ -68,6 +70,8 @@ impl ArgEnum for TargetType {
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum EmitType {
     LLVMIR,
+    /// LLVM bitcode
+    Bitcode,
     Asm,
     #[default]
     Obj,
@@ -77,6 +81,7 @@ impl ArgEnum for EmitType {
     fn value_variants<'a>() -> &'a [Self] {
         &[
             Self::LLVMIR,
+            Self::Bitcode,
             Self::Asm,
             Self::Obj
         ]
@@ -85,6 +90,7 @@ impl ArgEnum for EmitType {
     fn to_possible_value<'a>(&self) -> Option<PossibleValue<'a>> {
         Some(PossibleValue::new(match self {
             Self::LLVMIR => "llvm-ir",
+            Self::Bitcode => "llvm-bc",
             Self::Asm => "asm",
             Self::Obj => "obj",
         }))
@@ -144,6 +150,8 @@ impl PrintTy {
     }
 }
 
+/// Pointer size of the host, which may differ from the target's
+#[deprecated(note = "query the target's `TargetData` (or `StructDesc::size_of`) instead")]
 pub const fn usize_len() -> usize {
     if cfg!(target_pointer_width = "64") {
         8
@@ -158,6 +166,21 @@ pub struct CompilerConfig {
     pub target_type: TargetType,
     pub emit_type: EmitType,
     pub print_type: PrintTy,
+    /// Reuse emitted objects of identical modules
+    pub cache: Option<ObjCache>,
+    /// Merge all modules into one before codegen (link-time optimization)
+    pub lto: bool,
+    /// Symbols keep external besides `main`, others are internalized under LTO
+    /// (or hidden from the dynamic symbol table for `DyLib`)
+    pub exports: Vec<String>,
+    /// Version node of the generated version script (`DyLib`)
+    pub version_node: Option<String>,
+    /// Generate C header declaring `exports` (`DyLib`)
+    pub c_header: Option<PathBuf>,
+    /// Emit the checks of `VMMod::build_checked_*`, off to compile them away (release)
+    pub bounds_check: bool,
+    /// `libinkwellkit_rt.a` linked when modules call `rt_*`, `$INKWELLKIT_RT` if unset
+    pub runtime_lib: Option<PathBuf>,
 }
 
 
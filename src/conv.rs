@@ -0,0 +1,538 @@
+//! Integer formatting and parsing without libc, for freestanding builds
+//!
+//! The routines are internal functions emitted once per module on demand:
+//!
+//! - `conv.itoa.i<N>(value, buf*, base) -> len`, `conv.utoa.i<N>` for unsigned,
+//!   `N` is 64 or 128 (`printf` can't format `i128`), base is 2..=16.
+//! - `conv.parse_dec.i64(ptr, len, out*) -> ok`, optional sign, fails on overflow
+//! - `conv.parse_hex.u64(ptr, len, out*) -> ok`, optional `0x`/`0X` prefix
+//!
+//! Division of `i128` may be lowered to `__udivti3` of compiler-rt/libgcc, not libc.
+
+use inkwell::{
+    builder::Builder,
+    module::{Linkage, Module},
+    types::{FunctionType, IntType},
+    values::{FunctionValue, IntValue, PointerValue},
+    AddressSpace, IntPredicate,
+};
+
+use crate::{let_module_ctx, ret_as_bv, slice::StrValue, CommonTypes, VMMod};
+
+
+/// Enough bytes of the buffer of `build_fmt_int` for any value and base
+pub const FMT_INT_BUF_LEN: u32 = 129;
+
+
+fn add_conv_fn<'ctx>(
+    module: &Module<'ctx>,
+    name: &str,
+    fn_t: FunctionType<'ctx>,
+) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+    let_module_ctx!(ctx = module);
+
+    let fn_val = module.add_function(name, fn_t, Some(Linkage::Internal));
+    let builder = ctx.create_builder();
+    builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+    (fn_val, builder)
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Format
+
+fn get_or_gen_fmt<'ctx>(module: &Module<'ctx>, int_t: IntType<'ctx>, signed: bool) -> FunctionValue<'ctx> {
+    let name = format!(
+        "conv.{}.i{}",
+        if signed { "itoa" } else { "utoa" },
+        int_t.get_bit_width()
+    );
+
+    if let Some(fn_val) = module.get_function(&name) {
+        return fn_val;
+    }
+
+    let_module_ctx!(ctx = module);
+    let tys = CommonTypes::get(ctx);
+
+    let (fn_val, builder) = add_conv_fn(
+        module,
+        &name,
+        tys.size_t.fn_type(&[int_t.into(), tys.i8ptr_t.into(), tys.i32_t.into()], false),
+    );
+    let value = fn_val.get_nth_param(0).unwrap().into_int_value();
+    let buf = fn_val.get_nth_param(1).unwrap().into_pointer_value();
+    let base = fn_val.get_nth_param(2).unwrap().into_int_value();
+
+    let blk_entry = builder.get_insert_block().unwrap();
+    let blk_count = ctx.append_basic_block(fn_val, "count");
+    let blk_sign = ctx.append_basic_block(fn_val, "sign");
+    let blk_minus = ctx.append_basic_block(fn_val, "minus");
+    let blk_write = ctx.append_basic_block(fn_val, "write");
+    let blk_done = ctx.append_basic_block(fn_val, "done");
+
+    let size_one = tys.size_t.const_int(1, false);
+    let base = builder.build_int_z_extend(base, int_t, "base");
+
+    let is_neg = if signed {
+        builder.build_int_compare(IntPredicate::SLT, value, int_t.const_zero(), "is_neg")
+    } else {
+        tys.i1_t.const_zero()
+    };
+    // negation of the minimum wraps to itself, which is right as unsigned
+    let magnitude = builder
+        .build_select(is_neg, builder.build_int_neg(value, ""), value, "magnitude")
+        .into_int_value();
+    builder.build_unconditional_branch(blk_count);
+
+    // count digits
+    builder.position_at_end(blk_count);
+    let n_phi = builder.build_phi(tys.size_t, "n");
+    let rest_phi = builder.build_phi(int_t, "rest");
+    let n_nxt = builder.build_int_add(n_phi.as_basic_value().into_int_value(), size_one, "");
+    let rest_nxt = builder.build_int_unsigned_div(rest_phi.as_basic_value().into_int_value(), base, "");
+
+    n_phi.add_incoming(&[(&tys.size_t.const_zero(), blk_entry), (&n_nxt, blk_count)]);
+    rest_phi.add_incoming(&[(&magnitude, blk_entry), (&rest_nxt, blk_count)]);
+
+    let has_more = builder.build_int_compare(IntPredicate::NE, rest_nxt, int_t.const_zero(), "");
+    builder.build_conditional_branch(has_more, blk_count, blk_sign);
+
+    builder.position_at_end(blk_sign);
+    let len = builder.build_int_add(
+        n_nxt,
+        builder.build_int_z_extend(is_neg, tys.size_t, ""),
+        "len",
+    );
+    builder.build_conditional_branch(is_neg, blk_minus, blk_write);
+
+    builder.position_at_end(blk_minus);
+    builder.build_store(buf, tys.i8_t.const_int(b'-' as u64, false));
+    builder.build_unconditional_branch(blk_write);
+
+    // write digits backward from the end
+    builder.position_at_end(blk_write);
+    let pos_phi = builder.build_phi(tys.size_t, "pos");
+    let rest_phi = builder.build_phi(int_t, "rest");
+    let pos = pos_phi.as_basic_value().into_int_value();
+    let rest = rest_phi.as_basic_value().into_int_value();
+
+    let digit = builder.build_int_truncate(builder.build_int_unsigned_rem(rest, base, ""), tys.i8_t, "");
+    let is_dec = builder.build_int_compare(IntPredicate::ULT, digit, tys.i8_t.const_int(10, false), "");
+    let ch = builder.build_select(
+        is_dec,
+        builder.build_int_add(digit, tys.i8_t.const_int(b'0' as u64, false), ""),
+        builder.build_int_add(digit, tys.i8_t.const_int(b'a' as u64 - 10, false), ""),
+        "ch",
+    );
+
+    let pos_nxt = builder.build_int_sub(pos, size_one, "");
+    builder.build_store(unsafe { builder.build_in_bounds_gep(buf, &[pos_nxt], "") }, ch);
+    let rest_nxt = builder.build_int_unsigned_div(rest, base, "");
+
+    pos_phi.add_incoming(&[(&len, blk_sign), (&len, blk_minus), (&pos_nxt, blk_write)]);
+    rest_phi.add_incoming(&[(&magnitude, blk_sign), (&magnitude, blk_minus), (&rest_nxt, blk_write)]);
+
+    let has_more = builder.build_int_compare(IntPredicate::NE, rest_nxt, int_t.const_zero(), "");
+    builder.build_conditional_branch(has_more, blk_write, blk_done);
+
+    builder.position_at_end(blk_done);
+    builder.build_return(Some(&len));
+
+    fn_val
+}
+
+/// Write `value` in `base` of 2..=16 into `buf` of `FMT_INT_BUF_LEN` bytes at least,
+/// lowercase digits without NUL, return the length as `size_t`.
+///
+/// `value` narrower than 64 bits is extended, 128 bits is formatted as is.
+pub fn build_fmt_int<'ctx>(
+    vmmod: &VMMod<'ctx>,
+    builder: &Builder<'ctx>,
+    value: IntValue<'ctx>,
+    buf: PointerValue<'ctx>,
+    base: u32,
+    signed: bool,
+) -> IntValue<'ctx> {
+    assert!((2..=16).contains(&base), "base {} isn't in 2..=16", base);
+
+    let int_t = if value.get_type().get_bit_width() > 64 {
+        vmmod.tys.i128_t
+    } else {
+        vmmod.tys.i64_t
+    };
+    let fn_fmt = get_or_gen_fmt(&vmmod.module, int_t, signed);
+
+    let value = builder.build_int_cast_sign_flag(value, int_t, signed, "");
+
+    ret_as_bv!(builder.build_call(
+        fn_fmt,
+        &[value.into(), buf.into(), vmmod.i32(base as i32).into()],
+        "",
+    ))
+    .into_int_value()
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Parse
+
+fn parse_fn_type<'ctx>(tys: &CommonTypes<'ctx>) -> FunctionType<'ctx> {
+    tys.i1_t.fn_type(
+        &[
+            tys.i8ptr_t.into(),
+            tys.size_t.into(),
+            tys.i64_t.ptr_type(AddressSpace::Generic).into(),
+        ],
+        false,
+    )
+}
+
+fn get_or_gen_parse_dec<'ctx>(module: &Module<'ctx>) -> FunctionValue<'ctx> {
+    if let Some(fn_val) = module.get_function("conv.parse_dec.i64") {
+        return fn_val;
+    }
+
+    let_module_ctx!(ctx = module);
+    let tys = CommonTypes::get(ctx);
+    let i64_t = tys.i64_t;
+
+    let (fn_val, builder) = add_conv_fn(module, "conv.parse_dec.i64", parse_fn_type(&tys));
+    let ptr = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+    let len = fn_val.get_nth_param(1).unwrap().into_int_value();
+    let out = fn_val.get_nth_param(2).unwrap().into_pointer_value();
+
+    let blk_head = ctx.append_basic_block(fn_val, "head");
+    let blk_loop = ctx.append_basic_block(fn_val, "loop");
+    let blk_digit = ctx.append_basic_block(fn_val, "digit");
+    let blk_next = ctx.append_basic_block(fn_val, "next");
+    let blk_finish = ctx.append_basic_block(fn_val, "finish");
+    let blk_store = ctx.append_basic_block(fn_val, "store");
+    let blk_fail = ctx.append_basic_block(fn_val, "fail");
+
+    let size_zero = tys.size_t.const_zero();
+    let is_empty = builder.build_int_compare(IntPredicate::EQ, len, size_zero, "");
+    builder.build_conditional_branch(is_empty, blk_fail, blk_head);
+
+    builder.position_at_end(blk_head);
+    let c0 = builder.build_load(ptr, "c0").into_int_value();
+    let is_minus = builder.build_int_compare(IntPredicate::EQ, c0, tys.i8_t.const_int(b'-' as u64, false), "is_minus");
+    let is_plus = builder.build_int_compare(IntPredicate::EQ, c0, tys.i8_t.const_int(b'+' as u64, false), "");
+    let start = builder.build_int_z_extend(builder.build_or(is_minus, is_plus, ""), tys.size_t, "start");
+    let sign_only = builder.build_int_compare(IntPredicate::EQ, start, len, "");
+    builder.build_conditional_branch(sign_only, blk_fail, blk_loop);
+
+    // accumulate negatively, so that `i64::MIN` fits
+    builder.position_at_end(blk_loop);
+    let idx_phi = builder.build_phi(tys.size_t, "idx");
+    let acc_phi = builder.build_phi(i64_t, "acc");
+    let idx = idx_phi.as_basic_value().into_int_value();
+    let acc = acc_phi.as_basic_value().into_int_value();
+
+    let c = builder
+        .build_load(unsafe { builder.build_in_bounds_gep(ptr, &[idx], "") }, "c")
+        .into_int_value();
+    let digit = builder.build_int_sub(c, tys.i8_t.const_int(b'0' as u64, false), "digit");
+    let is_digit = builder.build_int_compare(IntPredicate::ULT, digit, tys.i8_t.const_int(10, false), "");
+    builder.build_conditional_branch(is_digit, blk_digit, blk_fail);
+
+    builder.position_at_end(blk_digit);
+    let digit = builder.build_int_z_extend(digit, i64_t, "");
+    let limit = i64_t.const_int((i64::MIN / 10) as u64, true);
+    let over_limit = builder.build_int_compare(IntPredicate::SLT, acc, limit, "");
+    let at_limit = builder.build_and(
+        builder.build_int_compare(IntPredicate::EQ, acc, limit, ""),
+        builder.build_int_compare(IntPredicate::UGT, digit, i64_t.const_int((-(i64::MIN % 10)) as u64, false), ""),
+        "",
+    );
+    let is_overflow = builder.build_or(over_limit, at_limit, "");
+    builder.build_conditional_branch(is_overflow, blk_fail, blk_next);
+
+    builder.position_at_end(blk_next);
+    let acc_nxt = builder.build_int_sub(
+        builder.build_int_mul(acc, i64_t.const_int(10, false), ""),
+        digit,
+        "",
+    );
+    let idx_nxt = builder.build_int_add(idx, tys.size_t.const_int(1, false), "");
+
+    idx_phi.add_incoming(&[(&start, blk_head), (&idx_nxt, blk_next)]);
+    acc_phi.add_incoming(&[(&i64_t.const_zero(), blk_head), (&acc_nxt, blk_next)]);
+
+    let has_more = builder.build_int_compare(IntPredicate::ULT, idx_nxt, len, "");
+    builder.build_conditional_branch(has_more, blk_loop, blk_finish);
+
+    builder.position_at_end(blk_finish);
+    let is_min = builder.build_int_compare(IntPredicate::EQ, acc_nxt, i64_t.const_int(i64::MIN as u64, true), "");
+    let is_overflow = builder.build_and(builder.build_not(is_minus, ""), is_min, "");
+    builder.build_conditional_branch(is_overflow, blk_fail, blk_store);
+
+    builder.position_at_end(blk_store);
+    let res = builder.build_select(is_minus, acc_nxt, builder.build_int_neg(acc_nxt, ""), "res");
+    builder.build_store(out, res);
+    builder.build_return(Some(&tys.i1_t.const_int(1, false)));
+
+    builder.position_at_end(blk_fail);
+    builder.build_return(Some(&tys.i1_t.const_zero()));
+
+    fn_val
+}
+
+fn get_or_gen_parse_hex<'ctx>(module: &Module<'ctx>) -> FunctionValue<'ctx> {
+    if let Some(fn_val) = module.get_function("conv.parse_hex.u64") {
+        return fn_val;
+    }
+
+    let_module_ctx!(ctx = module);
+    let tys = CommonTypes::get(ctx);
+    let i8_t = tys.i8_t;
+    let i64_t = tys.i64_t;
+
+    let (fn_val, builder) = add_conv_fn(module, "conv.parse_hex.u64", parse_fn_type(&tys));
+    let ptr = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+    let len = fn_val.get_nth_param(1).unwrap().into_int_value();
+    let out = fn_val.get_nth_param(2).unwrap().into_pointer_value();
+
+    let blk_entry = builder.get_insert_block().unwrap();
+    let blk_prefix = ctx.append_basic_block(fn_val, "prefix");
+    let blk_head = ctx.append_basic_block(fn_val, "head");
+    let blk_loop = ctx.append_basic_block(fn_val, "loop");
+    let blk_digit = ctx.append_basic_block(fn_val, "digit");
+    let blk_next = ctx.append_basic_block(fn_val, "next");
+    let blk_store = ctx.append_basic_block(fn_val, "store");
+    let blk_fail = ctx.append_basic_block(fn_val, "fail");
+
+    let size_zero = tys.size_t.const_zero();
+    let size_one = tys.size_t.const_int(1, false);
+    let may_prefix = builder.build_int_compare(IntPredicate::UGE, len, tys.size_t.const_int(2, false), "");
+    builder.build_conditional_branch(may_prefix, blk_prefix, blk_head);
+
+    builder.position_at_end(blk_prefix);
+    let c0 = builder.build_load(ptr, "c0").into_int_value();
+    let c1 = builder
+        .build_load(unsafe { builder.build_in_bounds_gep(ptr, &[size_one], "") }, "c1")
+        .into_int_value();
+    let is_prefix = builder.build_and(
+        builder.build_int_compare(IntPredicate::EQ, c0, i8_t.const_int(b'0' as u64, false), ""),
+        builder.build_int_compare(
+            IntPredicate::EQ,
+            builder.build_or(c1, i8_t.const_int(0x20, false), ""),
+            i8_t.const_int(b'x' as u64, false),
+            "",
+        ),
+        "is_prefix",
+    );
+    let prefix_len = builder.build_int_mul(
+        builder.build_int_z_extend(is_prefix, tys.size_t, ""),
+        tys.size_t.const_int(2, false),
+        "",
+    );
+    builder.build_unconditional_branch(blk_head);
+
+    builder.position_at_end(blk_head);
+    let start_phi = builder.build_phi(tys.size_t, "start");
+    start_phi.add_incoming(&[(&size_zero, blk_entry), (&prefix_len, blk_prefix)]);
+    let start = start_phi.as_basic_value().into_int_value();
+    let no_digits = builder.build_int_compare(IntPredicate::EQ, start, len, "");
+    builder.build_conditional_branch(no_digits, blk_fail, blk_loop);
+
+    builder.position_at_end(blk_loop);
+    let idx_phi = builder.build_phi(tys.size_t, "idx");
+    let acc_phi = builder.build_phi(i64_t, "acc");
+    let idx = idx_phi.as_basic_value().into_int_value();
+    let acc = acc_phi.as_basic_value().into_int_value();
+
+    let c = builder
+        .build_load(unsafe { builder.build_in_bounds_gep(ptr, &[idx], "") }, "c")
+        .into_int_value();
+    let dec = builder.build_int_sub(c, i8_t.const_int(b'0' as u64, false), "");
+    let is_dec = builder.build_int_compare(IntPredicate::ULT, dec, i8_t.const_int(10, false), "");
+    let lower = builder.build_or(c, i8_t.const_int(0x20, false), "");
+    let alpha = builder.build_int_sub(lower, i8_t.const_int(b'a' as u64, false), "");
+    let is_alpha = builder.build_int_compare(IntPredicate::ULT, alpha, i8_t.const_int(6, false), "");
+    let digit = builder
+        .build_select(is_dec, dec, builder.build_int_add(alpha, i8_t.const_int(10, false), ""), "digit")
+        .into_int_value();
+    builder.build_conditional_branch(builder.build_or(is_dec, is_alpha, ""), blk_digit, blk_fail);
+
+    builder.position_at_end(blk_digit);
+    let is_overflow = builder.build_int_compare(IntPredicate::UGT, acc, i64_t.const_int(u64::MAX >> 4, false), "");
+    builder.build_conditional_branch(is_overflow, blk_fail, blk_next);
+
+    builder.position_at_end(blk_next);
+    let acc_nxt = builder.build_or(
+        builder.build_left_shift(acc, i64_t.const_int(4, false), ""),
+        builder.build_int_z_extend(digit, i64_t, ""),
+        "",
+    );
+    let idx_nxt = builder.build_int_add(idx, size_one, "");
+
+    idx_phi.add_incoming(&[(&start, blk_head), (&idx_nxt, blk_next)]);
+    acc_phi.add_incoming(&[(&i64_t.const_zero(), blk_head), (&acc_nxt, blk_next)]);
+
+    let has_more = builder.build_int_compare(IntPredicate::ULT, idx_nxt, len, "");
+    builder.build_conditional_branch(has_more, blk_loop, blk_store);
+
+    builder.position_at_end(blk_store);
+    builder.build_store(out, acc_nxt);
+    builder.build_return(Some(&tys.i1_t.const_int(1, false)));
+
+    builder.position_at_end(blk_fail);
+    builder.build_return(Some(&tys.i1_t.const_zero()));
+
+    fn_val
+}
+
+/// Parse decimal `i64` of `s` into `out` (`i64*`), return `i1` of success.
+///
+/// It fails on empty input, non-digit or overflow, leaving `out` untouched.
+pub fn build_parse_dec<'ctx>(
+    vmmod: &VMMod<'ctx>,
+    builder: &Builder<'ctx>,
+    s: StrValue<'ctx>,
+    out: PointerValue<'ctx>,
+) -> IntValue<'ctx> {
+    let fn_parse = get_or_gen_parse_dec(&vmmod.module);
+
+    ret_as_bv!(builder.build_call(fn_parse, &[s.ptr.into(), s.len.into(), out.into()], ""))
+        .into_int_value()
+}
+
+/// Parse hexadecimal `u64` of `s` into `out` (`i64*`), return `i1` of success.
+///
+/// Digits are case-insensitive, it fails like `build_parse_dec`.
+pub fn build_parse_hex<'ctx>(
+    vmmod: &VMMod<'ctx>,
+    builder: &Builder<'ctx>,
+    s: StrValue<'ctx>,
+    out: PointerValue<'ctx>,
+) -> IntValue<'ctx> {
+    let fn_parse = get_or_gen_parse_hex(&vmmod.module);
+
+    ret_as_bv!(builder.build_call(fn_parse, &[s.ptr.into(), s.len.into(), out.into()], ""))
+        .into_int_value()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, AddressSpace};
+
+    use super::{build_fmt_int, build_parse_dec, build_parse_hex, FMT_INT_BUF_LEN};
+    use crate::{
+        slice::StrValue,
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_conv() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_conv");
+        let tys = vmmod.tys;
+        let i64ptr_t = tys.i64_t.ptr_type(AddressSpace::Generic);
+
+        // (value, buf*) -> len, for each (name, base, signed)
+        for (name, base, signed) in [("fmt_dec", 10, true), ("fmt_hex", 16, false), ("fmt_oct", 8, true)] {
+            let (fn_val, builder) = test_fn(
+                &vmmod,
+                name,
+                tys.size_t.fn_type(&[tys.i64_t.into(), tys.i8ptr_t.into()], false),
+            );
+            let value = fn_val.get_nth_param(0).unwrap().into_int_value();
+            let buf = fn_val.get_nth_param(1).unwrap().into_pointer_value();
+
+            let len = build_fmt_int(&vmmod, &builder, value, buf, base, signed);
+            builder.build_return(Some(&len));
+        }
+
+        // (buf*) -> len of i128::MIN in base 2 and 10
+        let (fn_val, builder) = test_fn(&vmmod, "fmt_i128", tys.size_t.fn_type(&[tys.i8ptr_t.into()], false));
+        let buf = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+        let min = tys.i128_t.const_int_arbitrary_precision(&[0, 1 << 63]);
+        let len = build_fmt_int(&vmmod, &builder, min, buf, 10, true);
+        let buf = unsafe { builder.build_in_bounds_gep(buf, &[len], "") };
+        let len2 = build_fmt_int(&vmmod, &builder, min, buf, 2, false);
+        builder.build_return(Some(&builder.build_int_add(len, len2, "")));
+
+        // (ptr, len, out*) -> ok
+        for name in ["parse_dec", "parse_hex"] {
+            let (fn_val, builder) = test_fn(
+                &vmmod,
+                name,
+                tys.i32_t.fn_type(&[tys.i8ptr_t.into(), tys.size_t.into(), i64ptr_t.into()], false),
+            );
+            let s = StrValue::from((
+                fn_val.get_nth_param(0).unwrap().into_pointer_value(),
+                fn_val.get_nth_param(1).unwrap().into_int_value(),
+            ));
+            let out = fn_val.get_nth_param(2).unwrap().into_pointer_value();
+
+            let ok = if name == "parse_dec" {
+                build_parse_dec(&vmmod, &builder, s, out)
+            } else {
+                build_parse_hex(&vmmod, &builder, s, out)
+            };
+            builder.build_return(Some(&builder.build_int_z_extend(ok, tys.i32_t, "")));
+        }
+
+        let ee = jit(&vmmod);
+
+        type FmtFn = unsafe extern "C" fn(i64, *mut u8) -> usize;
+        let fmt = |name: &str, value: i64| {
+            let f = unsafe { ee.get_function::<FmtFn>(name) }.unwrap();
+            let mut buf = [0u8; FMT_INT_BUF_LEN as usize];
+            let len = unsafe { f.call(value, buf.as_mut_ptr()) };
+
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+
+        for value in [0, 7, -1, 255, i64::MAX, i64::MIN] {
+            assert_eq!(fmt("fmt_dec", value), value.to_string());
+            assert_eq!(fmt("fmt_hex", value), format!("{:x}", value as u64));
+
+            let oct = format!("{:o}", value.unsigned_abs());
+            assert_eq!(fmt("fmt_oct", value), if value < 0 { format!("-{}", oct) } else { oct });
+        }
+
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn(*mut u8) -> usize>("fmt_i128") }.unwrap();
+        let mut buf = [0u8; FMT_INT_BUF_LEN as usize * 2];
+        let len = unsafe { f.call(buf.as_mut_ptr()) };
+        assert_eq!(
+            String::from_utf8(buf[..len].to_vec()).unwrap(),
+            format!("{}{:b}", i128::MIN, i128::MIN as u128),
+        );
+
+        type ParseFn = unsafe extern "C" fn(*const u8, usize, *mut i64) -> i32;
+        let parse = |name: &str, s: &str| {
+            let f = unsafe { ee.get_function::<ParseFn>(name) }.unwrap();
+            let mut out = 12345;
+            let ok = unsafe { f.call(s.as_ptr(), s.len(), &mut out) };
+
+            if ok != 0 {
+                Some(out)
+            } else {
+                assert_eq!(out, 12345, "{} {:?} touched out", name, s);
+                None
+            }
+        };
+
+        assert_eq!(parse("parse_dec", "0"), Some(0));
+        assert_eq!(parse("parse_dec", "+42"), Some(42));
+        assert_eq!(parse("parse_dec", "-42"), Some(-42));
+        assert_eq!(parse("parse_dec", "9223372036854775807"), Some(i64::MAX));
+        assert_eq!(parse("parse_dec", "-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(parse("parse_dec", "9223372036854775808"), None);
+        assert_eq!(parse("parse_dec", ""), None);
+        assert_eq!(parse("parse_dec", "-"), None);
+        assert_eq!(parse("parse_dec", "12a"), None);
+
+        assert_eq!(parse("parse_hex", "ff"), Some(255));
+        assert_eq!(parse("parse_hex", "0xDeadBeef"), Some(0xdeadbeef));
+        assert_eq!(parse("parse_hex", "ffffffffffffffff"), Some(-1));
+        assert_eq!(parse("parse_hex", "10000000000000000"), None);
+        assert_eq!(parse("parse_hex", "0x"), None);
+        assert_eq!(parse("parse_hex", "fg"), None);
+    }
+}
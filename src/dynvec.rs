@@ -0,0 +1,502 @@
+//! Growable vector of any element type, generated in IR over `malloc`/`realloc`
+//!
+//! `%dynvec.<elem> = { elem*, len, cap }` lives on heap and is passed by pointer,
+//! the helpers are internal functions emitted once per module:
+//!
+//! - `new(cap) -> vec*`
+//! - `push(vec*, elem)`
+//! - `pop(vec*) -> elem`
+//! - `get(vec*, idx) -> elem`
+//! - `set(vec*, idx, elem)`
+//! - `len(vec*) -> len`
+//! - `free(vec*)`
+//!
+//! Bounds checked `pop`/`get`/`set` abort on failure, they are named with suffix `.chk`.
+//! Out of memory and overflow of the size abort.
+
+use inkwell::{
+    builder::Builder,
+    module::{Linkage, Module},
+    types::{AnyType, BasicType, BasicTypeEnum, FunctionType, PointerType, StructType},
+    values::{BasicMetadataValueEnum, BasicValueEnum, FunctionValue, IntValue, PointerValue},
+    AddressSpace, IntPredicate,
+};
+
+use crate::{
+    build_abort_if_null, build_checked_add, build_checked_mul, get_or_create_struct_type,
+    let_module_ctx, ret_as_bv, CommonTypes, VMMod,
+};
+
+
+pub struct DynVec<'ctx> {
+    pub elem_t: BasicTypeEnum<'ctx>,
+    pub vec_t: StructType<'ctx>,
+    pub bounds_check: bool,
+    prefix: String,
+}
+
+/// Symbol-friendly name of `ty`, e.g. `%point*` => `point_p`
+fn type_tag(ty: BasicTypeEnum) -> String {
+    ty.print_to_string()
+        .to_string()
+        .chars()
+        .filter_map(|c| match c {
+            '%' | ' ' | '"' => None,
+            '*' => Some('p'),
+            c if c.is_ascii_alphanumeric() => Some(c),
+            _ => Some('_'),
+        })
+        .collect()
+}
+
+impl<'ctx> DynVec<'ctx> {
+    /// Define vector of `elem_t` in `module`, it's fine to define it again.
+    pub fn define(module: &Module<'ctx>, elem_t: BasicTypeEnum<'ctx>, bounds_check: bool) -> Self {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+
+        let prefix = format!("dynvec.{}", type_tag(elem_t));
+        let vec_t = get_or_create_struct_type(
+            ctx,
+            module,
+            &prefix,
+            Some(&[
+                elem_t.ptr_type(AddressSpace::Generic).into(),
+                tys.size_t.into(),
+                tys.size_t.into(),
+            ]),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let dynvec = Self {
+            elem_t,
+            vec_t,
+            bounds_check,
+            prefix,
+        };
+
+        if module.get_function(&dynvec.fn_name("new")).is_none() {
+            dynvec.gen_common_fns(module);
+        }
+        if module.get_function(&dynvec.fn_name("get")).is_none() {
+            dynvec.gen_access_fns(module);
+        }
+
+        dynvec
+    }
+
+    fn fn_name(&self, op: &str) -> String {
+        let checked = matches!(op, "pop" | "get" | "set") && self.bounds_check;
+
+        format!("{}.{}{}", self.prefix, op, if checked { ".chk" } else { "" })
+    }
+
+    fn vec_ptr_t(&self) -> PointerType<'ctx> {
+        self.vec_t.ptr_type(AddressSpace::Generic)
+    }
+
+    fn elem_ptr_t(&self) -> PointerType<'ctx> {
+        self.elem_t.ptr_type(AddressSpace::Generic)
+    }
+
+    fn add_fn(
+        &self,
+        module: &Module<'ctx>,
+        op: &str,
+        fn_t: FunctionType<'ctx>,
+    ) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+        let_module_ctx!(ctx = module);
+
+        let fn_val = module.add_function(&self.fn_name(op), fn_t, Some(Linkage::Internal));
+        let builder = ctx.create_builder();
+        builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+        (fn_val, builder)
+    }
+
+    /// `n * sizeof(elem)` but at least 1, so null from `malloc` means out of memory,
+    /// abort if it overflows.
+    fn build_bytes(&self, module: &Module<'ctx>, builder: &Builder<'ctx>, n: IntValue<'ctx>) -> IntValue<'ctx> {
+        let elem_size = self.elem_t.size_of().unwrap();
+        let elem_size = builder.build_int_cast(elem_size, n.get_type(), "");
+
+        let bytes = build_checked_mul(module, builder, n, elem_size);
+        let one = n.get_type().const_int(1, false);
+        let is_zero = builder.build_int_compare(IntPredicate::ULT, bytes, one, "");
+
+        builder.build_select(is_zero, one, bytes, "bytes").into_int_value()
+    }
+
+    fn build_field(
+        &self,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+        idx: u32,
+    ) -> (PointerValue<'ctx>, BasicValueEnum<'ctx>) {
+        let name = ["data", "len", "cap"][idx as usize];
+        let ptr = builder.build_struct_gep(vec, idx, &format!("{}_ptr", name)).unwrap();
+
+        (ptr, builder.build_load(ptr, name))
+    }
+
+    /// Abort unless `idx < len`
+    fn build_check(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        fn_val: FunctionValue<'ctx>,
+        idx: IntValue<'ctx>,
+        len: IntValue<'ctx>,
+    ) {
+        if !self.bounds_check {
+            return;
+        }
+
+        let_module_ctx!(ctx = module);
+        let blk_fail = ctx.append_basic_block(fn_val, "oob");
+        let blk_ok = ctx.append_basic_block(fn_val, "inbounds");
+
+        let cond = builder.build_int_compare(IntPredicate::ULT, idx, len, "");
+        builder.build_conditional_branch(cond, blk_ok, blk_fail);
+
+        builder.position_at_end(blk_fail);
+        builder.build_call(module.get_function("abort").unwrap(), &[], "");
+        builder.build_unreachable();
+
+        builder.position_at_end(blk_ok);
+    }
+
+    fn gen_common_fns(&self, module: &Module<'ctx>) {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+
+        VMMod::include_stdlib(module);
+        let fn_malloc = module.get_function("malloc").unwrap();
+        let fn_realloc = module.get_function("realloc").unwrap();
+        let fn_free = module.get_function("free").unwrap();
+
+        // new
+        let (fn_new, builder) = self.add_fn(
+            module,
+            "new",
+            self.vec_ptr_t().fn_type(&[tys.size_t.into()], false),
+        );
+        let cap = fn_new.get_nth_param(0).unwrap().into_int_value();
+        cap.set_name("cap");
+
+        let header_size = builder.build_int_cast(self.vec_t.size_of().unwrap(), tys.size_t, "");
+        let vec = ret_as_bv!(builder.build_call(fn_malloc, &[header_size.into()], ""));
+        build_abort_if_null(module, &builder, vec.into_pointer_value());
+        let vec = builder.build_bitcast(vec, self.vec_ptr_t(), "vec").into_pointer_value();
+
+        let bytes = self.build_bytes(module, &builder, cap);
+        let data = ret_as_bv!(builder.build_call(fn_malloc, &[bytes.into()], ""));
+        build_abort_if_null(module, &builder, data.into_pointer_value());
+        let data = builder.build_bitcast(data, self.elem_ptr_t(), "data");
+
+        builder.build_store(builder.build_struct_gep(vec, 0, "").unwrap(), data);
+        builder.build_store(builder.build_struct_gep(vec, 1, "").unwrap(), tys.size_t.const_zero());
+        builder.build_store(builder.build_struct_gep(vec, 2, "").unwrap(), cap);
+        builder.build_return(Some(&vec));
+
+        // push
+        let (fn_push, builder) = self.add_fn(
+            module,
+            "push",
+            tys.void_t.fn_type(&[self.vec_ptr_t().into(), self.elem_t.into()], false),
+        );
+        let vec = fn_push.get_nth_param(0).unwrap().into_pointer_value();
+        let elem = fn_push.get_nth_param(1).unwrap();
+
+        let (data_ptr, data) = self.build_field(&builder, vec, 0);
+        let (len_ptr, len) = self.build_field(&builder, vec, 1);
+        let (cap_ptr, cap) = self.build_field(&builder, vec, 2);
+        let (len, cap) = (len.into_int_value(), cap.into_int_value());
+
+        let blk_entry = builder.get_insert_block().unwrap();
+        let blk_grow = ctx.append_basic_block(fn_push, "grow");
+        let blk_store = ctx.append_basic_block(fn_push, "store");
+
+        let is_full = builder.build_int_compare(IntPredicate::EQ, len, cap, "is_full");
+        builder.build_conditional_branch(is_full, blk_grow, blk_store);
+
+        builder.position_at_end(blk_grow);
+        let is_empty = builder.build_int_compare(IntPredicate::EQ, cap, tys.size_t.const_zero(), "");
+        let doubled = build_checked_add(module, &builder, cap, cap);
+        let new_cap = builder
+            .build_select(is_empty, tys.size_t.const_int(4, false), doubled, "new_cap")
+            .into_int_value();
+        let data_raw = builder.build_bitcast(data, tys.i8ptr_t, "");
+        let bytes = self.build_bytes(module, &builder, new_cap);
+        let new_data = ret_as_bv!(builder.build_call(fn_realloc, &[data_raw.into(), bytes.into()], ""));
+        build_abort_if_null(module, &builder, new_data.into_pointer_value());
+        let new_data = builder.build_bitcast(new_data, self.elem_ptr_t(), "new_data");
+        builder.build_store(data_ptr, new_data);
+        builder.build_store(cap_ptr, new_cap);
+        builder.build_unconditional_branch(blk_store);
+
+        let blk_grown = builder.get_insert_block().unwrap();
+
+        builder.position_at_end(blk_store);
+        let data_phi = builder.build_phi(self.elem_ptr_t(), "data");
+        data_phi.add_incoming(&[(&data, blk_entry), (&new_data, blk_grown)]);
+        let data = data_phi.as_basic_value().into_pointer_value();
+        let slot = unsafe { builder.build_in_bounds_gep(data, &[len], "slot") };
+        builder.build_store(slot, elem);
+        let len = builder.build_int_add(len, tys.size_t.const_int(1, false), "");
+        builder.build_store(len_ptr, len);
+        builder.build_return(None);
+
+        // len
+        let (fn_len, builder) = self.add_fn(
+            module,
+            "len",
+            tys.size_t.fn_type(&[self.vec_ptr_t().into()], false),
+        );
+        let vec = fn_len.get_nth_param(0).unwrap().into_pointer_value();
+        let (_, len) = self.build_field(&builder, vec, 1);
+        builder.build_return(Some(&len));
+
+        // free
+        let (fn_vec_free, builder) = self.add_fn(
+            module,
+            "free",
+            tys.void_t.fn_type(&[self.vec_ptr_t().into()], false),
+        );
+        let vec = fn_vec_free.get_nth_param(0).unwrap().into_pointer_value();
+        let (_, data) = self.build_field(&builder, vec, 0);
+        let data = builder.build_bitcast(data, tys.i8ptr_t, "");
+        builder.build_call(fn_free, &[data.into()], "");
+        let vec = builder.build_bitcast(vec, tys.i8ptr_t, "");
+        builder.build_call(fn_free, &[vec.into()], "");
+        builder.build_return(None);
+    }
+
+    fn gen_access_fns(&self, module: &Module<'ctx>) {
+        let tys = CommonTypes::of_module(module);
+
+        VMMod::include_stdlib(module);
+
+        // pop
+        let (fn_pop, builder) = self.add_fn(
+            module,
+            "pop",
+            self.elem_t.fn_type(&[self.vec_ptr_t().into()], false),
+        );
+        let vec = fn_pop.get_nth_param(0).unwrap().into_pointer_value();
+        let (_, data) = self.build_field(&builder, vec, 0);
+        let (len_ptr, len) = self.build_field(&builder, vec, 1);
+        let len = len.into_int_value();
+        let last = builder.build_int_sub(len, tys.size_t.const_int(1, false), "last");
+        self.build_check(module, &builder, fn_pop, last, len);
+        builder.build_store(len_ptr, last);
+        let slot = unsafe { builder.build_in_bounds_gep(data.into_pointer_value(), &[last], "slot") };
+        builder.build_return(Some(&builder.build_load(slot, "elem")));
+
+        // get
+        let (fn_get, builder) = self.add_fn(
+            module,
+            "get",
+            self.elem_t.fn_type(&[self.vec_ptr_t().into(), tys.size_t.into()], false),
+        );
+        let vec = fn_get.get_nth_param(0).unwrap().into_pointer_value();
+        let idx = fn_get.get_nth_param(1).unwrap().into_int_value();
+        let (_, data) = self.build_field(&builder, vec, 0);
+        let (_, len) = self.build_field(&builder, vec, 1);
+        self.build_check(module, &builder, fn_get, idx, len.into_int_value());
+        let slot = unsafe { builder.build_in_bounds_gep(data.into_pointer_value(), &[idx], "slot") };
+        builder.build_return(Some(&builder.build_load(slot, "elem")));
+
+        // set
+        let (fn_set, builder) = self.add_fn(
+            module,
+            "set",
+            tys.void_t.fn_type(
+                &[self.vec_ptr_t().into(), tys.size_t.into(), self.elem_t.into()],
+                false,
+            ),
+        );
+        let vec = fn_set.get_nth_param(0).unwrap().into_pointer_value();
+        let idx = fn_set.get_nth_param(1).unwrap().into_int_value();
+        let elem = fn_set.get_nth_param(2).unwrap();
+        let (_, data) = self.build_field(&builder, vec, 0);
+        let (_, len) = self.build_field(&builder, vec, 1);
+        self.build_check(module, &builder, fn_set, idx, len.into_int_value());
+        let slot = unsafe { builder.build_in_bounds_gep(data.into_pointer_value(), &[idx], "slot") };
+        builder.build_store(slot, elem);
+        builder.build_return(None);
+    }
+
+    ///////////////////////////////////
+    //// Call
+
+    fn build_call_op(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        op: &str,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> Option<BasicValueEnum<'ctx>> {
+        let fn_val = module
+            .get_function(&self.fn_name(op))
+            .unwrap_or_else(|| panic!("{} isn't defined in module", self.fn_name(op)));
+
+        builder.build_call(fn_val, args, "").try_as_basic_value().left()
+    }
+
+    pub fn build_new(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        cap: IntValue<'ctx>,
+    ) -> PointerValue<'ctx> {
+        self.build_call_op(module, builder, "new", &[cap.into()])
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    pub fn build_push(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+        elem: BasicValueEnum<'ctx>,
+    ) {
+        self.build_call_op(module, builder, "push", &[vec.into(), elem.into()]);
+    }
+
+    /// Remove the last element, popping empty `vec` is undefined behavior unless `bounds_check`,
+    /// which aborts then.
+    pub fn build_pop(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+    ) -> BasicValueEnum<'ctx> {
+        self.build_call_op(module, builder, "pop", &[vec.into()]).unwrap()
+    }
+
+    pub fn build_get(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+        idx: IntValue<'ctx>,
+    ) -> BasicValueEnum<'ctx> {
+        self.build_call_op(module, builder, "get", &[vec.into(), idx.into()]).unwrap()
+    }
+
+    pub fn build_set(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+        idx: IntValue<'ctx>,
+        elem: BasicValueEnum<'ctx>,
+    ) {
+        self.build_call_op(module, builder, "set", &[vec.into(), idx.into(), elem.into()]);
+    }
+
+    pub fn build_len(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        self.build_call_op(module, builder, "len", &[vec.into()])
+            .unwrap()
+            .into_int_value()
+    }
+
+    pub fn build_free(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        vec: PointerValue<'ctx>,
+    ) {
+        self.build_call_op(module, builder, "free", &[vec.into()]);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+
+    use super::DynVec;
+    use crate::{
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_dynvec() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_dynvec");
+        let module = &vmmod.module;
+        let i64_t = vmmod.tys.i64_t;
+
+        let dynvec = DynVec::define(module, i64_t.into(), true);
+        // defining again reuses the helpers
+        DynVec::define(module, i64_t.into(), true);
+
+        let (_, builder) = test_fn(&vmmod, "test_dynvec", i64_t.fn_type(&[], false));
+
+        // grows past the capacity
+        let v = dynvec.build_new(module, &builder, vmmod.usize(2));
+        for i in 1..=4 {
+            dynvec.build_push(module, &builder, v, i64_t.const_int(i * i, false).into());
+        }
+        dynvec.build_set(module, &builder, v, vmmod.usize(0), i64_t.const_int(5, false).into());
+
+        let first = dynvec.build_get(module, &builder, v, vmmod.usize(0)).into_int_value();
+        let last = dynvec.build_pop(module, &builder, v).into_int_value();
+        let len = dynvec.build_len(module, &builder, v);
+        dynvec.build_free(module, &builder, v);
+
+        // first + last * 100 + len * 10000
+        let res = builder.build_int_add(
+            first,
+            builder.build_int_mul(last, i64_t.const_int(100, false), ""),
+            "",
+        );
+        let len = builder.build_int_z_extend_or_bit_cast(len, i64_t, "");
+        let res = builder.build_int_add(
+            res,
+            builder.build_int_mul(len, i64_t.const_int(10000, false), ""),
+            "",
+        );
+        builder.build_return(Some(&res));
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn() -> u64>("test_dynvec") }.unwrap();
+
+        assert_eq!(unsafe { f.call() }, 5 + 16 * 100 + 3 * 10000);
+    }
+
+    #[test]
+    fn test_dynvec_from_empty() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_dynvec_from_empty");
+        let module = &vmmod.module;
+        let i8_t = vmmod.tys.i8_t;
+
+        let dynvec = DynVec::define(module, i8_t.into(), false);
+
+        let (_, builder) = test_fn(&vmmod, "test_dynvec_from_empty", vmmod.tys.size_t.fn_type(&[], false));
+
+        // 1-byte allocation of no element, then 4, 8
+        let v = dynvec.build_new(module, &builder, vmmod.usize(0));
+        for i in 0..5 {
+            dynvec.build_push(module, &builder, v, i8_t.const_int(i, false).into());
+        }
+        let len = dynvec.build_len(module, &builder, v);
+        dynvec.build_free(module, &builder, v);
+        builder.build_return(Some(&len));
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn() -> usize>("test_dynvec_from_empty") }.unwrap();
+
+        assert_eq!(unsafe { f.call() }, 5);
+    }
+}
@@ -0,0 +1,518 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+use inkwell::{
+    attributes::{Attribute, AttributeLoc},
+    module::{Linkage, Module},
+    types::{AnyType, AnyTypeEnum},
+    values::{FunctionValue, GlobalValue},
+    GlobalVisibility,
+};
+
+use crate::{compiler::CompileResult, let_module_ctx};
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Export List
+
+/// Keep `exports` external with default visibility,
+/// hide other definitions from the dynamic symbol table.
+///
+/// Return the exports defined in `module`.
+pub fn apply_exports<'ctx>(module: &Module<'ctx>, exports: &[String]) -> BTreeSet<String> {
+    let mut defined = BTreeSet::new();
+
+    let mut fn_cur = module.get_first_function();
+    while let Some(f) = fn_cur {
+        if f.count_basic_blocks() > 0 {
+            let gv = f.as_global_value();
+            let name = f.get_name().to_string_lossy();
+
+            if exports.iter().any(|export| *export == name) {
+                f.set_linkage(Linkage::External);
+                gv.set_visibility(GlobalVisibility::Default);
+                defined.insert(name.into_owned());
+            }
+            else if f.get_linkage() == Linkage::External {
+                gv.set_visibility(GlobalVisibility::Hidden);
+            }
+        }
+
+        fn_cur = f.get_next_function();
+    }
+
+    let mut gv_cur = module.get_first_global();
+    while let Some(gv) = gv_cur {
+        if gv.get_initializer().is_some() {
+            let name = gv.get_name().to_string_lossy();
+
+            if exports.iter().any(|export| *export == name) {
+                gv.set_linkage(Linkage::External);
+                gv.set_visibility(GlobalVisibility::Default);
+                defined.insert(name.into_owned());
+            }
+            else if gv.get_linkage() == Linkage::External {
+                gv.set_visibility(GlobalVisibility::Hidden);
+            }
+        }
+
+        gv_cur = gv.get_next_global();
+    }
+
+    defined
+}
+
+/// Error if some of `exports` isn't in `defined` (of all modules)
+pub fn check_exports_defined(exports: &[String], defined: &BTreeSet<String>) -> CompileResult<()> {
+    let missing = exports
+        .iter()
+        .filter(|export| !defined.contains(*export))
+        .map(|export| export.as_str())
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        return Err(format!("exports not defined in any module: {}", missing.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+/// GNU ld version script, `version` None for an anonymous version node
+pub fn gen_version_script(version: Option<&str>, exports: &[String]) -> String {
+    let mut script = String::new();
+
+    match version {
+        Some(version) => writeln!(script, "{} {{", version).unwrap(),
+        None => writeln!(script, "{{").unwrap(),
+    }
+    writeln!(script, "    global:").unwrap();
+    for export in exports {
+        writeln!(script, "        {};", export).unwrap();
+    }
+    writeln!(script, "    local:").unwrap();
+    writeln!(script, "        *;").unwrap();
+    writeln!(script, "}};").unwrap();
+
+    script
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Signedness
+
+/// Named metadata of `!{!"symbol", !"su.."}` nodes, which survives linking modules
+const UNSIGNED_MD: &str = "inkwellkit.unsigned";
+
+/// Record which integers (or pointers to them) of `symbol` are unsigned,
+/// LLVM types don't tell it, `impl_fn_hdr!`/`impl_fn!` record their declarations.
+///
+/// `unsigned[0]` is of the return (or the value of a global), then of each parameter.
+pub fn record_unsigned<'ctx>(module: &Module<'ctx>, symbol: &str, unsigned: &[bool]) {
+    if !unsigned.contains(&true) {
+        return;
+    }
+
+    let_module_ctx!(ctx = module);
+    let signs = unsigned
+        .iter()
+        .map(|&unsigned| if unsigned { 'u' } else { 's' })
+        .collect::<String>();
+    let node = ctx.metadata_node(&[
+        ctx.metadata_string(symbol).into(),
+        ctx.metadata_string(&signs).into(),
+    ]);
+
+    module.add_global_metadata(UNSIGNED_MD, &node).unwrap();
+}
+
+/// Recorded by `record_unsigned`, None if there's no record
+fn recorded_unsigned<'ctx>(module: &Module<'ctx>, symbol: &str) -> Option<Vec<bool>> {
+    module
+        .get_global_metadata(UNSIGNED_MD)
+        .into_iter()
+        .find_map(|node| {
+            let values = node.get_node_values();
+            let name = values.first()?.into_metadata_value();
+
+            if name.get_string_value()?.to_bytes() != symbol.as_bytes() {
+                return None;
+            }
+
+            let signs = values.get(1)?.into_metadata_value();
+            Some(signs.get_string_value()?.to_bytes().iter().map(|&c| c == b'u').collect())
+        })
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// C Header
+
+fn c_ident(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn has_enum_attr<'ctx>(f: FunctionValue<'ctx>, loc: AttributeLoc, name: &str) -> bool {
+    f.get_enum_attribute(loc, Attribute::get_named_enum_kind_id(name))
+        .is_some()
+}
+
+/// Map LLVM type back to C type, `unsigned` applies to the integer (or the pointee).
+///
+/// Named structs are recorded into `structs` for forward declaration.
+fn c_type_of<'ctx>(
+    ty: AnyTypeEnum<'ctx>,
+    unsigned: bool,
+    structs: &mut BTreeSet<String>,
+) -> CompileResult<String> {
+    let sign = if unsigned { "u" } else { "" };
+
+    Ok(match ty {
+        AnyTypeEnum::VoidType(_) => "void".to_owned(),
+        AnyTypeEnum::IntType(int_t) => match int_t.get_bit_width() {
+            1 => "bool".to_owned(),
+            // signedness of `char` is implementation-defined
+            w @ (8 | 16 | 32 | 64) => format!("{}int{}_t", sign, w),
+            128 if unsigned => "unsigned __int128".to_owned(),
+            128 => "__int128".to_owned(),
+            w => return Err(format!("no C type for i{}", w).into()),
+        },
+        AnyTypeEnum::FloatType(float_t) => {
+            match float_t.print_to_string().to_string().as_str() {
+                "float" => "float".to_owned(),
+                "double" => "double".to_owned(),
+                "x86_fp80" => "long double".to_owned(),
+                other => return Err(format!("no C type for {}", other).into()),
+            }
+        }
+        AnyTypeEnum::PointerType(ptr_t) => match ptr_t.get_element_type() {
+            // function pointer declarator needs the name inside, keep it opaque
+            AnyTypeEnum::FunctionType(_) => "void *".to_owned(),
+            elem_t => format!("{} *", c_type_of(elem_t, unsigned, structs)?),
+        },
+        AnyTypeEnum::StructType(struct_t) => match struct_t.get_name() {
+            Some(name) => {
+                let name = c_ident(&name.to_string_lossy());
+                structs.insert(name.clone());
+
+                format!("struct {}", name)
+            }
+            None => return Err("no C type for literal struct".into()),
+        },
+        // LLVM passes arrays by value, a C array parameter decays to pointer instead
+        AnyTypeEnum::ArrayType(arr_t) => {
+            return Err(format!("no C type for array {}", arr_t.print_to_string()).into())
+        }
+        other => return Err(format!("no C type for {:?}", other).into()),
+    })
+}
+
+/// C declaration of function `f` in `module`, signedness is from `record_unsigned`,
+/// or the `zeroext` attribute for a function without record.
+pub fn c_fn_decl<'ctx>(
+    module: &Module<'ctx>,
+    f: FunctionValue<'ctx>,
+    structs: &mut BTreeSet<String>,
+) -> CompileResult<String> {
+    let fn_t = f.get_type();
+    let recorded = recorded_unsigned(module, &f.get_name().to_string_lossy());
+    let is_unsigned = |i: usize, loc: AttributeLoc| match recorded {
+        Some(ref unsigned) => unsigned.get(i).copied().unwrap_or(false),
+        None => has_enum_attr(f, loc, "zeroext"),
+    };
+
+    let ret = match fn_t.get_return_type() {
+        Some(ret_t) => c_type_of(
+            ret_t.as_any_type_enum(),
+            is_unsigned(0, AttributeLoc::Return),
+            structs,
+        )?,
+        None => "void".to_owned(),
+    };
+
+    let mut params = vec![];
+    for (i, param_t) in fn_t.get_param_types().into_iter().enumerate() {
+        params.push(c_type_of(
+            param_t.as_any_type_enum(),
+            is_unsigned(i + 1, AttributeLoc::Param(i as u32)),
+            structs,
+        )?);
+    }
+    if fn_t.is_var_arg() {
+        params.push("...".to_owned());
+    }
+    if params.is_empty() {
+        params.push("void".to_owned());
+    }
+
+    Ok(format!(
+        "{} {}({});",
+        ret,
+        f.get_name().to_string_lossy(),
+        params.join(", ")
+    ))
+}
+
+/// C declaration of global `gv` in `module`, an array keeps its bound,
+/// signedness is from `record_unsigned`.
+pub fn c_global_decl<'ctx>(
+    module: &Module<'ctx>,
+    gv: GlobalValue<'ctx>,
+    structs: &mut BTreeSet<String>,
+) -> CompileResult<String> {
+    let name = gv.get_name().to_string_lossy();
+    let unsigned = recorded_unsigned(module, &name)
+        .and_then(|unsigned| unsigned.first().copied())
+        .unwrap_or(false);
+    let qualifier = if gv.is_constant() { " const" } else { "" };
+
+    Ok(match gv.get_type().get_element_type() {
+        AnyTypeEnum::ArrayType(arr_t) => format!(
+            "extern {}{} {}[{}];",
+            c_type_of(arr_t.get_element_type().as_any_type_enum(), unsigned, structs)?,
+            qualifier,
+            name,
+            arr_t.len()
+        ),
+        elem_t => format!("extern {}{} {};", c_type_of(elem_t, unsigned, structs)?, qualifier, name),
+    })
+}
+
+/// Declarations of `exports` (functions and globals) found in `module`
+pub fn c_decls<'ctx>(
+    module: &Module<'ctx>,
+    exports: &[String],
+    structs: &mut BTreeSet<String>,
+) -> CompileResult<Vec<String>> {
+    let mut decls = vec![];
+
+    for export in exports {
+        if let Some(f) = module.get_function(export) {
+            if f.count_basic_blocks() > 0 {
+                decls.push(c_fn_decl(module, f, structs)?);
+            }
+        }
+        else if let Some(gv) = module.get_global(export) {
+            if gv.get_initializer().is_some() {
+                decls.push(c_global_decl(module, gv, structs)?);
+            }
+        }
+    }
+
+    Ok(decls)
+}
+
+pub fn gen_c_header(
+    guard: &str,
+    structs: &BTreeSet<String>,
+    decls: &[String],
+) -> String {
+    let guard = c_ident(guard).to_uppercase();
+    let mut header = String::new();
+
+    writeln!(header, "#ifndef {}", guard).unwrap();
+    writeln!(header, "#define {}", guard).unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#include <stdbool.h>").unwrap();
+    writeln!(header, "#include <stdint.h>").unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#ifdef __cplusplus").unwrap();
+    writeln!(header, "extern \"C\" {{").unwrap();
+    writeln!(header, "#endif").unwrap();
+    writeln!(header).unwrap();
+
+    for name in structs {
+        writeln!(header, "struct {};", name).unwrap();
+    }
+    if !structs.is_empty() {
+        writeln!(header).unwrap();
+    }
+
+    for decl in decls {
+        writeln!(header, "{}", decl).unwrap();
+    }
+
+    writeln!(header).unwrap();
+    writeln!(header, "#ifdef __cplusplus").unwrap();
+    writeln!(header, "}}").unwrap();
+    writeln!(header, "#endif").unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#endif /* {} */", guard).unwrap();
+
+    header
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_char, c_int, c_void, CString},
+        fs,
+    };
+
+    use inkwell::{context::Context, AddressSpace};
+
+    use super::*;
+    use crate::{
+        compiler::{compile_and_link, ModBuildJob},
+        config::{EmitType, TargetType},
+        impl_fn,
+        tests::{test_config, test_dir},
+    };
+
+    extern "C" {
+        fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        fn dlclose(handle: *mut c_void) -> c_int;
+    }
+
+    const RTLD_NOW: c_int = 2;
+
+    #[test]
+    fn test_gen_version_script() {
+        let exports = ["foo".to_owned(), "bar".to_owned()];
+
+        assert_eq!(
+            gen_version_script(Some("LIB_1.0"), &exports),
+            "LIB_1.0 {\n    global:\n        foo;\n        bar;\n    local:\n        *;\n};\n"
+        );
+        assert!(gen_version_script(None, &[]).starts_with("{\n    global:\n    local:"));
+    }
+
+    #[test]
+    fn test_c_type_of() {
+        let ctx = Context::create();
+        let mut structs = BTreeSet::new();
+        let mut c_type = |ty: AnyTypeEnum, unsigned| c_type_of(ty, unsigned, &mut structs);
+
+        let i8_t = ctx.i8_type();
+        assert_eq!(c_type(ctx.bool_type().into(), false).unwrap(), "bool");
+        assert_eq!(c_type(i8_t.into(), false).unwrap(), "int8_t");
+        assert_eq!(c_type(i8_t.into(), true).unwrap(), "uint8_t");
+        assert_eq!(c_type(ctx.i64_type().into(), true).unwrap(), "uint64_t");
+        assert_eq!(c_type(ctx.f64_type().into(), false).unwrap(), "double");
+        assert_eq!(
+            c_type(i8_t.ptr_type(AddressSpace::Generic).into(), false).unwrap(),
+            "int8_t *"
+        );
+
+        let fn_ptr_t = ctx.void_type().fn_type(&[], false).ptr_type(AddressSpace::Generic);
+        assert_eq!(c_type(fn_ptr_t.into(), false).unwrap(), "void *");
+
+        let node_t = ctx.opaque_struct_type("list.node");
+        assert_eq!(
+            c_type(node_t.ptr_type(AddressSpace::Generic).into(), false).unwrap(),
+            "struct list_node *"
+        );
+
+        assert!(c_type(i8_t.array_type(4).into(), false).is_err());
+        assert!(c_type(ctx.struct_type(&[i8_t.into()], false).into(), false).is_err());
+        assert!(c_type(ctx.custom_width_int_type(24).into(), false).is_err());
+
+        assert!(structs.contains("list_node"));
+    }
+
+    #[test]
+    fn test_check_exports_defined() {
+        let exports = ["foo".to_owned(), "bar".to_owned()];
+        let mut defined = BTreeSet::new();
+        defined.insert("foo".to_owned());
+
+        let err = check_exports_defined(&exports, &defined).unwrap_err();
+        assert!(err.to_string().ends_with(": bar"));
+
+        defined.insert("bar".to_owned());
+        assert!(check_exports_defined(&exports, &defined).is_ok());
+    }
+
+    #[test]
+    fn test_c_decls() {
+        let ctx = Context::create();
+        let module = ctx.create_module("test_c_decls");
+        let i32_t = ctx.i32_type();
+
+        impl_fn! { module |
+            f(n: usize, bytes: *u8, x: i64) -> u64 {
+                builder.build_return(Some(&n));
+            }
+        }
+        .unwrap();
+
+        let table = module.add_global(i32_t.array_type(4), None, "table");
+        table.set_initializer(&i32_t.array_type(4).const_zero());
+        table.set_constant(true);
+        let count = module.add_global(i32_t, None, "count");
+        count.set_initializer(&i32_t.const_zero());
+        record_unsigned(&module, "count", &[true]);
+
+        let exports = ["f", "table", "count", "undefined"].map(str::to_owned);
+        let decls = c_decls(&module, &exports, &mut BTreeSet::new()).unwrap();
+
+        assert_eq!(
+            decls,
+            [
+                "uint64_t f(uint64_t, uint8_t *, int64_t);",
+                "extern int32_t const table[4];",
+                "extern uint32_t count;",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dylib_round_trip() {
+        let dir = test_dir("dylib");
+        let output = dir.join("libexported.so");
+        let header = dir.join("exported.h");
+
+        let mut config = test_config(TargetType::DyLib, EmitType::Obj);
+        config.exports = vec!["add_twice".to_owned(), "counter".to_owned()];
+        config.c_header = Some(header.clone());
+
+        let job = ModBuildJob::new("exported", |vmmod| {
+            let module = &vmmod.module;
+            let i64_t = vmmod.tys.i64_t;
+
+            let counter = module.add_global(i64_t, None, "counter");
+            counter.set_initializer(&i64_t.const_int(5, false));
+
+            impl_fn! { module |
+                twice(x: u32) -> u32 {
+                    builder.build_return(Some(&builder.build_int_add(x, x, "")));
+                }
+
+                add_twice(a: u32, b: u32) -> u32 {
+                    let sum = builder.build_int_add(a, b, "");
+                    let res = builder.build_call(twice, &[sum.into()], "");
+                    builder.build_return(Some(&res.try_as_basic_value().left().unwrap()));
+                }
+            }
+        });
+        compile_and_link(&config, vec![job], &dir, &output).unwrap();
+
+        let header = fs::read_to_string(&header).unwrap();
+        assert!(header.contains("\nuint32_t add_twice(uint32_t, uint32_t);\n"), "{}", header);
+        assert!(header.contains("\nextern int64_t counter;\n"), "{}", header);
+        assert!(!header.contains(" twice("), "{}", header);
+
+        let path = CString::new(output.to_str().unwrap()).unwrap();
+        let sym = |name: &str| CString::new(name).unwrap();
+
+        unsafe {
+            let lib = dlopen(path.as_ptr(), RTLD_NOW);
+            assert!(!lib.is_null());
+
+            let add_twice = dlsym(lib, sym("add_twice").as_ptr());
+            let counter = dlsym(lib, sym("counter").as_ptr());
+            assert!(!add_twice.is_null() && !counter.is_null());
+            // not exported
+            assert!(dlsym(lib, sym("twice").as_ptr()).is_null());
+
+            let add_twice: unsafe extern "C" fn(u32, u32) -> u32 = std::mem::transmute(add_twice);
+            assert_eq!(add_twice(3, 4), 14);
+            assert_eq!(add_twice(u32::MAX, 0), u32::MAX - 1);
+            assert_eq!(*(counter as *const i64), 5);
+
+            dlclose(lib);
+        }
+    }
+}
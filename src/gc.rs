@@ -0,0 +1,299 @@
+//! Precise garbage collection with LLVM's `shadow-stack` strategy
+//!
+//! Functions holding GC references are marked `gc "shadow-stack"` and register their root
+//! slots with `llvm.gcroot`, codegen links their frames into `llvm_gc_root_chain`,
+//! which the mark-sweep collector `rt_gc_*` of the runtime walks.
+//!
+//! Objects don't move. Roots and GC fields point to the start of an object or are null.
+//! The root chain is a process-global, so only one thread may use the GC.
+
+use inkwell::{
+    builder::Builder,
+    module::Linkage,
+    targets::TargetData,
+    types::{BasicType, PointerType, StructType},
+    values::{FunctionValue, GlobalValue, PointerValue},
+    AddressSpace,
+};
+
+use crate::{builder_position_at_start, compiler::CompileResult, get_or_create_struct_type, VMMod};
+
+
+pub const SHADOW_STACK: &str = "shadow-stack";
+
+/// Struct type allocated on GC heap, with its descriptor `gc.desc.<name>`
+pub struct GcType<'ctx> {
+    pub name: String,
+    pub struct_t: StructType<'ctx>,
+    /// `%rt_gc_desc = { size, num_ptrs, ptr_offsets* }`
+    pub desc: GlobalValue<'ctx>,
+}
+
+impl<'ctx> GcType<'ctx> {
+    /// Describe `struct_t`, `gc_fields` are indexes of the fields pointing to GC objects.
+    ///
+    /// It's fine to define it again with the same size and GC field offsets,
+    /// a different one is an error.
+    pub fn define(
+        vmmod: &VMMod<'ctx>,
+        target_data: &TargetData,
+        name: &str,
+        struct_t: StructType<'ctx>,
+        gc_fields: &[u32],
+    ) -> CompileResult<Self> {
+        let desc_name = format!("gc.desc.{}", name);
+        let offsets_name = format!("{}.offsets", desc_name);
+
+        let mut offsets = vec![];
+
+        for &i in gc_fields {
+            match struct_t.get_field_type_at_index(i) {
+                Some(field_t) if field_t.is_pointer_type() => (),
+                Some(_) => return Err(format!("gc type {}: field {} isn't pointer", name, i).into()),
+                None => return Err(format!("gc type {}: no field {}", name, i).into()),
+            }
+
+            let offset = target_data.offset_of_element(&struct_t, i).unwrap();
+            offsets.push(vmmod.usize(offset as usize));
+        }
+
+        let size_t = vmmod.tys.size_t;
+        let desc_t = get_or_create_struct_type(
+            vmmod.ctx,
+            &vmmod.module,
+            "rt_gc_desc",
+            Some(&[size_t.into(), size_t.into(), vmmod.tys.sizeptr_t.into()]),
+        )?;
+
+        let offsets_init = size_t.const_array(&offsets);
+        let desc_init_of = |offsets_gv: GlobalValue<'ctx>| {
+            let offsets_ptr = offsets_gv
+                .as_pointer_value()
+                .const_cast(vmmod.tys.sizeptr_t);
+
+            desc_t.const_named_struct(&[
+                vmmod.usize(target_data.get_abi_size(&struct_t) as usize).into(),
+                vmmod.usize(offsets.len()).into(),
+                offsets_ptr.into(),
+            ])
+        };
+
+        // constants are uniqued, so the same layout gives the same initializers
+        if let Some(desc) = vmmod.module.get_global(&desc_name) {
+            let same = match vmmod.module.get_global(&offsets_name) {
+                Some(offsets_gv) => {
+                    offsets_gv.get_initializer() == Some(offsets_init.into())
+                        && desc.get_initializer() == Some(desc_init_of(offsets_gv).into())
+                }
+                None => false,
+            };
+
+            if !same {
+                return Err(format!(
+                    "gc type {}: defined again with different size or gc fields",
+                    name
+                )
+                .into());
+            }
+
+            return Ok(Self {
+                name: name.to_owned(),
+                struct_t,
+                desc,
+            });
+        }
+
+        let offsets_gv = vmmod.module.add_global(offsets_init.get_type(), None, &offsets_name);
+        offsets_gv.set_initializer(&offsets_init);
+        offsets_gv.set_constant(true);
+        offsets_gv.set_linkage(Linkage::Private);
+
+        let desc_init = desc_init_of(offsets_gv);
+        let desc = vmmod.module.add_global(desc_t, None, &desc_name);
+        desc.set_initializer(&desc_init);
+        desc.set_constant(true);
+        desc.set_linkage(Linkage::Internal);
+
+        Ok(Self {
+            name: name.to_owned(),
+            struct_t,
+            desc,
+        })
+    }
+
+    pub fn ptr_type(&self) -> PointerType<'ctx> {
+        self.struct_t.ptr_type(AddressSpace::Generic)
+    }
+}
+
+/// Use the `shadow-stack` strategy for `fn_val`
+pub fn set_shadow_stack(fn_val: FunctionValue) {
+    fn_val.set_gc(SHADOW_STACK);
+}
+
+/// Root slot of `ptr_t` in the entry block, registered with `llvm.gcroot` and initialized to null.
+///
+/// The function is switched to the `shadow-stack` strategy.
+pub fn build_gc_root<'ctx>(
+    vmmod: &VMMod<'ctx>,
+    builder: &Builder<'ctx>,
+    ptr_t: PointerType<'ctx>,
+    name: &str,
+) -> PointerValue<'ctx> {
+    let i8ptr_t = vmmod.tys.i8ptr_t;
+
+    let fn_gcroot = vmmod.module.get_function("llvm.gcroot").unwrap_or_else(|| {
+        let fn_t = vmmod.tys.void_t.fn_type(
+            &[i8ptr_t.ptr_type(AddressSpace::Generic).into(), i8ptr_t.into()],
+            false,
+        );
+
+        vmmod.module.add_function("llvm.gcroot", fn_t, None)
+    });
+
+    let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+    set_shadow_stack(fn_val);
+
+    let entry_builder = vmmod.builder();
+    builder_position_at_start(&entry_builder, fn_val.get_first_basic_block().unwrap());
+
+    let root = entry_builder.build_alloca(ptr_t, name);
+    let slot = entry_builder.build_bitcast(root, i8ptr_t.ptr_type(AddressSpace::Generic), "");
+    entry_builder.build_call(fn_gcroot, &[slot.into(), i8ptr_t.const_null().into()], "");
+    entry_builder.build_store(root, ptr_t.const_null());
+
+    root
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ptr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use inkwell::{context::Context, AddressSpace};
+
+    use super::{build_gc_root, GcType, SHADOW_STACK};
+    use crate::{
+        compiler::host_target_data,
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_gc_type() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_gc");
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+
+        let node_t = ctx.opaque_struct_type("node");
+        node_t.set_body(&[node_t.ptr_type(AddressSpace::Generic).into(), tys.i64_t.into()], false);
+
+        let err = GcType::define(&vmmod, &target_data, "node", node_t, &[1]).err().unwrap();
+        assert!(err.to_string().contains("isn't pointer"));
+        let err = GcType::define(&vmmod, &target_data, "node", node_t, &[2]).err().unwrap();
+        assert!(err.to_string().contains("no field"));
+
+        let gc_t = GcType::define(&vmmod, &target_data, "node", node_t, &[0]).unwrap();
+        GcType::define(&vmmod, &target_data, "node", node_t, &[0]).unwrap();
+
+        let wide_t = ctx.struct_type(&[node_t.ptr_type(AddressSpace::Generic).into(), tys.i128_t.into()], false);
+        let err = GcType::define(&vmmod, &target_data, "node", wide_t, &[0]).err().unwrap();
+        assert!(err.to_string().contains("defined again"));
+        assert!(GcType::define(&vmmod, &target_data, "node", node_t, &[]).is_err());
+
+        // node { next: alloc(), 7 } held by a root across the second allocation
+        let (fn_val, builder) = test_fn(&vmmod, "test_gc", gc_t.ptr_type().fn_type(&[], false));
+        let root = build_gc_root(&vmmod, &builder, gc_t.ptr_type(), "head");
+
+        let head = vmmod.build_gc_alloc(&builder, &gc_t);
+        builder.build_store(root, head);
+        let next = vmmod.build_gc_alloc(&builder, &gc_t);
+
+        let head = builder.build_load(root, "").into_pointer_value();
+        builder.build_store(builder.build_struct_gep(head, 0, "").unwrap(), next);
+        builder.build_store(builder.build_struct_gep(head, 1, "").unwrap(), tys.i64_t.const_int(7, false));
+        builder.build_return(Some(&head));
+
+        assert_eq!(fn_val.get_gc().to_str().unwrap(), SHADOW_STACK);
+        assert!(vmmod.module.get_global("gc.desc.node").is_some());
+
+        if let Err(err) = vmmod.module.verify() {
+            panic!("{}", err.to_string());
+        }
+    }
+
+    /// The JIT module defines its own `llvm_gc_root_chain` (`linkonce`, there's no linker to
+    /// pick the runtime's), so the runtime entries are wrapped to forward its head first.
+    static JIT_ROOT_CHAIN: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn forward_root_chain() {
+        let head = *(JIT_ROOT_CHAIN.load(Ordering::Acquire) as *const *mut inkwellkit_rt::StackEntry);
+
+        *ptr::addr_of_mut!(inkwellkit_rt::llvm_gc_root_chain) = head;
+    }
+
+    unsafe extern "C" fn jit_gc_alloc(desc: *const inkwellkit_rt::GcTypeDesc) -> *mut u8 {
+        forward_root_chain();
+        inkwellkit_rt::rt_gc_alloc(desc)
+    }
+
+    unsafe extern "C" fn jit_gc_collect() {
+        forward_root_chain();
+        inkwellkit_rt::rt_gc_collect()
+    }
+
+    #[test]
+    fn test_gc_collect() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_gc_collect");
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+
+        let node_t = ctx.opaque_struct_type("node");
+        node_t.set_body(&[node_t.ptr_type(AddressSpace::Generic).into(), tys.i64_t.into()], false);
+        let gc_t = GcType::define(&vmmod, &target_data, "node", node_t, &[0]).unwrap();
+
+        // () -> head.val + head.next.val, head { val: 7 } is rooted, the garbage isn't,
+        // next { val: 35 } is allocated after a collection and reachable from head only.
+        // If the collector missed the root, next reuses head's memory and it gives 70.
+        let (_, builder) = test_fn(&vmmod, "test_gc_collect", tys.i64_t.fn_type(&[], false));
+        let root = build_gc_root(&vmmod, &builder, gc_t.ptr_type(), "head");
+
+        let head = vmmod.build_gc_alloc(&builder, &gc_t);
+        builder.build_store(root, head);
+        builder.build_store(builder.build_struct_gep(head, 1, "").unwrap(), tys.i64_t.const_int(7, false));
+        vmmod.build_gc_alloc(&builder, &gc_t);
+
+        builder.build_call(vmmod.get_unchecked_fn("rt_gc_collect"), &[], "");
+
+        let next = vmmod.build_gc_alloc(&builder, &gc_t);
+        builder.build_store(builder.build_struct_gep(next, 1, "").unwrap(), tys.i64_t.const_int(35, false));
+        let head = builder.build_load(root, "").into_pointer_value();
+        builder.build_store(builder.build_struct_gep(head, 0, "").unwrap(), next);
+
+        let head = builder.build_load(root, "").into_pointer_value();
+        let next = builder
+            .build_load(builder.build_struct_gep(head, 0, "").unwrap(), "")
+            .into_pointer_value();
+        let x = builder.build_load(builder.build_struct_gep(head, 1, "").unwrap(), "").into_int_value();
+        let y = builder.build_load(builder.build_struct_gep(next, 1, "").unwrap(), "").into_int_value();
+        builder.build_return(Some(&builder.build_int_add(x, y, "")));
+
+        let ee = jit(&vmmod);
+        ee.add_global_mapping(&vmmod.get_unchecked_fn("rt_gc_alloc"), jit_gc_alloc as usize);
+        ee.add_global_mapping(&vmmod.get_unchecked_fn("rt_gc_collect"), jit_gc_collect as usize);
+
+        let chain = ee.get_global_address("llvm_gc_root_chain").unwrap();
+        JIT_ROOT_CHAIN.store(chain as usize, Ordering::Release);
+
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn() -> i64>("test_gc_collect") }.unwrap();
+
+        assert_eq!(unsafe { f.call() }, 42);
+        // the frame is popped
+        assert!(unsafe { *(chain as *const *mut u8) }.is_null());
+    }
+}
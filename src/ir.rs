@@ -0,0 +1,564 @@
+//! Runtime of `impl_ir!`: interpolate, declare what's from the host module,
+//! parse the IR and link it in.
+
+use std::collections::BTreeSet;
+
+use inkwell::{
+    memory_buffer::MemoryBuffer,
+    module::Module,
+    types::{
+        AnyType, AnyTypeEnum, ArrayType, BasicTypeEnum, FloatType,
+        FunctionType, IntType, PointerType, StructType, VectorType,
+    },
+    values::{
+        AnyValue, ArrayValue, BasicValue, BasicValueEnum, FloatValue,
+        FunctionValue, GlobalValue, IntValue, PointerValue, StructValue,
+    },
+};
+
+use crate::{compiler::CompileResult, let_module_ctx};
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Interpolation
+
+/// What's required from the host module by interpolated values
+#[derive(Default)]
+pub struct IrDeps {
+    pub symbols: BTreeSet<String>,
+    pub named_types: BTreeSet<String>,
+}
+
+impl IrDeps {
+    /// Record named struct types used by `ty`
+    pub fn add_type<'ctx>(&mut self, ty: AnyTypeEnum<'ctx>) {
+        match ty {
+            AnyTypeEnum::ArrayType(arr_t) => {
+                self.add_type(arr_t.get_element_type().as_any_type_enum())
+            }
+            AnyTypeEnum::VectorType(vec_t) => {
+                self.add_type(vec_t.get_element_type().as_any_type_enum())
+            }
+            AnyTypeEnum::PointerType(ptr_t) => {
+                self.add_type(ptr_t.get_element_type())
+            }
+            AnyTypeEnum::FunctionType(fn_t) => {
+                if let Some(ret_t) = fn_t.get_return_type() {
+                    self.add_type(ret_t.as_any_type_enum());
+                }
+                for param_t in fn_t.get_param_types() {
+                    self.add_type(param_t.as_any_type_enum());
+                }
+            }
+            AnyTypeEnum::StructType(struct_t) => {
+                match struct_t.get_name() {
+                    Some(name) => {
+                        // body is resolved with the host module
+                        self.named_types.insert(name.to_string_lossy().into_owned());
+                    }
+                    None => {
+                        for field_t in struct_t.get_field_types() {
+                            self.add_type(field_t.as_any_type_enum());
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Rust-side things which can be interpolated into `impl_ir!` by `#var`
+pub trait IrInterp {
+    fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String>;
+}
+
+impl<T: IrInterp + ?Sized> IrInterp for &T {
+    fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+        (**self).ir_repr(deps)
+    }
+}
+
+impl IrInterp for str {
+    fn ir_repr(&self, _deps: &mut IrDeps) -> CompileResult<String> {
+        Ok(self.to_owned())
+    }
+}
+
+impl IrInterp for String {
+    fn ir_repr(&self, _deps: &mut IrDeps) -> CompileResult<String> {
+        Ok(self.clone())
+    }
+}
+
+impl IrInterp for bool {
+    fn ir_repr(&self, _deps: &mut IrDeps) -> CompileResult<String> {
+        Ok(self.to_string())
+    }
+}
+
+macro_rules! impl_ir_interp_int {
+    ($($ty:ty),*) => {
+        $(
+            impl IrInterp for $ty {
+                fn ir_repr(&self, _deps: &mut IrDeps) -> CompileResult<String> {
+                    Ok(self.to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_ir_interp_int!(i8, u8, i16, u16, i32, u32, i64, u64, i128, u128, isize, usize);
+
+/// LLVM accepts exact double in hex for both float and double
+impl IrInterp for f64 {
+    fn ir_repr(&self, _deps: &mut IrDeps) -> CompileResult<String> {
+        Ok(format!("0x{:016X}", self.to_bits()))
+    }
+}
+
+impl IrInterp for f32 {
+    fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+        (*self as f64).ir_repr(deps)
+    }
+}
+
+macro_rules! impl_ir_interp_type {
+    ($($ty:ident),*) => {
+        $(
+            impl<'ctx> IrInterp for $ty<'ctx> {
+                fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+                    deps.add_type(self.as_any_type_enum());
+
+                    Ok(self.print_to_string().to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_ir_interp_type!(
+    IntType,
+    FloatType,
+    PointerType,
+    StructType,
+    ArrayType,
+    VectorType,
+    FunctionType,
+    BasicTypeEnum,
+    AnyTypeEnum
+);
+
+impl<'ctx> IrInterp for FunctionValue<'ctx> {
+    fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+        let name = self.get_name().to_string_lossy().into_owned();
+        deps.symbols.insert(name.clone());
+
+        Ok(format!("@{}", name))
+    }
+}
+
+impl<'ctx> IrInterp for GlobalValue<'ctx> {
+    fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+        let name = self.get_name().to_string_lossy().into_owned();
+        deps.symbols.insert(name.clone());
+
+        Ok(format!("@{}", name))
+    }
+}
+
+/// Constant operand without its type, e.g. `i32 5` => `5`
+fn const_operand<'ctx>(value: BasicValueEnum<'ctx>, deps: &mut IrDeps) -> CompileResult<String> {
+    let ty = value.get_type();
+    deps.add_type(ty.as_any_type_enum());
+
+    let is_const = match value {
+        BasicValueEnum::ArrayValue(v) => v.is_const(),
+        BasicValueEnum::IntValue(v) => v.is_const(),
+        BasicValueEnum::FloatValue(v) => v.is_const(),
+        BasicValueEnum::PointerValue(v) => v.is_const(),
+        BasicValueEnum::VectorValue(v) => v.is_const(),
+        BasicValueEnum::StructValue(v) => v.as_instruction_value().is_none(),
+    };
+    if !is_const {
+        return Err(format!(
+            "can only interpolate constant, found `{}`",
+            value.print_to_string().to_string().trim()
+        )
+        .into());
+    }
+
+    let repr = value.print_to_string().to_string();
+    let ty_repr = ty.print_to_string().to_string();
+
+    Ok(repr
+        .strip_prefix(&ty_repr)
+        .map(|operand| operand.trim_start().to_owned())
+        .unwrap_or(repr))
+}
+
+macro_rules! impl_ir_interp_value {
+    ($($ty:ident),*) => {
+        $(
+            impl<'ctx> IrInterp for $ty<'ctx> {
+                fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+                    const_operand(self.as_basic_value_enum(), deps)
+                }
+            }
+        )*
+    };
+}
+
+impl_ir_interp_value!(IntValue, FloatValue, PointerValue, StructValue, ArrayValue);
+
+impl<'ctx> IrInterp for BasicValueEnum<'ctx> {
+    fn ir_repr(&self, deps: &mut IrDeps) -> CompileResult<String> {
+        const_operand(*self, deps)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// IR Builder
+
+/// Generated by `impl_ir!`, each piece takes its own line
+pub struct IrBuilder {
+    file: &'static str,
+    /// (line, column) of the invocation
+    loc: (u32, u32),
+    pieces: Vec<String>,
+    /// (line, column) of each piece in `file`, `(0, 0)` if unknown
+    piece_locs: Vec<(u32, u32)>,
+    deps: IrDeps,
+    /// The first failed interpolation, returned by `splice`
+    err: Option<String>,
+}
+
+impl IrBuilder {
+    pub fn new(file: &'static str, line: u32, column: u32) -> Self {
+        Self {
+            file,
+            loc: (line, column),
+            pieces: vec![],
+            piece_locs: vec![],
+            deps: IrDeps::default(),
+            err: None,
+        }
+    }
+
+    pub fn text(&mut self, text: &str, line: u32, column: u32) {
+        self.pieces.push(text.to_owned());
+        self.piece_locs.push((line, column));
+    }
+
+    pub fn interp<T: IrInterp + ?Sized>(&mut self, value: &T, line: u32, column: u32) {
+        let repr = match value.ir_repr(&mut self.deps) {
+            Ok(repr) => repr,
+            Err(err) => {
+                if self.err.is_none() {
+                    self.err = Some(format!("{}: {}", self.at((line, column)), err));
+                }
+                String::new()
+            }
+        };
+        self.pieces.push(repr);
+        self.piece_locs.push((line, column));
+    }
+
+    pub fn extern_symbol(&mut self, name: &str) {
+        self.deps.symbols.insert(name.to_owned());
+    }
+
+    pub fn named_type(&mut self, name: &str) {
+        self.deps.named_types.insert(name.to_owned());
+    }
+
+    /// Declarations of dependencies found in `module`
+    fn prelude<'ctx>(&mut self, module: &Module<'ctx>) -> Vec<String> {
+        let defined: BTreeSet<&str> = self
+            .pieces
+            .windows(2)
+            .filter(|w| w[1] == "=")
+            .filter_map(|w| w[0].strip_prefix('%'))
+            .collect();
+
+        let mut decls = vec![];
+
+        for name in self.deps.symbols.clone() {
+            if let Some(f) = module.get_function(&name) {
+                let fn_t = f.get_type();
+                self.deps.add_type(fn_t.as_any_type_enum());
+
+                let mut params = fn_t
+                    .get_param_types()
+                    .into_iter()
+                    .map(|param_t| param_t.print_to_string().to_string())
+                    .collect::<Vec<_>>();
+                if fn_t.is_var_arg() {
+                    params.push("...".to_owned());
+                }
+
+                decls.push(format!(
+                    "declare {} @{}({})",
+                    fn_t.get_return_type()
+                        .map(|ret_t| ret_t.print_to_string().to_string())
+                        .unwrap_or_else(|| "void".to_owned()),
+                    name,
+                    params.join(", ")
+                ));
+            }
+            else if let Some(gv) = module.get_global(&name) {
+                let elem_t = gv.get_type().get_element_type();
+                self.deps.add_type(elem_t);
+
+                decls.push(format!(
+                    "@{} = external global {}",
+                    name,
+                    elem_t.print_to_string()
+                ));
+            }
+        }
+
+        // named types (and the ones nested in their bodies)
+        let mut type_decls = vec![];
+        let mut visited = BTreeSet::new();
+        let mut queue = self.deps.named_types.iter().cloned().collect::<Vec<_>>();
+
+        while let Some(name) = queue.pop() {
+            if defined.contains(name.as_str()) || !visited.insert(name.clone()) {
+                continue;
+            }
+
+            let struct_t = match module.get_struct_type(&name) {
+                Some(struct_t) => struct_t,
+                None => continue,
+            };
+
+            if struct_t.is_opaque() {
+                type_decls.push(format!("%{} = type opaque", name));
+                continue;
+            }
+
+            let mut nested = IrDeps::default();
+            let fields = struct_t
+                .get_field_types()
+                .into_iter()
+                .map(|field_t| {
+                    nested.add_type(field_t.as_any_type_enum());
+                    field_t.print_to_string().to_string()
+                })
+                .collect::<Vec<_>>();
+            queue.extend(nested.named_types);
+
+            let (open, close) = if struct_t.is_packed() {
+                ("<{", "}>")
+            } else {
+                ("{", "}")
+            };
+
+            type_decls.push(format!(
+                "%{} = type {} {} {}",
+                name,
+                open,
+                fields.join(", "),
+                close
+            ));
+        }
+
+        type_decls.extend(decls);
+        type_decls
+    }
+
+    fn at(&self, (line, column): (u32, u32)) -> String {
+        format!("impl_ir! at {}:{}:{}", self.file, line, column)
+    }
+
+    /// Map LLVM error `ir:LINE:COL: msg` back to the piece of the invocation
+    fn report(&self, prelude_len: usize, err: &str) -> String {
+        let mut parts = err.splitn(4, ':');
+        let line = parts.nth(1).and_then(|line| line.trim().parse::<usize>().ok());
+        let column = parts.next().and_then(|col| col.trim().parse::<u32>().ok());
+        let msg = match (line, column) {
+            (Some(_), Some(_)) => parts.next().unwrap_or(err).trim(),
+            _ => err,
+        };
+
+        match line {
+            Some(line) if line > prelude_len && line - prelude_len <= self.pieces.len() => {
+                let i = line - prelude_len - 1;
+                let start = i.saturating_sub(4);
+                let end = (i + 4).min(self.pieces.len());
+
+                let loc = match self.piece_locs[i] {
+                    (0, _) => self.loc,
+                    (line, column_start) => {
+                        (line, column_start + column.unwrap_or(1).saturating_sub(1))
+                    }
+                };
+
+                format!(
+                    "{}: {}, near `{}`",
+                    self.at(loc),
+                    msg,
+                    self.pieces[start..end].join(" ")
+                )
+            }
+            Some(_) => format!("{}: in declarations from the module: {}", self.at(self.loc), msg),
+            None => format!("{}: {}", self.at(self.loc), msg),
+        }
+    }
+
+    /// Parse the IR, verify and link it into `module`
+    pub fn splice<'ctx>(mut self, module: &Module<'ctx>) -> CompileResult<()> {
+        let_module_ctx!(ctx = module);
+
+        if let Some(err) = self.err.take() {
+            return Err(err.into());
+        }
+
+        let prelude = self.prelude(module);
+        let prelude_len = prelude.len();
+
+        // the first piece is at line `prelude_len + 1`
+        let text = prelude
+            .iter()
+            .chain(self.pieces.iter())
+            .map(|line| line.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let buf = MemoryBuffer::create_from_memory_range_copy(text.as_bytes(), "ir");
+        let ir_module = ctx
+            .create_module_from_ir(buf)
+            .map_err(|err| self.report(prelude_len, &err.to_string()))?;
+
+        ir_module.set_triple(&module.get_triple());
+        ir_module.set_data_layout(&module.get_data_layout());
+
+        ir_module
+            .verify()
+            .map_err(|err| format!("{}: {}", self.at(self.loc), err.to_string().trim()))?;
+
+        module
+            .link_in_module(ir_module)
+            .map_err(|err| format!("{}: {}", self.at(self.loc), err.to_string().trim()))?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+
+    use super::*;
+    use crate::{
+        impl_ir,
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    fn builder() -> IrBuilder {
+        let mut ir = IrBuilder::new("src/gen.rs", 10, 5);
+
+        for (i, text) in ["define", "void", "@f()", "{", "ret", "void", "}"].iter().enumerate() {
+            ir.text(text, 11 + i as u32 / 4, 9 + i as u32 * 8);
+        }
+
+        ir
+    }
+
+    #[test]
+    fn test_report_maps_line_to_piece() {
+        // 2 lines of prelude, so the second piece is at line 4
+        let msg = builder().report(2, "ir:4:3: error: expected type");
+
+        assert!(
+            msg.starts_with("impl_ir! at src/gen.rs:11:19: error: expected type, near `"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_report_outside_pieces() {
+        let msg = builder().report(2, "ir:1:1: error: redefinition");
+        assert!(msg.starts_with("impl_ir! at src/gen.rs:10:5: in declarations"), "{}", msg);
+
+        let msg = builder().report(2, "unexpected");
+        assert_eq!(msg, "impl_ir! at src/gen.rs:10:5: unexpected");
+    }
+
+    #[test]
+    fn test_impl_ir() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_impl_ir");
+        let module = &vmmod.module;
+        let i64_t = vmmod.tys.i64_t;
+
+        let base = module.add_global(i64_t, None, "base");
+        base.set_initializer(&i64_t.const_int(30, false));
+
+        let (double, builder) = test_fn(&vmmod, "double", i64_t.fn_type(&[i64_t.into()], false));
+        let x = double.get_nth_param(0).unwrap().into_int_value();
+        builder.build_return(Some(&builder.build_int_add(x, x, "")));
+
+        let k = i64_t.const_int(12, false);
+
+        // (x + base) * 2 + k
+        impl_ir!(module |
+            define i64 @ir_f(#i64_t %x) {
+            entry:
+                %b = load i64, i64* #base
+                %y = add i64 %x, %b
+                %z = call i64 #double(i64 %y)
+                %r = add i64 %z, #k
+                ret i64 %r
+            }
+        )
+        .unwrap();
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn(i64) -> i64>("ir_f") }.unwrap();
+
+        assert_eq!(unsafe { f.call(100) }, (100 + 30) * 2 + 12);
+    }
+
+    #[test]
+    fn test_impl_ir_errors() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_impl_ir_errors");
+        let module = &vmmod.module;
+        let i64_t = vmmod.tys.i64_t;
+
+        let (fn_val, _) = test_fn(&vmmod, "host", i64_t.fn_type(&[i64_t.into()], false));
+        let param = fn_val.get_nth_param(0).unwrap().into_int_value();
+
+        let err = impl_ir!(module |
+            define i64 @non_const() {
+            entry:
+                ret i64 #param
+            }
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("impl_ir! at src/ir.rs:"), "{}", err);
+        assert!(err.contains("can only interpolate constant"), "{}", err);
+
+        let err = impl_ir!(module |
+            define i64 @mistyped(i64 %x) {
+            entry:
+                ret i32 %x
+            }
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("impl_ir! at src/ir.rs:"), "{}", err);
+        assert!(err.contains("near `"), "{}", err);
+
+        assert!(module.get_function("non_const").is_none());
+        assert!(module.get_function("mistyped").is_none());
+    }
+}
@@ -1,22 +1,53 @@
+extern crate self as inkwellkit;
+
 pub mod config;
+pub mod compiler;
+pub mod cache;
+pub mod export;
+pub mod ir;
+pub mod repr;
+pub mod slice;
+pub mod dynvec;
+pub mod structs;
+pub mod tagged;
+pub mod strbuf;
+pub mod conv;
+pub mod arena;
+pub mod rc;
+pub mod gc;
+mod common;
 
 use either::Either;
 pub use inkwell::*;
+pub use common::CommonTypes;
+
+use arena::Arena;
+use compiler::CompileResult;
+use gc::GcType;
+use slice::{SliceValue, StrValue};
 
 use inkwell::{
+    attributes::{Attribute, AttributeLoc},
     basic_block::BasicBlock,
     builder::Builder,
     context::{Context, ContextRef},
     module::{Linkage, Module},
-    values::{BasicMetadataValueEnum, IntValue, PointerValue, FunctionValue, BasicValueEnum, FloatValue, VectorValue},
+    targets::TargetData,
+    types::{BasicType, BasicTypeEnum, IntType, StructType},
+    values::{BasicMetadataValueEnum, BasicValue, IntValue, PointerValue, FunctionValue, BasicValueEnum, FloatValue, VectorValue},
 };
 
-pub use proc_macros::{impl_fn_hdr, load_vm_common_ty};
+pub use proc_macros::{impl_fn, impl_fn_hdr, impl_ir, load_vm_common_ty, LlvmType};
 
 thread_local! {
     pub static CTX: ContextRef<'static> = ContextRef::new2();
 
     // pub static CTX: &'static Context = & Context::create();
+
+    static COMMON_TYS: CommonTypes<'static> = {
+        CommonTypes::register(get_ctx());
+        CommonTypes::get(get_ctx())
+    };
 }
 
 #[inline]
@@ -24,19 +55,99 @@ pub fn get_ctx<'ctx>() -> &'ctx Context {
     CTX.with(|ctx| unsafe { ctx.get() })
 }
 
+/// Common types of the thread-local `CTX`
+#[inline]
+pub fn get_common_tys<'ctx>() -> CommonTypes<'ctx> {
+    COMMON_TYS.with(|tys| *tys)
+}
+
 // pub type IncludeClosure<'ctx> = Box<dyn FnOnce(&Module<'ctx>) + 'ctx>;
 
+/// Own a fresh `Context` instead of the thread-wide `CTX`.
+///
+/// All types, constants and modules created by it are released on drop or `reset`,
+/// and borrowck guarantees no `VMMod` outlives them.
+pub struct ScopedCtx {
+    ctx: Context,
+}
+
+impl ScopedCtx {
+    pub fn new() -> Self {
+        let ctx = Context::create();
+        CommonTypes::register(&ctx);
+
+        Self { ctx }
+    }
+
+    pub fn ctx(&self) -> &Context {
+        &self.ctx
+    }
+
+    pub fn create_vmmod(&self, name: &str) -> VMMod<'_> {
+        VMMod::new_in(&self.ctx, name)
+    }
+
+    /// Drop the underlying context and start with an empty one
+    pub fn reset(&mut self) {
+        CommonTypes::unregister(&self.ctx);
+        self.ctx = Context::create();
+        CommonTypes::register(&self.ctx);
+    }
+}
+
+impl Drop for ScopedCtx {
+    fn drop(&mut self) {
+        CommonTypes::unregister(&self.ctx);
+    }
+}
+
+impl Default for ScopedCtx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` with a fresh context, which is released when the block ends.
+pub fn with_scoped_ctx<R, F>(f: F) -> R
+where
+    F: for<'ctx> FnOnce(&'ctx Context) -> R,
+{
+    let scoped = ScopedCtx::new();
+
+    f(scoped.ctx())
+}
+
 pub struct VMMod<'ctx> {
+    pub ctx: &'ctx Context,
+    pub tys: CommonTypes<'ctx>,
     pub module: Module<'ctx>,
+    /// Emit the checks of `build_checked_*`, set from `CompilerConfig::bounds_check`
+    pub bounds_check: bool,
 }
 
 #[allow(unused)]
 impl<'ctx> VMMod<'ctx> {
+    /// Create on the thread-local `CTX`
     pub fn new(name: &str) -> Self {
-        let module = get_ctx().create_module(name);
+        let ctx = get_ctx();
+        let module = ctx.create_module(name);
 
         Self {
+            ctx,
+            tys: get_common_tys(),
             module,
+            bounds_check: true,
+        }
+    }
+
+    pub fn new_in(ctx: &'ctx Context, name: &str) -> Self {
+        let module = ctx.create_module(name);
+
+        Self {
+            ctx,
+            tys: CommonTypes::get(ctx),
+            module,
+            bounds_check: true,
         }
     }
 
@@ -52,12 +163,34 @@ impl<'ctx> VMMod<'ctx> {
     pub fn include_stdio(module: &Module<'ctx>) {
         impl_fn_hdr![ module |
             printf(*i8, ...) -> i32;
+            dprintf(i32, *i8, ...) -> i32;
+            snprintf(*i8, usize, *i8, ...) -> i32;
+        ];
+    }
+
+    pub fn include_stdlib(module: &Module<'ctx>) {
+        impl_fn_hdr![ module |
+            #[noreturn, nounwind] exit(i32);
+            #[noreturn, nounwind] abort();
+            #[nounwind, ret(noalias)] malloc(usize) -> *void;
+            #[nounwind, ret(noalias)] aligned_alloc(usize, usize) -> *void;
+            #[nounwind] realloc(*void, usize) -> *void;
+            #[nounwind] free(*void);
+        ];
+    }
+
+    pub fn include_mman(module: &Module<'ctx>) {
+        impl_fn_hdr![ module |
+            #[nounwind] mmap(*void, usize, i32, i32, i32, i64) -> *void;
+            #[nounwind] munmap(*void, usize) -> i32;
         ];
     }
 
     pub fn include_string(module: &Module<'ctx>) {
         impl_fn_hdr![ module |
             strlen(*i8) -> usize;
+            #[nounwind, readonly] memcmp(*void, *void, usize) -> i32;
+            #[nounwind] memcpy(*void, *void, usize) -> *void;
         ];
     }
 
@@ -69,42 +202,131 @@ impl<'ctx> VMMod<'ctx> {
         ];
     }
 
+    ///////////////////////////////////
+    //// Runtime (`libinkwellkit_rt.a`)
+
+    /// Declare `rt_*` of `runtime/src/lib.rs`, `test_include_runtime_in_sync` checks the names and arities.
+    pub fn include_runtime(module: &Module<'ctx>) {
+        impl_fn_hdr![ module |
+            #[noreturn, nounwind] rt_panic(*u8, usize);
+            #[noreturn, nounwind] rt_abort();
+
+            rt_vec_new(usize, usize) -> *struct rt_vec;
+            rt_vec_push(*struct rt_vec, *u8);
+            rt_vec_pop(*struct rt_vec, *u8) -> bool;
+            rt_vec_get(*struct rt_vec, usize) -> *u8;
+            rt_vec_len(*struct rt_vec) -> usize;
+            rt_vec_free(*struct rt_vec);
+
+            rt_str_new() -> *struct rt_str;
+            rt_str_push(*struct rt_str, *u8, usize);
+            rt_str_push_i64(*struct rt_str, i64);
+            rt_str_len(*struct rt_str) -> usize;
+            rt_str_ptr(*struct rt_str) -> *i8;
+            rt_str_eq(*struct rt_str, *u8, usize) -> bool;
+            rt_str_free(*struct rt_str);
+
+            rt_map_new() -> *struct rt_map;
+            rt_map_insert(*struct rt_map, *u8, usize, i64) -> bool;
+            rt_map_get(*struct rt_map, *u8, usize, *i64) -> bool;
+            rt_map_remove(*struct rt_map, *u8, usize) -> bool;
+            rt_map_len(*struct rt_map) -> usize;
+            rt_map_free(*struct rt_map);
+
+            rt_gc_alloc(*struct rt_gc_desc { usize, usize, *usize }) -> *u8;
+            rt_gc_collect();
+        ];
+    }
+
     ///////////////////////////////////
     //// Get Function
     pub fn get_unchecked_fn(&self, name: &str) -> FunctionValue<'ctx> {
         self.module.get_function(name).unwrap()
     }
 
+    ///////////////////////////////////
+    //// Named Struct Types
+
+    /// Named struct `name`, opaque one is created at the first call.
+    ///
+    /// Named types live in the context, so modules of the same context share them.
+    pub fn declare_struct(&self, name: &str) -> StructType<'ctx> {
+        self.module
+            .get_struct_type(name)
+            .unwrap_or_else(|| self.ctx.opaque_struct_type(name))
+    }
+
+    /// Set body of struct `name` (declare it if not yet),
+    /// redefinition is fine only if it's the same.
+    pub fn define_struct(
+        &self,
+        name: &str,
+        fields: &[BasicTypeEnum<'ctx>],
+        packed: bool,
+    ) -> CompileResult<StructType<'ctx>> {
+        let struct_t = self.declare_struct(name);
+
+        set_struct_body(struct_t, name, fields, packed)?;
+
+        Ok(struct_t)
+    }
+
+    pub fn get_struct(&self, name: &str) -> Option<StructType<'ctx>> {
+        self.module.get_struct_type(name)
+    }
+
     ///////////////////////////////////
     //// Builder
 
+    #[deprecated(note = "it's on the thread-local `CTX`, use `vmmod.builder()` instead")]
     pub fn get_builder() -> Builder<'ctx> {
         get_ctx().create_builder()
     }
 
+    #[deprecated(note = "it's on the thread-local `CTX`, use `vmmod.builder_at_end(blk)` instead")]
     pub fn get_builder_at_end(blk: BasicBlock<'ctx>) -> Builder<'ctx> {
-        let builder = Self::get_builder();
+        let builder = get_ctx().create_builder();
 
         builder.position_at_end(blk);
 
         builder
     }
 
+    #[deprecated(note = "it's on the thread-local `CTX`, use `vmmod.builder_at_start(blk)` instead")]
     pub fn get_builder_at_start(blk: BasicBlock<'ctx>) -> Builder<'ctx> {
-        let builder = Self::get_builder();
+        let builder = get_ctx().create_builder();
 
         builder_position_at_start(&builder, blk);
 
         builder
     }
 
-    pub fn append_main(&self) -> BasicBlock<'ctx> {
-        load_vm_common_ty!(get_ctx());
+    /// Builder on the module's own context (works for scoped contexts)
+    pub fn builder(&self) -> Builder<'ctx> {
+        self.ctx.create_builder()
+    }
+
+    pub fn builder_at_end(&self, blk: BasicBlock<'ctx>) -> Builder<'ctx> {
+        let builder = self.builder();
+
+        builder.position_at_end(blk);
+
+        builder
+    }
+
+    pub fn builder_at_start(&self, blk: BasicBlock<'ctx>) -> Builder<'ctx> {
+        let builder = self.builder();
+
+        builder_position_at_start(&builder, blk);
 
-        let fn_main_t = i64_t.fn_type(&[], false);
+        builder
+    }
+
+    pub fn append_main(&self) -> BasicBlock<'ctx> {
+        let fn_main_t = self.tys.i64_t.fn_type(&[], false);
         let fn_main = self.module.add_function("main", fn_main_t, None);
 
-        get_ctx().append_basic_block(fn_main, "blk_main")
+        self.ctx.append_basic_block(fn_main, "blk_main")
     }
 
     //////////////////////////////////////////////////////////////////////
@@ -116,9 +338,7 @@ impl<'ctx> VMMod<'ctx> {
     }
 
     pub fn bcnt_init(&self, builder: &Builder<'ctx>, init: IntValue<'ctx>) -> PointerValue<'ctx> {
-        load_vm_common_ty!(get_ctx());
-
-        let var = builder.build_alloca(i32_t, "");
+        let var = builder.build_alloca(self.tys.i32_t, "");
         builder.build_store(var, init);
 
         var
@@ -155,36 +375,47 @@ impl<'ctx> VMMod<'ctx> {
         builder: &Builder<'ctx>,
         value: &str,
     ) -> (PointerValue<'ctx>, IntValue<'ctx>) {
-        load_vm_common_ty!(get_ctx());
-        let var = get_ctx().const_string(value.as_bytes(), true);
+        let var = self.ctx.const_string(value.as_bytes(), true);
         let len = self.usize(value.len());
 
         let var_ptr = builder.build_alloca(var.get_type(), "");
         builder.build_store(var_ptr, var);
 
         let var_ptr_cast = builder
-            .build_bitcast(var_ptr, i8ptr_t, "")
+            .build_bitcast(var_ptr, self.tys.i8ptr_t, "")
             .into_pointer_value();
 
         (var_ptr_cast, len)
     }
 
+    /// String on stack as fat pointer, without NUL
+    pub fn build_local_str_value(&self, builder: &Builder<'ctx>, value: &str) -> StrValue<'ctx> {
+        let var = self.ctx.const_string(value.as_bytes(), false);
+
+        let var_ptr = builder.build_alloca(var.get_type(), "");
+        builder.build_store(var_ptr, var);
+
+        let ptr = builder
+            .build_bitcast(var_ptr, self.tys.i8ptr_t, "")
+            .into_pointer_value();
+
+        StrValue(SliceValue::new(ptr, self.usize(value.len())))
+    }
+
     /// (*u8, len)
     pub fn build_local_const_u8_array(
         &self,
         builder: &Builder<'ctx>,
         values: &[IntValue<'ctx>],
     ) -> (PointerValue<'ctx>, IntValue<'ctx>) {
-        load_vm_common_ty!(get_ctx());
-
-        let var = i8_t.const_array(values);
+        let var = self.tys.i8_t.const_array(values);
         let len = self.usize((values.len() as u64).try_into().unwrap());
 
         let var_ptr = builder.build_alloca(var.get_type(), "");
         builder.build_store(var_ptr, var);
 
         let var_ptr_cast = builder
-            .build_bitcast(var_ptr, i8ptr_t, "")
+            .build_bitcast(var_ptr, self.tys.i8ptr_t, "")
             .into_pointer_value();
 
         (var_ptr_cast, len)
@@ -196,16 +427,14 @@ impl<'ctx> VMMod<'ctx> {
         builder: &Builder<'ctx>,
         values: &[IntValue<'ctx>],
     ) -> (PointerValue<'ctx>, IntValue<'ctx>) {
-        load_vm_common_ty!(get_ctx());
-
-        let var = size_t.const_array(values);
+        let var = self.tys.size_t.const_array(values);
         let len = self.usize((values.len() as u64).try_into().unwrap());
 
         let var_ptr = builder.build_alloca(var.get_type(), "");
         builder.build_store(var_ptr, var);
 
         let var_ptr_cast = builder
-            .build_bitcast(var_ptr, sizeptr_t, "")
+            .build_bitcast(var_ptr, self.tys.sizeptr_t, "")
             .into_pointer_value();
 
         (var_ptr_cast, len)
@@ -217,11 +446,9 @@ impl<'ctx> VMMod<'ctx> {
         builder: &Builder<'ctx>,
         values: &[IntValue<'ctx>],
     ) -> (PointerValue<'ctx>, IntValue<'ctx>) {
-        load_vm_common_ty!(get_ctx());
-
         let len = self.usize((values.len() as u64).try_into().unwrap());
 
-        let var_ptr = builder.build_array_alloca(size_t, len, "");
+        let var_ptr = builder.build_array_alloca(self.tys.size_t, len, "");
         for (i, value) in values.into_iter().enumerate() {
             let idx = self.usize(i);
             let ptr = unsafe { builder.build_in_bounds_gep(var_ptr, &[idx], "") };
@@ -229,7 +456,7 @@ impl<'ctx> VMMod<'ctx> {
         }
 
         let var_ptr_cast = builder
-            .build_bitcast(var_ptr, sizeptr_t, "")
+            .build_bitcast(var_ptr, self.tys.sizeptr_t, "")
             .into_pointer_value();
 
         (var_ptr_cast, len)
@@ -250,6 +477,366 @@ impl<'ctx> VMMod<'ctx> {
         builder.build_call(fn_printf, &args[..], "");
     }
 
+    /// Same as `build_call_printf`, but `values` are checked against `fcs`.
+    pub fn build_call_printf_checked(
+        &self,
+        builder: &Builder<'ctx>,
+        fcs: &str,
+        values: &[BasicMetadataValueEnum<'ctx>],
+    ) -> CompileResult<()> {
+        strbuf::check_format(&self.tys, fcs, values)?;
+        self.build_call_printf(builder, fcs, values);
+
+        Ok(())
+    }
+
+    //////////////////////////////////////////////////////////////////////
+    //// Heap
+    //////////////////////////////////////////////////////////////////////
+
+    /// `malloc` one `ty`, return `ty*`, abort if out of memory.
+    pub fn build_malloc(
+        &self,
+        builder: &Builder<'ctx>,
+        target_data: &TargetData,
+        ty: BasicTypeEnum<'ctx>,
+        name: &str,
+    ) -> PointerValue<'ctx> {
+        self.build_array_malloc(builder, target_data, ty, self.usize(1), name)
+    }
+
+    /// `malloc` `len` (unsigned) of `ty`, return `ty*`, abort if the size overflows
+    /// or out of memory. It may be null for `len` of zero.
+    ///
+    /// `aligned_alloc` is used instead if `ty` is aligned more than `malloc` does
+    /// (two pointers, e.g. `StructLayout::Aligned(64)`).
+    pub fn build_array_malloc(
+        &self,
+        builder: &Builder<'ctx>,
+        target_data: &TargetData,
+        ty: BasicTypeEnum<'ctx>,
+        len: IntValue<'ctx>,
+        name: &str,
+    ) -> PointerValue<'ctx> {
+        Self::include_stdlib(&self.module);
+        let fn_malloc = self.module.get_function("malloc").unwrap();
+
+        let size = self.build_checked_size(builder, len, self.usize(target_data.get_abi_size(&ty) as usize));
+        let align = target_data.get_abi_alignment(&ty);
+
+        // size of `ty` is a multiple of its alignment, as `aligned_alloc` requires
+        let ptr = if align > 2 * target_data.get_pointer_byte_size(None) {
+            let fn_aligned_alloc = self.module.get_function("aligned_alloc").unwrap();
+            let align = self.usize(align as usize);
+
+            ret_as_bv!(builder.build_call(fn_aligned_alloc, &[align.into(), size.into()], ""))
+        }
+        else {
+            ret_as_bv!(builder.build_call(fn_malloc, &[size.into()], ""))
+        };
+
+        // null of zero size isn't out of memory
+        let is_null = builder.build_is_null(ptr.into_pointer_value(), "");
+        let nonzero = builder.build_int_compare(IntPredicate::NE, size, self.usize(0), "");
+        build_abort_if(&self.module, builder, builder.build_and(is_null, nonzero, ""), "oom");
+
+        builder
+            .build_bitcast(ptr, ty.ptr_type(AddressSpace::Generic), name)
+            .into_pointer_value()
+    }
+
+    /// Allocate `len` (unsigned) of `ty` from `arena`, a handle of `arena_def`, return `ty*`,
+    /// abort if the size overflows.
+    pub fn build_arena_alloc(
+        &self,
+        builder: &Builder<'ctx>,
+        target_data: &TargetData,
+        arena_def: &Arena<'ctx>,
+        arena: PointerValue<'ctx>,
+        ty: BasicTypeEnum<'ctx>,
+        len: IntValue<'ctx>,
+    ) -> PointerValue<'ctx> {
+        let size = self.build_checked_size(builder, len, self.usize(target_data.get_abi_size(&ty) as usize));
+        let align = self.usize(target_data.get_abi_alignment(&ty) as usize);
+
+        let ptr = arena_def.build_alloc(&self.module, builder, arena, size, align);
+
+        builder
+            .build_bitcast(ptr, ty.ptr_type(AddressSpace::Generic), "")
+            .into_pointer_value()
+    }
+
+    /// Zeroed object of `gc_t` on GC heap of the runtime, it may collect first.
+    ///
+    /// Keep it in a root of `gc::build_gc_root` across the next allocation.
+    pub fn build_gc_alloc(&self, builder: &Builder<'ctx>, gc_t: &GcType<'ctx>) -> PointerValue<'ctx> {
+        Self::include_runtime(&self.module);
+        let fn_gc_alloc = self.module.get_function("rt_gc_alloc").unwrap();
+
+        let obj = ret_as_bv!(builder.build_call(fn_gc_alloc, &[gc_t.desc.as_pointer_value().into()], ""));
+
+        builder
+            .build_bitcast(obj, gc_t.ptr_type(), &gc_t.name)
+            .into_pointer_value()
+    }
+
+    /// `free` pointer of any type
+    pub fn build_free(&self, builder: &Builder<'ctx>, ptr: PointerValue<'ctx>) {
+        Self::include_stdlib(&self.module);
+        let fn_free = self.module.get_function("free").unwrap();
+
+        let ptr = builder.build_bitcast(ptr, self.tys.i8ptr_t, "");
+        builder.build_call(fn_free, &[ptr.into()], "");
+    }
+
+    /// `llvm.memcpy` of `size` bytes, both sides are aligned to `align` (power of two).
+    pub fn build_memcpy(
+        &self,
+        builder: &Builder<'ctx>,
+        dst: PointerValue<'ctx>,
+        src: PointerValue<'ctx>,
+        size: IntValue<'ctx>,
+        align: u32,
+    ) -> CompileResult<()> {
+        builder.build_memcpy(dst, align, src, align, size)?;
+
+        Ok(())
+    }
+
+    /// `llvm.memmove` of `size` bytes, both sides are aligned to `align` (power of two).
+    pub fn build_memmove(
+        &self,
+        builder: &Builder<'ctx>,
+        dst: PointerValue<'ctx>,
+        src: PointerValue<'ctx>,
+        size: IntValue<'ctx>,
+        align: u32,
+    ) -> CompileResult<()> {
+        builder.build_memmove(dst, align, src, align, size)?;
+
+        Ok(())
+    }
+
+    /// `llvm.memset` of `size` bytes to `byte` (`i8`), `dst` is aligned to `align` (power of two).
+    pub fn build_memset(
+        &self,
+        builder: &Builder<'ctx>,
+        dst: PointerValue<'ctx>,
+        byte: IntValue<'ctx>,
+        size: IntValue<'ctx>,
+        align: u32,
+    ) -> CompileResult<()> {
+        builder.build_memset(dst, align, byte, size)?;
+
+        Ok(())
+    }
+
+    /// Copy `len` elements from `src` to `dst` (non-overlapping),
+    /// size and alignment come from the element type of `dst`.
+    pub fn build_copy_elems(
+        &self,
+        builder: &Builder<'ctx>,
+        target_data: &TargetData,
+        dst: PointerValue<'ctx>,
+        src: PointerValue<'ctx>,
+        len: IntValue<'ctx>,
+    ) -> CompileResult<()> {
+        let (size, align) = self.elems_size_align(builder, target_data, dst, len)?;
+
+        self.build_memcpy(builder, dst, src, size, align)
+    }
+
+    /// Zero `len` elements from `dst`, size and alignment come from the element type.
+    pub fn build_zero_elems(
+        &self,
+        builder: &Builder<'ctx>,
+        target_data: &TargetData,
+        dst: PointerValue<'ctx>,
+        len: IntValue<'ctx>,
+    ) -> CompileResult<()> {
+        let (size, align) = self.elems_size_align(builder, target_data, dst, len)?;
+
+        self.build_memset(builder, dst, self.u8(0), size, align)
+    }
+
+    /// (bytes of `len` elements of `ptr`, ABI alignment)
+    fn elems_size_align(
+        &self,
+        builder: &Builder<'ctx>,
+        target_data: &TargetData,
+        ptr: PointerValue<'ctx>,
+        len: IntValue<'ctx>,
+    ) -> CompileResult<(IntValue<'ctx>, u32)> {
+        let elem_t = BasicTypeEnum::try_from(ptr.get_type().get_element_type())
+            .map_err(|_| format!("{:?} isn't pointer to sized type", ptr.get_type()))?;
+
+        Ok((
+            self.build_checked_size(builder, len, self.usize(target_data.get_abi_size(&elem_t) as usize)),
+            target_data.get_abi_alignment(&elem_t),
+        ))
+    }
+
+    /// `len * elem_size` bytes as `size_t`, `len` is unsigned, abort if it overflows.
+    pub(crate) fn build_checked_size(
+        &self,
+        builder: &Builder<'ctx>,
+        len: IntValue<'ctx>,
+        elem_size: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        let size_t = self.tys.size_t;
+        let len_t = len.get_type();
+
+        // high bits of a wider `len` are lost by the truncation
+        if len_t.get_bit_width() > size_t.get_bit_width() {
+            let size_max = builder.build_int_z_extend(size_t.const_all_ones(), len_t, "");
+            let truncated = builder.build_int_compare(IntPredicate::UGT, len, size_max, "");
+            build_abort_if(&self.module, builder, truncated, "size");
+        }
+
+        let len = self.build_size_cast(builder, len, false);
+
+        build_checked_mul(&self.module, builder, len, elem_size)
+    }
+
+    //////////////////////////////////////////////////////////////////////
+    //// Checked Access
+    //////////////////////////////////////////////////////////////////////
+
+    /// `&ptr[idx]` after checking `0 <= idx < len` (`len` is unsigned, `idx` is
+    /// if not `signed`), the failure prints "index N out of bounds for length M"
+    /// after `loc` (e.g. `"main.dsl:3:7"`) and aborts.
+    ///
+    /// The check is omitted unless `self.bounds_check`.
+    pub fn build_checked_gep(
+        &self,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        len: IntValue<'ctx>,
+        idx: IntValue<'ctx>,
+        signed: bool,
+        loc: Option<&str>,
+    ) -> PointerValue<'ctx> {
+        if self.bounds_check {
+            self.build_bounds_check(builder, idx, signed, len, loc);
+        }
+
+        // GEP indices are signed, unsigned ones must not be sign extended
+        let idx = self.build_size_cast(builder, idx, signed);
+
+        unsafe { builder.build_in_bounds_gep(ptr, &[idx], "") }
+    }
+
+    pub fn build_checked_load(
+        &self,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        len: IntValue<'ctx>,
+        idx: IntValue<'ctx>,
+        signed: bool,
+        loc: Option<&str>,
+    ) -> BasicValueEnum<'ctx> {
+        let elem_ptr = self.build_checked_gep(builder, ptr, len, idx, signed, loc);
+
+        builder.build_load(elem_ptr, "")
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn build_checked_store<V: BasicValue<'ctx>>(
+        &self,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        len: IntValue<'ctx>,
+        idx: IntValue<'ctx>,
+        signed: bool,
+        value: V,
+        loc: Option<&str>,
+    ) {
+        let elem_ptr = self.build_checked_gep(builder, ptr, len, idx, signed, loc);
+
+        builder.build_store(elem_ptr, value);
+    }
+
+    /// `value` as `size_t`: truncated if it's wider, else extended by `signed`
+    pub(crate) fn build_size_cast(
+        &self,
+        builder: &Builder<'ctx>,
+        value: IntValue<'ctx>,
+        signed: bool,
+    ) -> IntValue<'ctx> {
+        let size_t = self.tys.size_t;
+
+        if value.get_type().get_bit_width() > size_t.get_bit_width() {
+            builder.build_int_truncate(value, size_t, "")
+        }
+        else if signed {
+            builder.build_int_s_extend_or_bit_cast(value, size_t, "")
+        }
+        else {
+            builder.build_int_z_extend_or_bit_cast(value, size_t, "")
+        }
+    }
+
+    /// Continue in `bounds.ok` if `0 <= idx < len`, or call cold `bounds.fail` in `bounds.oob`.
+    ///
+    /// They're compared in the wider type of them, a negative `idx` fails before that.
+    fn build_bounds_check(
+        &self,
+        builder: &Builder<'ctx>,
+        idx: IntValue<'ctx>,
+        signed: bool,
+        len: IntValue<'ctx>,
+        loc: Option<&str>,
+    ) {
+        let fn_fail = get_or_gen_bounds_fail(&self.module);
+        let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+
+        let blk_oob = self.ctx.append_basic_block(fn_val, "bounds.oob");
+        let blk_ok = self.ctx.append_basic_block(fn_val, "bounds.ok");
+
+        let neg = if signed {
+            let blk_nonneg = self.ctx.append_basic_block(fn_val, "bounds.nonneg");
+            blk_nonneg.move_before(blk_oob).unwrap();
+
+            let neg = builder.build_int_compare(
+                IntPredicate::SLT,
+                idx,
+                idx.get_type().const_zero(),
+                "neg",
+            );
+            builder.build_conditional_branch(neg, blk_oob, blk_nonneg);
+            builder.position_at_end(blk_nonneg);
+
+            neg
+        }
+        else {
+            self.tys.i1_t.const_zero()
+        };
+
+        let idx_w = idx.get_type().get_bit_width();
+        let len_w = len.get_type().get_bit_width();
+        let cmp_t = self.ctx.custom_width_int_type(idx_w.max(len_w));
+
+        // non-negative here
+        let idx_ext = builder.build_int_z_extend_or_bit_cast(idx, cmp_t, "");
+        let len_ext = builder.build_int_z_extend_or_bit_cast(len, cmp_t, "");
+        let in_bounds = builder.build_int_compare(IntPredicate::ULT, idx_ext, len_ext, "in_bounds");
+        builder.build_conditional_branch(in_bounds, blk_ok, blk_oob);
+
+        builder.position_at_end(blk_oob);
+        let loc = match loc {
+            Some(loc) => builder
+                .build_global_string_ptr(loc, "bounds.loc")
+                .as_pointer_value(),
+            None => self.tys.i8ptr_t.const_null(),
+        };
+        let idx = self.build_size_cast(builder, idx, signed);
+        let len = self.build_size_cast(builder, len, false);
+        builder.build_call(fn_fail, &[idx.into(), len.into(), loc.into(), neg.into()], "");
+        builder.build_unreachable();
+
+        builder.position_at_end(blk_ok);
+    }
+
     //////////////////////////////////////////////////////////////////////
     //// Convenient Const
     //////////////////////////////////////////////////////////////////////
@@ -259,9 +846,7 @@ impl<'ctx> VMMod<'ctx> {
     }
 
     pub fn u8(&self, value: u8) -> IntValue<'ctx> {
-        load_vm_common_ty!(get_ctx());
-
-        i8_t.const_int(value as u64, false)
+        self.tys.i8_t.const_int(value as u64, false)
     }
 
     pub fn bool(&self, value: bool) -> IntValue<'ctx> {
@@ -274,27 +859,20 @@ impl<'ctx> VMMod<'ctx> {
     }
 
     pub fn i32(&self, value: i32) -> IntValue<'ctx> {
-        load_vm_common_ty!(get_ctx());
-
-        i32_t.const_int(value as u64, false)
+        self.tys.i32_t.const_int(value as u64, false)
     }
 
-    // size_t
     pub fn usize(&self, value: usize) -> IntValue<'ctx> {
-        load_vm_common_ty!(get_ctx());
-
-        size_t.const_int(value as u64, false)
+        self.tys.size_t.const_int(value as u64, false)
     }
 
     pub fn f64(&self, value: f64) -> FloatValue<'ctx> {
-        load_vm_common_ty!(get_ctx());
-
-        f64_t.const_float(value)
+        self.tys.f64_t.const_float(value)
     }
 
     /// c raw char*
     pub fn str(&self, value: &str) -> VectorValue<'ctx> {
-        get_ctx().const_string(value.as_bytes(), true)
+        self.ctx.const_string(value.as_bytes(), true)
     }
 
     //////////////////////////////////////////////////////////////////////
@@ -320,6 +898,214 @@ impl<'ctx> VMMod<'ctx> {
     }
 }
 
+/// Get named struct type or create it (opaque if `fields` is None).
+///
+/// Error if it has been defined with other fields.
+pub fn get_or_create_struct_type<'ctx>(
+    ctx: &'ctx Context,
+    module: &Module<'ctx>,
+    name: &str,
+    fields: Option<&[BasicTypeEnum<'ctx>]>,
+) -> CompileResult<StructType<'ctx>> {
+    let struct_t = module
+        .get_struct_type(name)
+        .unwrap_or_else(|| ctx.opaque_struct_type(name));
+
+    if let Some(fields) = fields {
+        set_struct_body(struct_t, name, fields, false)?;
+    }
+
+    Ok(struct_t)
+}
+
+/// Set body of opaque `struct_t`, or check that it's already the same.
+pub fn set_struct_body<'ctx>(
+    struct_t: StructType<'ctx>,
+    name: &str,
+    fields: &[BasicTypeEnum<'ctx>],
+    packed: bool,
+) -> CompileResult<()> {
+    if struct_t.is_opaque() {
+        struct_t.set_body(fields, packed);
+    }
+    else if struct_t.get_field_types() != fields || struct_t.is_packed() != packed {
+        return Err(format!("struct {} redefined with different fields", name).into());
+    }
+
+    Ok(())
+}
+
+/// `llvm.<op>.with.overflow.iN(a, b) -> { iN, i1 }` of `int_t`, `op` is e.g. `"umul"`
+fn get_or_declare_overflow_op<'ctx>(module: &Module<'ctx>, op: &str, int_t: IntType<'ctx>) -> FunctionValue<'ctx> {
+    let name = format!("llvm.{}.with.overflow.i{}", op, int_t.get_bit_width());
+
+    module.get_function(&name).unwrap_or_else(|| {
+        let_module_ctx!(ctx = module);
+        let res_t = ctx.struct_type(&[int_t.into(), ctx.bool_type().into()], false);
+        let fn_t = res_t.fn_type(&[int_t.into(), int_t.into()], false);
+
+        module.add_function(&name, fn_t, None)
+    })
+}
+
+/// Abort in `<name>.fail` if `cond`, continue in `<name>.ok`
+pub(crate) fn build_abort_if<'ctx>(
+    module: &Module<'ctx>,
+    builder: &Builder<'ctx>,
+    cond: IntValue<'ctx>,
+    name: &str,
+) {
+    let_module_ctx!(ctx = module);
+    VMMod::include_stdlib(module);
+    let fn_abort = module.get_function("abort").unwrap();
+    let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+
+    let blk_fail = ctx.append_basic_block(fn_val, &format!("{}.fail", name));
+    let blk_ok = ctx.append_basic_block(fn_val, &format!("{}.ok", name));
+    builder.build_conditional_branch(cond, blk_fail, blk_ok);
+
+    builder.position_at_end(blk_fail);
+    builder.build_call(fn_abort, &[], "");
+    builder.build_unreachable();
+
+    builder.position_at_end(blk_ok);
+}
+
+/// Abort if `ptr` is null, e.g. `malloc` is out of memory
+pub(crate) fn build_abort_if_null<'ctx>(module: &Module<'ctx>, builder: &Builder<'ctx>, ptr: PointerValue<'ctx>) {
+    let is_null = builder.build_is_null(ptr, "");
+
+    build_abort_if(module, builder, is_null, "oom");
+}
+
+/// `a + b` of unsigned, abort if it overflows
+pub(crate) fn build_checked_add<'ctx>(
+    module: &Module<'ctx>,
+    builder: &Builder<'ctx>,
+    a: IntValue<'ctx>,
+    b: IntValue<'ctx>,
+) -> IntValue<'ctx> {
+    build_checked_op(module, builder, "uadd", a, b)
+}
+
+/// `a * b` of unsigned, abort if it overflows
+pub(crate) fn build_checked_mul<'ctx>(
+    module: &Module<'ctx>,
+    builder: &Builder<'ctx>,
+    a: IntValue<'ctx>,
+    b: IntValue<'ctx>,
+) -> IntValue<'ctx> {
+    build_checked_op(module, builder, "umul", a, b)
+}
+
+fn build_checked_op<'ctx>(
+    module: &Module<'ctx>,
+    builder: &Builder<'ctx>,
+    op: &str,
+    a: IntValue<'ctx>,
+    b: IntValue<'ctx>,
+) -> IntValue<'ctx> {
+    let fn_op = get_or_declare_overflow_op(module, op, a.get_type());
+
+    let res = ret_as_bv!(builder.build_call(fn_op, &[a.into(), b.into()], "")).into_struct_value();
+    let overflow = builder.build_extract_value(res, 1, "").unwrap().into_int_value();
+
+    build_abort_if(module, builder, overflow, op);
+    builder.build_extract_value(res, 0, "").unwrap().into_int_value()
+}
+
+/// `bounds.fail(idx, len, loc*, neg)`: print to stderr and abort, `loc` may be null.
+///
+/// `idx` is printed as signed if `neg`.
+fn get_or_gen_bounds_fail<'ctx>(module: &Module<'ctx>) -> FunctionValue<'ctx> {
+    if let Some(fn_val) = module.get_function("bounds.fail") {
+        return fn_val;
+    }
+
+    let_module_ctx!(ctx = module);
+    let tys = CommonTypes::get(ctx);
+
+    VMMod::include_stdio(module);
+    VMMod::include_stdlib(module);
+    let fn_dprintf = module.get_function("dprintf").unwrap();
+    let fn_abort = module.get_function("abort").unwrap();
+
+    let fn_t = tys.void_t.fn_type(
+        &[tys.size_t.into(), tys.size_t.into(), tys.i8ptr_t.into(), tys.i1_t.into()],
+        false,
+    );
+    let fn_val = module.add_function("bounds.fail", fn_t, Some(Linkage::Internal));
+
+    // keep the failure path out of the hot code
+    for attr in ["cold", "noinline", "noreturn", "nounwind"] {
+        fn_val.add_attribute(
+            AttributeLoc::Function,
+            ctx.create_enum_attribute(Attribute::get_named_enum_kind_id(attr), 0),
+        );
+    }
+
+    let builder = ctx.create_builder();
+    builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+    let idx = fn_val.get_nth_param(0).unwrap();
+    let len = fn_val.get_nth_param(1).unwrap();
+    let loc = fn_val.get_nth_param(2).unwrap().into_pointer_value();
+    let neg = fn_val.get_nth_param(3).unwrap().into_int_value();
+
+    let has_loc = builder.build_is_not_null(loc, "has_loc");
+    let empty = builder.build_global_string_ptr("", "bounds.empty").as_pointer_value();
+    let sep = builder.build_global_string_ptr(": ", "bounds.sep").as_pointer_value();
+    let loc = builder.build_select(has_loc, loc, empty, "");
+    let sep = builder.build_select(has_loc, sep, empty, "");
+
+    let fmt = builder
+        .build_global_string_ptr("%s%sindex %zu out of bounds for length %zu\n", "bounds.fmt")
+        .as_pointer_value();
+    let fmt_neg = builder
+        .build_global_string_ptr("%s%sindex %zd out of bounds for length %zu\n", "bounds.fmt.neg")
+        .as_pointer_value();
+    let fmt = builder.build_select(neg, fmt_neg, fmt, "");
+    builder.build_call(
+        fn_dprintf,
+        &[
+            tys.i32_t.const_int(2, false).into(),
+            fmt.into(),
+            loc.into(),
+            sep.into(),
+            idx.into(),
+            len.into(),
+        ],
+        "",
+    );
+    builder.build_call(fn_abort, &[], "");
+    builder.build_unreachable();
+
+    fn_val
+}
+
+/// Error if some block of `fn_val` has no terminator (some path doesn't return)
+/// or the verifier rejects it.
+pub fn check_fn<'ctx>(fn_val: FunctionValue<'ctx>) -> CompileResult<()> {
+    let fn_name = fn_val.get_name().to_string_lossy();
+
+    for blk in fn_val.get_basic_blocks() {
+        if blk.get_terminator().is_none() {
+            return Err(format!(
+                "fn {}: block {} isn't terminated",
+                fn_name,
+                blk.get_name().to_string_lossy()
+            )
+            .into());
+        }
+    }
+
+    if !fn_val.verify(true) {
+        return Err(format!("fn {}: rejected by the verifier (details on stderr)", fn_name).into());
+    }
+
+    Ok(())
+}
+
 pub fn builder_position_at_start<'ctx>(builder: &Builder<'ctx>, entry: BasicBlock<'ctx>) {
     match entry.get_first_instruction() {
         Some(first_instr) => builder.position_before(&first_instr),
@@ -327,6 +1113,18 @@ pub fn builder_position_at_start<'ctx>(builder: &Builder<'ctx>, entry: BasicBloc
     }
 }
 
+/// Bind `$ctx: &'ctx Context` of `$module` (which may be a scoped one).
+///
+/// The reference points into a `ContextRef` kept as a hidden local of the caller,
+/// so `$ctx` mustn't outlive the enclosing block (types and values made from it may).
+#[macro_export]
+macro_rules! let_module_ctx {
+    ($ctx: ident = $module: expr) => {
+        let __module_ctx = $module.get_context();
+        let $ctx = unsafe { __module_ctx.get() };
+    };
+}
+
 #[macro_export]
 macro_rules! ret_as_bv {
     ($ret: expr) => {{
@@ -336,4 +1134,412 @@ macro_rules! ret_as_bv {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use inkwell::{
+        attributes::{Attribute, AttributeLoc},
+        builder::Builder,
+        context::Context,
+        execution_engine::ExecutionEngine,
+        module::Linkage,
+        targets::{InitializationConfig, Target},
+        types::{BasicTypeEnum, FunctionType},
+        values::FunctionValue,
+        AddressSpace, OptimizationLevel,
+    };
+
+    use std::{env, fs, path::PathBuf, process};
+
+    use crate::{
+        common::{cache_key, is_cached},
+        compiler::host_target_data,
+        config::{CompilerConfig, EmitType, OptLv, PrintTy, TargetType},
+        get_or_create_struct_type, impl_fn, impl_fn_hdr, with_scoped_ctx, ScopedCtx, VMMod,
+    };
+
+    /// Empty `inkwellkit-<name>-<pid>` in the temporary directory (not created)
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("inkwellkit-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    /// Config of nothing but `target_type` and `emit_type`
+    pub(crate) fn test_config(target_type: TargetType, emit_type: EmitType) -> CompilerConfig {
+        CompilerConfig {
+            optlv: OptLv::Debug,
+            target_type,
+            emit_type,
+            print_type: PrintTy::StdErr,
+            cache: None,
+            lto: false,
+            exports: vec![],
+            version_node: None,
+            c_header: None,
+            bounds_check: true,
+            runtime_lib: None,
+        }
+    }
+
+    /// External `name` for the test to call, builder is positioned at its entry.
+    pub(crate) fn test_fn<'ctx>(
+        vmmod: &VMMod<'ctx>,
+        name: &str,
+        fn_t: FunctionType<'ctx>,
+    ) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+        let fn_val = vmmod.module.add_function(name, fn_t, None);
+        let builder = vmmod.builder();
+        builder.position_at_end(vmmod.ctx.append_basic_block(fn_val, "entry"));
+
+        (fn_val, builder)
+    }
+
+    /// Verify the module then JIT it, libc comes from the test process.
+    pub(crate) fn jit<'ctx>(vmmod: &VMMod<'ctx>) -> ExecutionEngine<'ctx> {
+        if let Err(err) = vmmod.module.verify() {
+            vmmod.module.print_to_stderr();
+            panic!("{}", err.to_string());
+        }
+
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        vmmod
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_heap() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_heap");
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+        let i64ptr_t = tys.i64_t.ptr_type(AddressSpace::Generic);
+
+        // (src*) -> digits of the copy after zeroing the first and moving the last
+        let (fn_val, builder) = test_fn(&vmmod, "test_heap", tys.i64_t.fn_type(&[i64ptr_t.into()], false));
+        let src = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+
+        let dst = vmmod.build_array_malloc(&builder, &target_data, tys.i64_t.into(), vmmod.i32(4), "dst");
+        vmmod.build_copy_elems(&builder, &target_data, dst, src, vmmod.usize(4)).unwrap();
+        vmmod.build_zero_elems(&builder, &target_data, dst, tys.i8_t.const_int(1, false)).unwrap();
+
+        let elem = |i: usize| unsafe { builder.build_in_bounds_gep(dst, &[vmmod.usize(i)], "") };
+        vmmod.build_memmove(&builder, elem(1), elem(2), vmmod.usize(16), 8).unwrap();
+
+        let mut res = tys.i64_t.const_zero();
+        for i in 0..4 {
+            let digit = builder.build_load(elem(i), "").into_int_value();
+            let scale = tys.i64_t.const_int(10u64.pow(i as u32), false);
+            res = builder.build_int_add(res, builder.build_int_mul(digit, scale, ""), "");
+        }
+
+        let boxed = vmmod.build_malloc(&builder, &target_data, tys.i64_t.into(), "boxed");
+        builder.build_store(boxed, res);
+        let res = builder.build_load(boxed, "");
+        vmmod.build_free(&builder, boxed);
+        vmmod.build_free(&builder, dst);
+
+        // malloc(0) may give null, it doesn't abort
+        let empty = vmmod.build_array_malloc(&builder, &target_data, tys.i64_t.into(), vmmod.usize(0), "empty");
+        vmmod.build_free(&builder, empty);
+        builder.build_return(Some(&res));
+
+        assert!(fn_val.get_basic_blocks().iter().any(|blk| blk.get_name().to_str() == Ok("oom.fail")));
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn(*const i64) -> i64>("test_heap") }.unwrap();
+
+        // [1, 2, 3, 4] => [0, 2, 3, 4] => [0, 3, 4, 4]
+        assert_eq!(unsafe { f.call([1, 2, 3, 4].as_ptr()) }, 4430);
+    }
+
+    #[test]
+    fn test_checked_access() {
+        let ctx = Context::create();
+        let mut vmmod = VMMod::new_in(&ctx, "test_checked_access");
+        let tys = vmmod.tys;
+
+        for (name, bounds_check) in [("test_checked", true), ("test_unchecked", false)] {
+            vmmod.bounds_check = bounds_check;
+
+            // (bytes*) -> sum of loads, bytes[i] == i
+            let (fn_val, builder) = test_fn(&vmmod, name, tys.i64_t.fn_type(&[tys.i8ptr_t.into()], false));
+            let bytes = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+            let len = vmmod.i32(256);
+
+            let mut sum = tys.i64_t.const_zero();
+            for (idx, signed) in [
+                // unsigned indices mustn't be sign extended
+                (tys.i8_t.const_int(200, false), false),
+                (tys.i8_t.const_int(5, false), true),
+                (tys.i128_t.const_int(255, false), false),
+                (tys.i128_t.const_int(254, false), true),
+            ] {
+                let byte = vmmod.build_checked_load(&builder, bytes, len, idx, signed, Some("test:1:1"));
+                let byte = builder.build_int_z_extend(byte.into_int_value(), tys.i64_t, "");
+                sum = builder.build_int_add(sum, byte, "");
+            }
+
+            vmmod.build_checked_store(&builder, bytes, len, vmmod.i32(7), true, vmmod.u8(70), None);
+            builder.build_return(Some(&sum));
+        }
+
+        // failure paths are verified, but they abort the process if taken
+        assert!(vmmod.module.get_function("bounds.fail").is_some());
+        let ee = jit(&vmmod);
+
+        for name in ["test_checked", "test_unchecked"] {
+            let f = unsafe { ee.get_function::<unsafe extern "C" fn(*mut u8) -> i64>(name) }.unwrap();
+            let mut bytes = (0..=255u8).collect::<Vec<_>>();
+
+            assert_eq!(unsafe { f.call(bytes.as_mut_ptr()) }, 200 + 5 + 255 + 254, "{}", name);
+            assert_eq!(bytes[7], 70);
+        }
+    }
+
+    #[test]
+    fn test_impl_fn() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_impl_fn");
+        let module = &vmmod.module;
+
+        impl_fn! { module |
+            add3(a: i64, b: i64, c: i64) -> i64 {
+                let ab = builder.build_int_add(a, b, "");
+                // the value of the body is discarded
+                builder.build_return(Some(&builder.build_int_add(ab, c, "")))
+            }
+
+            #[nounwind]
+            store_sum(p: *i64, v: i64) {
+                let sum = builder.build_call(add3, &[v.into(), v.into(), v.into()], "");
+                builder.build_store(p, sum.try_as_basic_value().left().unwrap());
+                builder.build_return(None);
+            }
+        }
+        .unwrap();
+
+        let err = impl_fn! { module |
+            unterminated(x: i32) -> i32 {}
+        }
+        .unwrap_err();
+        assert!(err.to_string().contains("fn unterminated: block entry isn't terminated"), "{}", err);
+        unsafe { module.get_function("unterminated").unwrap().delete() };
+
+        let ee = jit(&vmmod);
+        let add3 = unsafe { ee.get_function::<unsafe extern "C" fn(i64, i64, i64) -> i64>("add3") }.unwrap();
+        let store_sum = unsafe { ee.get_function::<unsafe extern "C" fn(*mut i64, i64)>("store_sum") }.unwrap();
+
+        let mut out = 0;
+        unsafe {
+            assert_eq!(add3.call(1, 20, 300), 321);
+            store_sum.call(&mut out, 7);
+        }
+        assert_eq!(out, 21);
+    }
+
+    /// (name, number of params, returns a value) of `extern "C" fn rt_*` in the runtime source
+    fn runtime_signatures() -> Vec<(String, usize, bool)> {
+        let src = include_str!("../runtime/src/lib.rs");
+        let mut sigs = vec![];
+
+        for (i, _) in src.match_indices("extern \"C\" fn rt_") {
+            let rest = &src[i + "extern \"C\" fn ".len()..];
+            let (name, rest) = rest.split_at(rest.find('(').unwrap());
+            let (params, rest) = rest[1..].split_at(rest[1..].find(')').unwrap());
+            let ret = rest[1..rest.find('{').unwrap()].trim();
+
+            let nparams = params.split(',').filter(|param| !param.trim().is_empty()).count();
+            sigs.push((name.to_owned(), nparams, !ret.is_empty() && ret != "-> !"));
+        }
+
+        sigs.sort();
+        sigs
+    }
+
+    #[test]
+    fn test_include_runtime_in_sync() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_include_runtime_in_sync");
+        VMMod::include_runtime(&vmmod.module);
+
+        let mut decls = vec![];
+        let mut fn_cur = vmmod.module.get_first_function();
+        while let Some(f) = fn_cur {
+            let name = f.get_name().to_string_lossy().into_owned();
+
+            if name.starts_with("rt_") {
+                decls.push((name, f.count_params() as usize, f.get_type().get_return_type().is_some()));
+            }
+            fn_cur = f.get_next_function();
+        }
+        decls.sort();
+
+        assert!(!decls.is_empty());
+        assert_eq!(decls, runtime_signatures());
+    }
+
+    #[test]
+    fn test_scoped_ctx() {
+        let mut a = ScopedCtx::new();
+        let b = ScopedCtx::new();
+        let (key_a, key_b) = (cache_key(a.ctx()), cache_key(b.ctx()));
+        assert!(is_cached(key_a) && is_cached(key_b));
+
+        {
+            // same names, different bodies
+            let vmmod_a = a.create_vmmod("m");
+            let vmmod_b = b.create_vmmod("m");
+            let pair_a = vmmod_a
+                .define_struct("pair", &[vmmod_a.tys.i8_t.into(), vmmod_a.tys.i64_t.into()], false)
+                .unwrap();
+            let pair_b = vmmod_b.define_struct("pair", &[vmmod_b.tys.i32_t.into()], false).unwrap();
+
+            assert_eq!(pair_a.count_fields(), 2);
+            assert_eq!(pair_b.count_fields(), 1);
+            assert_eq!(vmmod_b.get_struct("pair"), Some(pair_b));
+            assert!(vmmod_a.tys.i8_t != vmmod_b.tys.i8_t);
+            assert!(vmmod_a.tys.i8_t == a.ctx().i8_type());
+        }
+
+        a.reset();
+        assert!(a.create_vmmod("m").get_struct("pair").is_none());
+        assert!(!is_cached(key_a));
+        assert!(is_cached(cache_key(a.ctx())));
+        assert!(b.create_vmmod("m2").get_struct("pair").is_some());
+
+        drop(b);
+        assert!(!is_cached(key_b));
+
+        let key = with_scoped_ctx(|ctx| {
+            let vmmod = VMMod::new_in(ctx, "m");
+            vmmod.define_struct("pair", &[vmmod.tys.f64_t.into()], false).unwrap();
+
+            assert!(is_cached(cache_key(ctx)));
+            cache_key(ctx)
+        });
+        assert!(!is_cached(key));
+    }
+
+    #[test]
+    fn test_named_structs() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_named_structs");
+        let tys = vmmod.tys;
+        let fields: [BasicTypeEnum; 2] = [tys.i32_t.into(), tys.i8ptr_t.into()];
+
+        assert!(vmmod.get_struct("node").is_none());
+
+        // declare, then define
+        let node_t = vmmod.declare_struct("node");
+        assert!(node_t.is_opaque());
+        assert_eq!(vmmod.get_struct("node"), Some(node_t));
+        assert_eq!(vmmod.declare_struct("node"), node_t);
+
+        assert_eq!(vmmod.define_struct("node", &fields, false).unwrap(), node_t);
+        assert_eq!(node_t.get_field_types(), fields);
+        assert_eq!(vmmod.define_struct("node", &fields, false).unwrap(), node_t);
+
+        // redefinition with another body
+        for (fields, packed) in [(&fields[..1], false), (&fields[..], true)] {
+            let err = vmmod.define_struct("node", fields, packed).unwrap_err();
+            assert_eq!(err.to_string(), "struct node redefined with different fields");
+        }
+        assert!(get_or_create_struct_type(&ctx, &vmmod.module, "node", Some(&[tys.i64_t.into()])).is_err());
+        assert_eq!(get_or_create_struct_type(&ctx, &vmmod.module, "node", Some(&fields)).unwrap(), node_t);
+        assert_eq!(node_t.get_field_types(), fields);
+    }
+
+    #[test]
+    fn test_impl_fn_hdr() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_impl_fn_hdr");
+        let module = &vmmod.module;
+        let tys = vmmod.tys;
+        let g = AddressSpace::Generic;
+
+        impl_fn_hdr![ module |
+            take_pair(struct hdr_pair { i32, *u8 }, *struct hdr_pair) -> struct hdr_pair;
+            sum_arr(*[i64; 4], [[u8; 3]; 2]) -> i64;
+            set_cb(fn(i32, *i8, ...) -> i32, *fn(f64)) -> fn() -> bool;
+        ];
+
+        let pair_t = module.get_struct_type("hdr_pair").unwrap();
+        assert_eq!(pair_t.get_field_types(), [tys.i32_t.into(), tys.i8ptr_t.into()]);
+        assert_eq!(
+            take_pair.get_type(),
+            pair_t.fn_type(&[pair_t.into(), pair_t.ptr_type(g).into()], false)
+        );
+
+        assert_eq!(
+            sum_arr.get_type(),
+            tys.i64_t.fn_type(
+                &[tys.i64_t.array_type(4).ptr_type(g).into(), tys.i8_t.array_type(3).array_type(2).into()],
+                false
+            )
+        );
+
+        let cb_t = tys.i32_t.fn_type(&[tys.i32_t.into(), tys.i8ptr_t.into()], true);
+        let f64_cb_t = tys.void_t.fn_type(&[tys.f64_t.into()], false);
+        let ret_t = tys.i1_t.fn_type(&[], false).ptr_type(g);
+        assert_eq!(
+            set_cb.get_type(),
+            ret_t.fn_type(&[cb_t.ptr_type(g).into(), f64_cb_t.ptr_type(g).ptr_type(g).into()], false)
+        );
+
+        // declared once, the existing ones are reused
+        impl_fn_hdr![ module |
+            sum_arr(*[i64; 4], [[u8; 3]; 2]) -> i64;
+        ];
+        assert_eq!(sum_arr, module.get_function("sum_arr").unwrap());
+        assert!(module.get_function("sum_arr.1").is_none());
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_fn_anno() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_fn_anno");
+        let module = &vmmod.module;
+
+        impl_fn_hdr![ module |
+            #[noreturn, nounwind, cc = fast] die();
+            #[readonly, ret(noalias, nonnull), linkage = extern_weak]
+            #[link_name = "real_lookup"]
+            lookup(*i8) -> *i8;
+            #[cc = coldcc] narrow(u8, i16, i32) -> u16;
+        ];
+
+        let has_attr = |fn_val: FunctionValue, loc: AttributeLoc, name: &str| {
+            fn_val
+                .get_enum_attribute(loc, Attribute::get_named_enum_kind_id(name))
+                .is_some()
+        };
+
+        assert_eq!(die.get_name().to_str(), Ok("die"));
+        assert!(has_attr(die, AttributeLoc::Function, "noreturn"));
+        assert!(has_attr(die, AttributeLoc::Function, "nounwind"));
+        assert!(!has_attr(die, AttributeLoc::Function, "readonly"));
+        assert_eq!(die.get_call_conventions(), 8);
+        assert_eq!(die.get_linkage(), Linkage::External);
+
+        // bound as `lookup`, the symbol is the link name
+        assert_eq!(lookup.get_name().to_str(), Ok("real_lookup"));
+        assert!(module.get_function("lookup").is_none());
+        assert!(has_attr(lookup, AttributeLoc::Function, "readonly"));
+        assert!(has_attr(lookup, AttributeLoc::Return, "noalias"));
+        assert!(has_attr(lookup, AttributeLoc::Return, "nonnull"));
+        assert_eq!(lookup.get_call_conventions(), 0);
+        assert_eq!(lookup.get_linkage(), Linkage::ExternalWeak);
+
+        // integers narrower than `int` are extended by their signedness
+        assert_eq!(narrow.get_call_conventions(), 9);
+        assert!(has_attr(narrow, AttributeLoc::Param(0), "zeroext"));
+        assert!(has_attr(narrow, AttributeLoc::Param(1), "signext"));
+        assert!(!has_attr(narrow, AttributeLoc::Param(2), "signext"));
+        assert!(has_attr(narrow, AttributeLoc::Return, "zeroext"));
+
+        assert!(module.verify().is_ok());
+    }
+}
@@ -0,0 +1,322 @@
+//! Reference-counted boxes generated in IR
+//!
+//! `%rc.<name> = { size_t count, payload }` lives on heap with the count inline,
+//! `%arc.<name>` is the same but counted atomically for threaded programs.
+//! The helpers are internal functions emitted once per module:
+//!
+//! - `new(payload) -> box*`, count starts at 1, abort if out of memory
+//! - `retain(box*)`
+//! - `release(box*)`, at zero call the drop function (if any) on the payload, then free the box
+
+use inkwell::{
+    builder::Builder,
+    module::{Linkage, Module},
+    types::{BasicType, BasicTypeEnum, FunctionType, PointerType, StructType},
+    values::{BasicMetadataValueEnum, BasicValue, BasicValueEnum, FunctionValue, PointerValue},
+    AddressSpace, AtomicOrdering, AtomicRMWBinOp, IntPredicate,
+};
+
+use crate::{build_abort_if_null, get_or_create_struct_type, let_module_ctx, ret_as_bv, CommonTypes, VMMod};
+
+
+pub struct RcBox<'ctx> {
+    pub payload_t: BasicTypeEnum<'ctx>,
+    pub box_t: StructType<'ctx>,
+    /// `void(payload*)`
+    pub drop_fn: Option<FunctionValue<'ctx>>,
+    pub atomic: bool,
+    prefix: String,
+}
+
+impl<'ctx> RcBox<'ctx> {
+    /// Define box `name` of `payload_t` in `module`, it's fine to define it again,
+    /// but panic if the box of `name` has another payload type.
+    ///
+    /// `drop_fn` of `void(payload*)` releases what the payload owns, it mustn't free the payload.
+    pub fn define(
+        module: &Module<'ctx>,
+        name: &str,
+        payload_t: BasicTypeEnum<'ctx>,
+        drop_fn: Option<FunctionValue<'ctx>>,
+        atomic: bool,
+    ) -> Self {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+
+        if let Some(drop_fn) = drop_fn {
+            let payload_ptr_t: BasicTypeEnum = payload_t.ptr_type(AddressSpace::Generic).into();
+
+            assert!(
+                drop_fn.get_type().get_param_types() == [payload_ptr_t]
+                    && drop_fn.get_type().get_return_type().is_none(),
+                "drop function of {} should be void({:?})",
+                name,
+                payload_ptr_t
+            );
+        }
+
+        let prefix = format!("{}.{}", if atomic { "arc" } else { "rc" }, name);
+        let box_t = get_or_create_struct_type(
+            ctx,
+            module,
+            &prefix,
+            Some(&[tys.size_t.into(), payload_t]),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let rc = Self {
+            payload_t,
+            box_t,
+            drop_fn,
+            atomic,
+            prefix,
+        };
+
+        if module.get_function(&rc.fn_name("new")).is_none() {
+            rc.gen_fns(module);
+        }
+
+        rc
+    }
+
+    fn fn_name(&self, op: &str) -> String {
+        format!("{}.{}", self.prefix, op)
+    }
+
+    fn box_ptr_t(&self) -> PointerType<'ctx> {
+        self.box_t.ptr_type(AddressSpace::Generic)
+    }
+
+    fn add_fn(
+        &self,
+        module: &Module<'ctx>,
+        op: &str,
+        fn_t: FunctionType<'ctx>,
+    ) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+        let_module_ctx!(ctx = module);
+
+        let fn_val = module.add_function(&self.fn_name(op), fn_t, Some(Linkage::Internal));
+        let builder = ctx.create_builder();
+        builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+        (fn_val, builder)
+    }
+
+    ///////////////////////////////////
+    //// Generate
+
+    fn gen_fns(&self, module: &Module<'ctx>) {
+        let_module_ctx!(ctx = module);
+        let tys = CommonTypes::get(ctx);
+        let one = tys.size_t.const_int(1, false);
+
+        VMMod::include_stdlib(module);
+        let fn_malloc = module.get_function("malloc").unwrap();
+        let fn_free = module.get_function("free").unwrap();
+
+        // new
+        let (fn_new, builder) = self.add_fn(
+            module,
+            "new",
+            self.box_ptr_t().fn_type(&[self.payload_t.into()], false),
+        );
+        let payload = fn_new.get_nth_param(0).unwrap();
+        let size = self.box_t.size_of().unwrap().const_cast(tys.size_t, false);
+        let raw = ret_as_bv!(builder.build_call(fn_malloc, &[size.into()], ""));
+        build_abort_if_null(module, &builder, raw.into_pointer_value());
+        let rc = builder
+            .build_bitcast(raw, self.box_ptr_t(), "rc")
+            .into_pointer_value();
+        builder.build_store(builder.build_struct_gep(rc, 0, "count_ptr").unwrap(), one);
+        builder.build_store(builder.build_struct_gep(rc, 1, "payload_ptr").unwrap(), payload);
+        builder.build_return(Some(&rc));
+
+        // retain
+        let (fn_retain, builder) = self.add_fn(
+            module,
+            "retain",
+            tys.void_t.fn_type(&[self.box_ptr_t().into()], false),
+        );
+        let rc = fn_retain.get_nth_param(0).unwrap().into_pointer_value();
+        let count_ptr = builder.build_struct_gep(rc, 0, "count_ptr").unwrap();
+
+        if self.atomic {
+            // nothing is published by a new reference
+            builder
+                .build_atomicrmw(AtomicRMWBinOp::Add, count_ptr, one, AtomicOrdering::Monotonic)
+                .unwrap();
+        } else {
+            let count = builder.build_load(count_ptr, "count").into_int_value();
+            builder.build_store(count_ptr, builder.build_int_add(count, one, ""));
+        }
+        builder.build_return(None);
+
+        // release
+        let (fn_release, builder) = self.add_fn(
+            module,
+            "release",
+            tys.void_t.fn_type(&[self.box_ptr_t().into()], false),
+        );
+        let rc = fn_release.get_nth_param(0).unwrap().into_pointer_value();
+        let count_ptr = builder.build_struct_gep(rc, 0, "count_ptr").unwrap();
+
+        let blk_drop = ctx.append_basic_block(fn_release, "drop");
+        let blk_done = ctx.append_basic_block(fn_release, "done");
+
+        let old = if self.atomic {
+            // the last one sees all writes of the others before dropping
+            builder
+                .build_atomicrmw(AtomicRMWBinOp::Sub, count_ptr, one, AtomicOrdering::AcquireRelease)
+                .unwrap()
+        } else {
+            let count = builder.build_load(count_ptr, "count").into_int_value();
+            builder.build_store(count_ptr, builder.build_int_sub(count, one, ""));
+            count
+        };
+        let is_last = builder.build_int_compare(IntPredicate::EQ, old, one, "is_last");
+        builder.build_conditional_branch(is_last, blk_drop, blk_done);
+
+        builder.position_at_end(blk_drop);
+        if let Some(drop_fn) = self.drop_fn {
+            let payload_ptr = builder.build_struct_gep(rc, 1, "payload_ptr").unwrap();
+            builder.build_call(drop_fn, &[payload_ptr.into()], "");
+        }
+        let raw = builder.build_bitcast(rc, tys.i8ptr_t, "");
+        builder.build_call(fn_free, &[raw.into()], "");
+        builder.build_unconditional_branch(blk_done);
+
+        builder.position_at_end(blk_done);
+        builder.build_return(None);
+    }
+
+    ///////////////////////////////////
+    //// Call
+
+    fn build_call_op(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        op: &str,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> Option<BasicValueEnum<'ctx>> {
+        let fn_val = module
+            .get_function(&self.fn_name(op))
+            .unwrap_or_else(|| panic!("{} isn't defined in module", self.fn_name(op)));
+
+        builder.build_call(fn_val, args, "").try_as_basic_value().left()
+    }
+
+    pub fn build_new(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        payload: BasicValueEnum<'ctx>,
+    ) -> PointerValue<'ctx> {
+        self.build_call_op(module, builder, "new", &[payload.into()])
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    pub fn build_retain(&self, module: &Module<'ctx>, builder: &Builder<'ctx>, rc: PointerValue<'ctx>) {
+        self.build_call_op(module, builder, "retain", &[rc.into()]);
+    }
+
+    pub fn build_release(&self, module: &Module<'ctx>, builder: &Builder<'ctx>, rc: PointerValue<'ctx>) {
+        self.build_call_op(module, builder, "release", &[rc.into()]);
+    }
+
+    /// `payload*` inside the box, valid while a reference is held
+    pub fn build_payload_ptr(&self, builder: &Builder<'ctx>, rc: PointerValue<'ctx>) -> PointerValue<'ctx> {
+        builder.build_struct_gep(rc, 1, "payload_ptr").unwrap()
+    }
+
+    /// Current count, it may be stale at once for the atomic box.
+    pub fn build_count(&self, builder: &Builder<'ctx>, rc: PointerValue<'ctx>) -> BasicValueEnum<'ctx> {
+        let count_ptr = builder.build_struct_gep(rc, 0, "count_ptr").unwrap();
+        let count = builder.build_load(count_ptr, "count");
+
+        if self.atomic {
+            count
+                .as_instruction_value()
+                .unwrap()
+                .set_atomic_ordering(AtomicOrdering::Monotonic)
+                .unwrap();
+        }
+
+        count
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, module::Linkage, AddressSpace};
+
+    use super::RcBox;
+    use crate::{
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_rc() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_rc");
+        let module = &vmmod.module;
+        let tys = vmmod.tys;
+        let i64ptr_t = tys.i64_t.ptr_type(AddressSpace::Generic);
+
+        // payload points to the counter of drops
+        let drop_fn = module.add_function(
+            "drop_counter",
+            tys.void_t.fn_type(&[i64ptr_t.ptr_type(AddressSpace::Generic).into()], false),
+            Some(Linkage::Internal),
+        );
+        let builder = vmmod.builder();
+        builder.position_at_end(ctx.append_basic_block(drop_fn, "entry"));
+        let payload = drop_fn.get_nth_param(0).unwrap().into_pointer_value();
+        let counter = builder.build_load(payload, "").into_pointer_value();
+        let dropped = builder.build_load(counter, "").into_int_value();
+        builder.build_store(counter, builder.build_int_add(dropped, tys.i64_t.const_int(1, false), ""));
+        builder.build_return(None);
+
+        // (counter*) -> count after retain * 100 + after release * 10 + drops before the last release
+        for (name, atomic) in [("test_rc", false), ("test_arc", true)] {
+            let rc_def = RcBox::define(module, "counter", i64ptr_t.into(), Some(drop_fn), atomic);
+            RcBox::define(module, "counter", i64ptr_t.into(), Some(drop_fn), atomic);
+
+            let (fn_val, builder) = test_fn(&vmmod, name, tys.i64_t.fn_type(&[i64ptr_t.into()], false));
+            let counter = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+
+            let rc = rc_def.build_new(module, &builder, counter.into());
+            rc_def.build_retain(module, &builder, rc);
+            let retained = rc_def.build_count(&builder, rc).into_int_value();
+            rc_def.build_release(module, &builder, rc);
+            let released = rc_def.build_count(&builder, rc).into_int_value();
+
+            let payload = builder.build_load(rc_def.build_payload_ptr(&builder, rc), "").into_pointer_value();
+            let dropped = builder.build_load(payload, "").into_int_value();
+            rc_def.build_release(module, &builder, rc);
+
+            let hundred = tys.size_t.const_int(100, false);
+            let ten = tys.size_t.const_int(10, false);
+            let res = builder.build_int_add(
+                builder.build_int_mul(retained, hundred, ""),
+                builder.build_int_mul(released, ten, ""),
+                "",
+            );
+            let res = builder.build_int_z_extend_or_bit_cast(res, tys.i64_t, "");
+            builder.build_return(Some(&builder.build_int_add(res, dropped, "")));
+        }
+
+        let ee = jit(&vmmod);
+
+        for name in ["test_rc", "test_arc"] {
+            let f = unsafe { ee.get_function::<unsafe extern "C" fn(*mut i64) -> i64>(name) }.unwrap();
+            let mut dropped = 0;
+
+            assert_eq!(unsafe { f.call(&mut dropped) }, 210, "{}", name);
+            assert_eq!(dropped, 1, "{}", name);
+        }
+    }
+}
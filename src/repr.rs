@@ -0,0 +1,309 @@
+//! Rust types mirrored as LLVM types, the runtime of `#[derive(LlvmType)]`
+
+use inkwell::{
+    context::Context,
+    module::Module,
+    types::{BasicType, BasicTypeEnum, StructType},
+    values::{ArrayValue, BasicValueEnum, StructValue},
+};
+
+use crate::{compiler::host_target_data, CommonTypes};
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Field
+
+/// Rust type which has a fixed LLVM counterpart with the same layout
+pub trait LlvmField {
+    fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx>;
+
+    fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx>;
+}
+
+macro_rules! impl_llvm_field_int {
+    ($($ty:ty => $field:ident, $signed:expr);* $(;)?) => {
+        $(
+            impl LlvmField for $ty {
+                fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx> {
+                    CommonTypes::of_module(module).$field.into()
+                }
+
+                fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx> {
+                    CommonTypes::of_module(module)
+                        .$field
+                        .const_int(*self as u64, $signed)
+                        .into()
+                }
+            }
+        )*
+    };
+}
+
+impl_llvm_field_int!(
+    i8 => i8_t, true;
+    u8 => i8_t, false;
+    i16 => i16_t, true;
+    u16 => i16_t, false;
+    i32 => i32_t, true;
+    u32 => i32_t, false;
+    i64 => i64_t, true;
+    u64 => i64_t, false;
+    isize => size_t, true;
+    usize => size_t, false;
+);
+
+macro_rules! impl_llvm_field_i128 {
+    ($($ty:ty),*) => {
+        $(
+            impl LlvmField for $ty {
+                fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx> {
+                    CommonTypes::of_module(module).i128_t.into()
+                }
+
+                fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx> {
+                    let bits = *self as u128;
+
+                    CommonTypes::of_module(module)
+                        .i128_t
+                        .const_int_arbitrary_precision(&[bits as u64, (bits >> 64) as u64])
+                        .into()
+                }
+            }
+        )*
+    };
+}
+
+impl_llvm_field_i128!(i128, u128);
+
+/// Rust `bool` takes a whole byte
+impl LlvmField for bool {
+    fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx> {
+        CommonTypes::of_module(module).i8_t.into()
+    }
+
+    fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx> {
+        CommonTypes::of_module(module)
+            .i8_t
+            .const_int(*self as u64, false)
+            .into()
+    }
+}
+
+macro_rules! impl_llvm_field_float {
+    ($($ty:ty => $field:ident),*) => {
+        $(
+            impl LlvmField for $ty {
+                fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx> {
+                    CommonTypes::of_module(module).$field.into()
+                }
+
+                fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx> {
+                    CommonTypes::of_module(module)
+                        .$field
+                        .const_float(*self as f64)
+                        .into()
+                }
+            }
+        )*
+    };
+}
+
+impl_llvm_field_float!(f32 => f32_t, f64 => f64_t);
+
+/// Pointers are opaque `i8*`, the constant of a non-null one is the host address
+/// (only meaningful for JIT).
+macro_rules! impl_llvm_field_ptr {
+    ($($ptr:ty),*) => {
+        $(
+            impl<T> LlvmField for $ptr {
+                fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx> {
+                    CommonTypes::of_module(module).ptr_t.into()
+                }
+
+                fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx> {
+                    let tys = CommonTypes::of_module(module);
+
+                    if self.is_null() {
+                        tys.ptr_t.const_null().into()
+                    } else {
+                        tys.size_t
+                            .const_int(*self as usize as u64, false)
+                            .const_to_pointer(tys.ptr_t)
+                            .into()
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_llvm_field_ptr!(*const T, *mut T);
+
+impl<T: LlvmField, const N: usize> LlvmField for [T; N] {
+    fn llvm_field_type<'ctx>(module: &Module<'ctx>) -> BasicTypeEnum<'ctx> {
+        T::llvm_field_type(module).array_type(N as u32).into()
+    }
+
+    fn const_field<'ctx>(&self, module: &Module<'ctx>) -> BasicValueEnum<'ctx> {
+        let values = self
+            .iter()
+            .map(|elem| elem.const_field(module))
+            .collect::<Vec<_>>();
+
+        const_array_of(T::llvm_field_type(module), &values).into()
+    }
+}
+
+/// Constant array of `elem_t` from the untyped `values`
+pub fn const_array_of<'ctx>(
+    elem_t: BasicTypeEnum<'ctx>,
+    values: &[BasicValueEnum<'ctx>],
+) -> ArrayValue<'ctx> {
+    macro_rules! collect_into {
+        ($into:ident) => {
+            values.iter().map(|v| v.$into()).collect::<Vec<_>>()
+        };
+    }
+
+    match elem_t {
+        BasicTypeEnum::ArrayType(t) => t.const_array(&collect_into!(into_array_value)),
+        BasicTypeEnum::FloatType(t) => t.const_array(&collect_into!(into_float_value)),
+        BasicTypeEnum::IntType(t) => t.const_array(&collect_into!(into_int_value)),
+        BasicTypeEnum::PointerType(t) => {
+            t.const_array(&collect_into!(into_pointer_value))
+        }
+        BasicTypeEnum::StructType(t) => {
+            t.const_array(&collect_into!(into_struct_value))
+        }
+        BasicTypeEnum::VectorType(t) => {
+            t.const_array(&collect_into!(into_vector_value))
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Struct
+
+/// `#[repr(C)]` struct mirrored as a named LLVM struct type,
+/// implemented by `#[derive(LlvmType)]`.
+///
+/// The derive also adds `IDX_<FIELD>` constants for GEPs
+/// and a test comparing the layout with `TargetData`.
+pub trait LlvmType: LlvmField + Sized {
+    /// Name of the LLVM struct, `#[llvm(name = "..")]` or the Rust name
+    const LLVM_NAME: &'static str;
+
+    const FIELD_NAMES: &'static [&'static str];
+
+    /// Named struct type in `module`, created at the first call
+    fn llvm_type<'ctx>(module: &Module<'ctx>) -> StructType<'ctx>;
+
+    fn const_value<'ctx>(&self, module: &Module<'ctx>) -> StructValue<'ctx>;
+}
+
+/// Compare layout of `T` computed by native `TargetData` with the one from rustc
+pub fn assert_layout<T: LlvmType>(offsets: &[usize], size: usize) {
+    let ctx = Context::create();
+    let module = ctx.create_module(T::LLVM_NAME);
+    let struct_t = T::llvm_type(&module);
+    let target_data = host_target_data().unwrap();
+
+    for (i, (name, offset)) in T::FIELD_NAMES.iter().zip(offsets).enumerate() {
+        assert_eq!(
+            target_data.offset_of_element(&struct_t, i as u32),
+            Some(*offset as u64),
+            "offset of field `{}.{}` mismatched",
+            T::LLVM_NAME,
+            name
+        );
+    }
+
+    assert_eq!(
+        target_data.get_abi_size(&struct_t),
+        size as u64,
+        "size of struct `{}` mismatched",
+        T::LLVM_NAME
+    );
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, targets::{InitializationConfig, Target}, OptimizationLevel};
+
+    use super::LlvmType;
+    use crate::compiler::host_target_data;
+
+    #[derive(crate::LlvmType, Debug, Clone, Copy, PartialEq)]
+    #[repr(C)]
+    struct Inner {
+        a: u8,
+        b: u32,
+    }
+
+    #[derive(crate::LlvmType, Debug, Clone, Copy, PartialEq)]
+    #[repr(C)]
+    #[llvm(name = "outer_t")]
+    struct Outer {
+        flag: bool,
+        inner: Inner,
+        ptr: *const u8,
+        arr: [u16; 3],
+        x: f64,
+    }
+
+    static BYTE: u8 = 42;
+
+    #[test]
+    fn test_derive_layout() {
+        let ctx = Context::create();
+        let module = ctx.create_module("test_derive_layout");
+        let target_data = host_target_data().unwrap();
+
+        let outer_t = Outer::llvm_type(&module);
+        assert_eq!(outer_t.get_name().unwrap().to_str(), Ok("outer_t"));
+        assert_eq!(module.get_struct_type("Inner"), Some(Inner::llvm_type(&module)));
+
+        assert_eq!(target_data.get_abi_size(&outer_t), std::mem::size_of::<Outer>() as u64);
+        assert_eq!(target_data.get_abi_alignment(&outer_t), std::mem::align_of::<Outer>() as u32);
+        for (idx, offset) in [
+            (Outer::IDX_FLAG, std::mem::offset_of!(Outer, flag)),
+            (Outer::IDX_INNER, std::mem::offset_of!(Outer, inner)),
+            (Outer::IDX_PTR, std::mem::offset_of!(Outer, ptr)),
+            (Outer::IDX_ARR, std::mem::offset_of!(Outer, arr)),
+            (Outer::IDX_X, std::mem::offset_of!(Outer, x)),
+        ] {
+            assert_eq!(target_data.offset_of_element(&outer_t, idx), Some(offset as u64));
+        }
+        assert_eq!(
+            target_data.offset_of_element(&Inner::llvm_type(&module), Inner::IDX_B),
+            Some(std::mem::offset_of!(Inner, b) as u64)
+        );
+        assert_eq!(Outer::FIELD_NAMES, ["flag", "inner", "ptr", "arr", "x"]);
+    }
+
+    #[test]
+    fn test_derive_const_value() {
+        let ctx = Context::create();
+        let module = ctx.create_module("test_derive_const_value");
+
+        let value = Outer {
+            flag: true,
+            inner: Inner { a: 7, b: 0xdead_beef },
+            ptr: &BYTE,
+            arr: [1, 2, 65535],
+            x: -2.5,
+        };
+
+        let gv = module.add_global(Outer::llvm_type(&module), None, "outer");
+        gv.set_initializer(&value.const_value(&module));
+        gv.set_constant(true);
+
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let ee = module.create_jit_execution_engine(OptimizationLevel::None).unwrap();
+        let addr = ee.get_global_address("outer").unwrap();
+
+        assert_eq!(unsafe { *(addr as *const Outer) }, value);
+    }
+}
@@ -0,0 +1,381 @@
+//! Fat pointers: `SliceValue` (`elem*`, len) and `StrValue` (bytes of it),
+//! lowered to `{ elem*, len }` struct when stored or passed by value.
+
+use std::ops::Deref;
+
+use inkwell::{
+    basic_block::BasicBlock,
+    builder::Builder,
+    types::{BasicType, BasicTypeEnum, StructType},
+    values::{BasicValueEnum, IntValue, PointerValue, StructValue},
+    AddressSpace, IntPredicate,
+};
+
+use crate::{builder_position_at_start, VMMod};
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Slice
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SliceValue<'ctx> {
+    /// to the first element
+    pub ptr: PointerValue<'ctx>,
+    /// number of elements, `size_t`
+    pub len: IntValue<'ctx>,
+}
+
+impl<'ctx> From<(PointerValue<'ctx>, IntValue<'ctx>)> for SliceValue<'ctx> {
+    fn from((ptr, len): (PointerValue<'ctx>, IntValue<'ctx>)) -> Self {
+        Self { ptr, len }
+    }
+}
+
+/// `{ elem*, size_t }`
+pub fn slice_type<'ctx>(vmmod: &VMMod<'ctx>, elem_t: BasicTypeEnum<'ctx>) -> StructType<'ctx> {
+    vmmod.ctx.struct_type(
+        &[
+            elem_t.ptr_type(AddressSpace::Generic).into(),
+            vmmod.tys.size_t.into(),
+        ],
+        false,
+    )
+}
+
+impl<'ctx> SliceValue<'ctx> {
+    pub fn new(ptr: PointerValue<'ctx>, len: IntValue<'ctx>) -> Self {
+        Self { ptr, len }
+    }
+
+    pub fn elem_type(&self) -> BasicTypeEnum<'ctx> {
+        self.ptr.get_type().get_element_type().try_into().unwrap()
+    }
+
+    /// Lower to `{ elem*, len }`
+    pub fn build_into_struct(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>) -> StructValue<'ctx> {
+        let fat_t = slice_type(vmmod, self.elem_type());
+
+        let fat = builder
+            .build_insert_value(fat_t.get_undef(), self.ptr, 0, "")
+            .unwrap();
+        builder
+            .build_insert_value(fat, self.len, 1, "")
+            .unwrap()
+            .into_struct_value()
+    }
+
+    pub fn build_from_struct(builder: &Builder<'ctx>, fat: StructValue<'ctx>) -> Self {
+        Self {
+            ptr: builder.build_extract_value(fat, 0, "ptr").unwrap().into_pointer_value(),
+            len: builder.build_extract_value(fat, 1, "len").unwrap().into_int_value(),
+        }
+    }
+
+    /// Pointer to the `idx`th element, unchecked
+    pub fn build_index_ptr(&self, builder: &Builder<'ctx>, idx: IntValue<'ctx>) -> PointerValue<'ctx> {
+        unsafe { builder.build_in_bounds_gep(self.ptr, &[idx], "") }
+    }
+
+    /// Load the `idx`th element, unchecked
+    pub fn build_index(&self, builder: &Builder<'ctx>, idx: IntValue<'ctx>) -> BasicValueEnum<'ctx> {
+        builder.build_load(self.build_index_ptr(builder, idx), "")
+    }
+
+    /// `[start, end)`, unchecked
+    pub fn build_subslice(
+        &self,
+        builder: &Builder<'ctx>,
+        start: IntValue<'ctx>,
+        end: IntValue<'ctx>,
+    ) -> Self {
+        Self {
+            ptr: self.build_index_ptr(builder, start),
+            len: builder.build_int_sub(end, start, ""),
+        }
+    }
+
+    /// Bytes of the elements, `size_t`, abort if it overflows
+    pub fn build_byte_len(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>) -> IntValue<'ctx> {
+        let elem_size = self.elem_type().size_of().unwrap();
+        let elem_size = vmmod.build_size_cast(builder, elem_size, false);
+
+        vmmod.build_checked_size(builder, self.len, elem_size)
+    }
+
+    /// Bitwise equality as `i1`
+    pub fn build_eq(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, other: &Self) -> IntValue<'ctx> {
+        let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+
+        let blk_head = builder.get_insert_block().unwrap();
+        let blk_cmp = vmmod.ctx.append_basic_block(fn_val, "eq.cmp");
+        let blk_end = vmmod.ctx.append_basic_block(fn_val, "eq.end");
+
+        let same_len = builder.build_int_compare(IntPredicate::EQ, self.len, other.len, "");
+        builder.build_conditional_branch(same_len, blk_cmp, blk_end);
+
+        builder.position_at_end(blk_cmp);
+        let res = self.build_memcmp(vmmod, builder, other, self.build_byte_len(vmmod, builder));
+        let same_bytes = builder.build_int_compare(IntPredicate::EQ, res, vmmod.tys.i32_t.const_zero(), "");
+        let blk_cmp_end = builder.get_insert_block().unwrap();
+        builder.build_unconditional_branch(blk_end);
+
+        builder.position_at_end(blk_end);
+        let phi = builder.build_phi(vmmod.tys.i1_t, "eq");
+        phi.add_incoming(&[
+            (&vmmod.tys.i1_t.const_zero(), blk_head),
+            (&same_bytes, blk_cmp_end),
+        ]);
+
+        phi.as_basic_value().into_int_value()
+    }
+
+    fn build_memcmp(
+        &self,
+        vmmod: &VMMod<'ctx>,
+        builder: &Builder<'ctx>,
+        other: &Self,
+        bytes: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        VMMod::include_string(&vmmod.module);
+        let fn_memcmp = vmmod.module.get_function("memcmp").unwrap();
+
+        let lhs = builder.build_bitcast(self.ptr, vmmod.tys.i8ptr_t, "");
+        let rhs = builder.build_bitcast(other.ptr, vmmod.tys.i8ptr_t, "");
+
+        builder
+            .build_call(fn_memcmp, &[lhs.into(), rhs.into(), bytes.into()], "")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value()
+    }
+
+    /// `for (idx = 0; idx < len; idx++) body(builder, idx, &self[idx])`
+    ///
+    /// Builder is positioned after the loop at return.
+    pub fn build_for_each<F>(
+        &self,
+        vmmod: &VMMod<'ctx>,
+        builder: &Builder<'ctx>,
+        mut body: F,
+    ) -> BasicBlock<'ctx>
+    where
+        F: FnMut(&Builder<'ctx>, IntValue<'ctx>, PointerValue<'ctx>),
+    {
+        let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+
+        // counter lives in the entry block, so nested loops don't grow stack
+        let entry_builder = vmmod.builder();
+        builder_position_at_start(&entry_builder, fn_val.get_first_basic_block().unwrap());
+        let cnt = entry_builder.build_alloca(vmmod.tys.size_t, "idx");
+        builder.build_store(cnt, vmmod.tys.size_t.const_zero());
+
+        let blk_cond = vmmod.ctx.append_basic_block(fn_val, "for.cond");
+        let blk_body = vmmod.ctx.append_basic_block(fn_val, "for.body");
+        let blk_end = vmmod.ctx.append_basic_block(fn_val, "for.end");
+
+        builder.build_unconditional_branch(blk_cond);
+
+        builder.position_at_end(blk_cond);
+        let idx = vmmod.bload_int(builder, cnt);
+        let in_range = builder.build_int_compare(IntPredicate::ULT, idx, self.len, "");
+        builder.build_conditional_branch(in_range, blk_body, blk_end);
+
+        builder.position_at_end(blk_body);
+        body(builder, idx, self.build_index_ptr(builder, idx));
+
+        if builder.get_insert_block().unwrap().get_terminator().is_none() {
+            let nxt = builder.build_int_add(idx, vmmod.tys.size_t.const_int(1, false), "");
+            builder.build_store(cnt, nxt);
+            builder.build_unconditional_branch(blk_cond);
+        }
+
+        builder.position_at_end(blk_end);
+        blk_end
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Str
+
+/// UTF-8 bytes, not NUL-terminated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrValue<'ctx>(pub SliceValue<'ctx>);
+
+impl<'ctx> From<(PointerValue<'ctx>, IntValue<'ctx>)> for StrValue<'ctx> {
+    fn from(parts: (PointerValue<'ctx>, IntValue<'ctx>)) -> Self {
+        Self(parts.into())
+    }
+}
+
+impl<'ctx> Deref for StrValue<'ctx> {
+    type Target = SliceValue<'ctx>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'ctx> StrValue<'ctx> {
+    pub fn build_subslice(
+        &self,
+        builder: &Builder<'ctx>,
+        start: IntValue<'ctx>,
+        end: IntValue<'ctx>,
+    ) -> Self {
+        Self(self.0.build_subslice(builder, start, end))
+    }
+
+    pub fn build_eq(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, other: &Self) -> IntValue<'ctx> {
+        self.0.build_eq(vmmod, builder, &other.0)
+    }
+
+    /// Lexicographic comparison like `memcmp`, `i32` of negative, zero or positive
+    pub fn build_cmp(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, other: &Self) -> IntValue<'ctx> {
+        let i32_t = vmmod.tys.i32_t;
+
+        let lhs_shorter = builder.build_int_compare(IntPredicate::ULT, self.len, other.len, "");
+        let min_len = builder
+            .build_select(lhs_shorter, self.len, other.len, "")
+            .into_int_value();
+
+        let res = self.0.build_memcmp(vmmod, builder, &other.0, min_len);
+
+        // same prefix, the shorter one is less
+        let lhs_longer = builder.build_int_compare(IntPredicate::UGT, self.len, other.len, "");
+        let len_ord = builder.build_select(
+            lhs_shorter,
+            i32_t.const_int(-1i64 as u64, true),
+            builder.build_int_z_extend(lhs_longer, i32_t, ""),
+            "",
+        );
+        let prefix_eq = builder.build_int_compare(IntPredicate::EQ, res, i32_t.const_zero(), "");
+
+        builder
+            .build_select(prefix_eq, len_ord.into_int_value(), res, "cmp")
+            .into_int_value()
+    }
+
+    /// `write(fd, ptr, len)`
+    pub fn build_write(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, fd: IntValue<'ctx>) {
+        VMMod::include_unistd(&vmmod.module);
+        let fn_write = vmmod.module.get_function("write").unwrap();
+
+        builder.build_call(fn_write, &[fd.into(), self.ptr.into(), self.len.into()], "");
+    }
+
+    /// `printf("%.*s", len, ptr)`, at most `INT_MAX` bytes are printed
+    pub fn build_print(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>) {
+        VMMod::include_stdio(&vmmod.module);
+
+        // precision is `int`, a truncated one could be negative (print all up to NUL)
+        let int_max = vmmod.usize(i32::MAX as usize);
+        let len = vmmod.build_size_cast(builder, self.len, false);
+        let too_long = builder.build_int_compare(IntPredicate::UGT, len, int_max, "");
+        let len = builder.build_select(too_long, int_max, len, "").into_int_value();
+        let len = builder.build_int_truncate_or_bit_cast(len, vmmod.tys.i32_t, "");
+        vmmod.build_call_printf(builder, "%.*s", &[len.into(), self.ptr.into()]);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, AddressSpace, IntPredicate};
+
+    use super::{SliceValue, StrValue};
+    use crate::{
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_slice() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_slice");
+        let tys = vmmod.tys;
+        let i64ptr_t = tys.i64_t.ptr_type(AddressSpace::Generic);
+
+        // (ptr, len) -> sum of [1..] + byte len * 1000, through the struct form
+        let (fn_val, builder) = test_fn(
+            &vmmod,
+            "test_slice_sum",
+            tys.i64_t.fn_type(&[i64ptr_t.into(), tys.size_t.into()], false),
+        );
+        let slice = SliceValue::new(
+            fn_val.get_nth_param(0).unwrap().into_pointer_value(),
+            fn_val.get_nth_param(1).unwrap().into_int_value(),
+        );
+        let fat = slice.build_into_struct(&vmmod, &builder);
+        let slice = SliceValue::build_from_struct(&builder, fat);
+        let tail = slice.build_subslice(&builder, vmmod.usize(1), slice.len);
+
+        let sum = builder.build_alloca(tys.i64_t, "sum");
+        builder.build_store(sum, tys.i64_t.const_zero());
+        tail.build_for_each(&vmmod, &builder, |builder, _, elem| {
+            let acc = builder.build_load(sum, "").into_int_value();
+            let elem = builder.build_load(elem, "").into_int_value();
+            builder.build_store(sum, builder.build_int_add(acc, elem, ""));
+        });
+
+        let byte_len = slice.build_byte_len(&vmmod, &builder);
+        let byte_len = builder.build_int_z_extend_or_bit_cast(byte_len, tys.i64_t, "");
+        let res = builder.build_int_add(
+            builder.build_load(sum, "").into_int_value(),
+            builder.build_int_mul(byte_len, tys.i64_t.const_int(1000, false), ""),
+            "",
+        );
+        builder.build_return(Some(&res));
+
+        // (a, alen, b, blen) -> cmp * 10 + eq
+        let (fn_val, builder) = test_fn(
+            &vmmod,
+            "test_str_cmp",
+            tys.i32_t.fn_type(
+                &[tys.i8ptr_t.into(), tys.size_t.into(), tys.i8ptr_t.into(), tys.size_t.into()],
+                false,
+            ),
+        );
+        let param = |i| fn_val.get_nth_param(i).unwrap();
+        let a = StrValue::from((param(0).into_pointer_value(), param(1).into_int_value()));
+        let b = StrValue::from((param(2).into_pointer_value(), param(3).into_int_value()));
+
+        let eq = builder.build_int_z_extend(a.build_eq(&vmmod, &builder, &b), tys.i32_t, "");
+        let cmp = a.build_cmp(&vmmod, &builder, &b);
+        let cmp_neg = builder.build_int_compare(IntPredicate::SLT, cmp, vmmod.i32(0), "");
+        let cmp_pos = builder.build_int_compare(IntPredicate::SGT, cmp, vmmod.i32(0), "");
+        let sign = builder.build_int_sub(
+            builder.build_int_z_extend(cmp_pos, tys.i32_t, ""),
+            builder.build_int_z_extend(cmp_neg, tys.i32_t, ""),
+            "",
+        );
+        let res = builder.build_int_add(builder.build_int_mul(sign, vmmod.i32(10), ""), eq, "");
+        builder.build_return(Some(&res));
+
+        let ee = jit(&vmmod);
+        let sum = unsafe {
+            ee.get_function::<unsafe extern "C" fn(*const i64, usize) -> i64>("test_slice_sum")
+        }
+        .unwrap();
+        let str_cmp = unsafe {
+            ee.get_function::<unsafe extern "C" fn(*const u8, usize, *const u8, usize) -> i32>(
+                "test_str_cmp",
+            )
+        }
+        .unwrap();
+
+        let values = [100i64, 1, 2, 3];
+        assert_eq!(unsafe { sum.call(values.as_ptr(), 4) }, 6 + 32 * 1000);
+        assert_eq!(unsafe { sum.call(values.as_ptr(), 1) }, 8 * 1000);
+
+        let cmp = |a: &str, b: &str| unsafe { str_cmp.call(a.as_ptr(), a.len(), b.as_ptr(), b.len()) };
+        assert_eq!(cmp("abc", "abc"), 1);
+        assert_eq!(cmp("", ""), 1);
+        assert_eq!(cmp("abc", "abd"), -10);
+        assert_eq!(cmp("abd", "abc"), 10);
+        assert_eq!(cmp("ab", "abc"), -10);
+        assert_eq!(cmp("abc", "ab"), 10);
+        assert_eq!(cmp("b", "abc"), 10);
+    }
+}
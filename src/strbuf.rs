@@ -0,0 +1,818 @@
+//! String building in generated code, and type-checked printf-style formatting
+//!
+//! `%strbuf = { i8* data, size_t len, size_t cap, i1 heap }` keeps the content NUL-terminated,
+//! a buffer on stack moves to heap once it's full, out of memory aborts.
+//! The helpers are internal functions emitted once per module:
+//!
+//! - `strbuf.reserve(strbuf*, extra)`
+//! - `strbuf.push(strbuf*, i8*, len)`
+//! - `strbuf.push_int(strbuf*, i64, base, signed)`
+//! - `strbuf.push_float(strbuf*, double, precision)`
+//! - `strbuf.free(strbuf*)`
+
+use inkwell::{
+    builder::Builder,
+    module::{Linkage, Module},
+    types::{FunctionType, StructType},
+    values::{BasicMetadataValueEnum, FloatValue, FunctionValue, IntValue, PointerValue},
+    AddressSpace, IntPredicate,
+};
+
+use crate::{
+    build_abort_if_null, build_checked_add, builder_position_at_start, compiler::CompileResult, get_or_create_struct_type,
+    let_module_ctx, ret_as_bv,
+    slice::{SliceValue, StrValue},
+    CommonTypes, VMMod,
+};
+
+
+const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// `i64` in base 2 with sign
+const INT_BUF_LEN: u64 = 66;
+
+/// Longer output of `%.*f` is truncated
+const FLOAT_BUF_LEN: u64 = 128;
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Format Check
+
+/// Check `values` against the conversions of printf-style `fmt`
+pub fn check_format<'ctx>(
+    tys: &CommonTypes<'ctx>,
+    fmt: &str,
+    values: &[BasicMetadataValueEnum<'ctx>],
+) -> CompileResult<()> {
+    let is_int = |bits: u32| {
+        move |arg: &BasicMetadataValueEnum<'ctx>| {
+            matches!(arg, BasicMetadataValueEnum::IntValue(v) if v.get_type().get_bit_width() == bits)
+        }
+    };
+    let is_double = |arg: &BasicMetadataValueEnum<'ctx>| {
+        matches!(arg, BasicMetadataValueEnum::FloatValue(v) if v.get_type() == tys.f64_t)
+    };
+    let is_ptr = |arg: &BasicMetadataValueEnum<'ctx>| {
+        matches!(arg, BasicMetadataValueEnum::PointerValue(_))
+    };
+
+    let mut args = values.iter();
+    let mut expect = |what: &str, ok: &dyn Fn(&BasicMetadataValueEnum<'ctx>) -> bool| {
+        match args.next() {
+            Some(arg) if ok(arg) => Ok(()),
+            Some(arg) => Err(format!("format {:?}: expect {}, found {:?}", fmt, what, arg)),
+            None => Err(format!("format {:?}: missing argument of {}", fmt, what)),
+        }
+    };
+
+    let mut chars = fmt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+
+        while matches!(chars.peek(), Some('-' | '+' | ' ' | '#' | '0')) {
+            chars.next();
+        }
+
+        // width, then precision
+        for leading in [None, Some('.')] {
+            if let Some(leading) = leading {
+                if chars.peek() != Some(&leading) {
+                    continue;
+                }
+                chars.next();
+            }
+
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                expect("i32 of `*`", &is_int(32))?;
+            }
+            while matches!(chars.peek(), Some('0'..='9')) {
+                chars.next();
+            }
+        }
+
+        let mut length = String::new();
+        while let Some(&c @ ('h' | 'l' | 'z' | 'j' | 't')) = chars.peek() {
+            length.push(c);
+            chars.next();
+        }
+
+        // integers narrower than int are promoted
+        let int_bits = match length.as_str() {
+            "" | "h" | "hh" => 32,
+            "l" | "ll" | "j" => 64,
+            "z" | "t" => tys.size_t.get_bit_width(),
+            _ => return Err(format!("format {:?}: unknown length `{}`", fmt, length).into()),
+        };
+
+        match chars.next() {
+            Some('%') => (),
+            Some('d' | 'i' | 'u' | 'x' | 'X' | 'o' | 'c') => {
+                expect(&format!("i{}", int_bits), &is_int(int_bits))?
+            }
+            Some('f' | 'F' | 'e' | 'E' | 'g' | 'G' | 'a' | 'A') if length.is_empty() || length == "l" => {
+                expect("double", &is_double)?
+            }
+            Some('s' | 'p') if length.is_empty() => expect("pointer", &is_ptr)?,
+            Some(conv) => {
+                return Err(format!("format {:?}: unsupported `%{}{}`", fmt, length, conv).into())
+            }
+            None => return Err(format!("format {:?}: dangling `%`", fmt).into()),
+        }
+    }
+
+    if args.next().is_some() {
+        return Err(format!("format {:?}: too many arguments", fmt).into());
+    }
+
+    Ok(())
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// Helper Functions
+
+fn strbuf_type<'ctx>(module: &Module<'ctx>) -> StructType<'ctx> {
+    let_module_ctx!(ctx = module);
+    let tys = CommonTypes::get(ctx);
+
+    get_or_create_struct_type(
+        ctx,
+        module,
+        "strbuf",
+        Some(&[
+            tys.i8ptr_t.into(),
+            tys.size_t.into(),
+            tys.size_t.into(),
+            tys.i1_t.into(),
+        ]),
+    )
+    .unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn get_helper<'ctx>(module: &Module<'ctx>, name: &str) -> FunctionValue<'ctx> {
+    if module.get_function("strbuf.reserve").is_none() {
+        gen_helpers(module);
+    }
+
+    module.get_function(name).unwrap()
+}
+
+fn add_helper<'ctx>(
+    module: &Module<'ctx>,
+    name: &str,
+    fn_t: FunctionType<'ctx>,
+) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+    let_module_ctx!(ctx = module);
+
+    let fn_val = module.add_function(name, fn_t, Some(Linkage::Internal));
+    let builder = ctx.create_builder();
+    builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+    (fn_val, builder)
+}
+
+/// (`data` field pointer, `data`)
+fn load_data<'ctx>(
+    builder: &Builder<'ctx>,
+    buf: PointerValue<'ctx>,
+) -> (PointerValue<'ctx>, PointerValue<'ctx>) {
+    let field_ptr = builder.build_struct_gep(buf, 0, "data_ptr").unwrap();
+
+    (field_ptr, builder.build_load(field_ptr, "data").into_pointer_value())
+}
+
+/// (field pointer, field) of `len`, `cap` or `heap`
+fn load_int_field<'ctx>(
+    builder: &Builder<'ctx>,
+    buf: PointerValue<'ctx>,
+    name: &str,
+) -> (PointerValue<'ctx>, IntValue<'ctx>) {
+    let idx = match name {
+        "len" => 1,
+        "cap" => 2,
+        "heap" => 3,
+        _ => unreachable!("{}", name),
+    };
+    let field_ptr = builder.build_struct_gep(buf, idx, "").unwrap();
+
+    (field_ptr, builder.build_load(field_ptr, name).into_int_value())
+}
+
+fn gen_helpers(module: &Module) {
+    let_module_ctx!(ctx = module);
+    let tys = CommonTypes::get(ctx);
+    let buf_ptr_t = strbuf_type(module).ptr_type(AddressSpace::Generic);
+
+    VMMod::include_stdlib(module);
+    VMMod::include_string(module);
+    VMMod::include_stdio(module);
+    let fn_malloc = module.get_function("malloc").unwrap();
+    let fn_realloc = module.get_function("realloc").unwrap();
+    let fn_free = module.get_function("free").unwrap();
+    let fn_memcpy = module.get_function("memcpy").unwrap();
+    let fn_snprintf = module.get_function("snprintf").unwrap();
+
+    let one = tys.size_t.const_int(1, false);
+
+    ///////////////////////////////////
+    //// reserve room of `extra` bytes besides NUL
+
+    let (fn_reserve, builder) = add_helper(
+        module,
+        "strbuf.reserve",
+        tys.void_t.fn_type(&[buf_ptr_t.into(), tys.size_t.into()], false),
+    );
+    let buf = fn_reserve.get_nth_param(0).unwrap().into_pointer_value();
+    let extra = fn_reserve.get_nth_param(1).unwrap().into_int_value();
+
+    let blk_grow = ctx.append_basic_block(fn_reserve, "grow");
+    let blk_realloc = ctx.append_basic_block(fn_reserve, "realloc");
+    let blk_spill = ctx.append_basic_block(fn_reserve, "spill");
+    let blk_done = ctx.append_basic_block(fn_reserve, "done");
+
+    let (data_ptr, data) = load_data(&builder, buf);
+    let (_, len) = load_int_field(&builder, buf, "len");
+    let (cap_ptr, cap) = load_int_field(&builder, buf, "cap");
+    let (heap_ptr, heap) = load_int_field(&builder, buf, "heap");
+
+    let need = build_checked_add(module, &builder, len, extra);
+    let need = build_checked_add(module, &builder, need, one);
+    let is_full = builder.build_int_compare(IntPredicate::UGT, need, cap, "");
+    builder.build_conditional_branch(is_full, blk_grow, blk_done);
+
+    // doubled, or just `need` if it overflows
+    builder.position_at_end(blk_grow);
+    let doubled = builder.build_int_mul(cap, tys.size_t.const_int(2, false), "");
+    let wrapped = builder.build_int_compare(IntPredicate::ULT, doubled, cap, "");
+    let need_more = builder.build_or(
+        wrapped,
+        builder.build_int_compare(IntPredicate::UGT, need, doubled, ""),
+        "",
+    );
+    let new_cap = builder.build_select(need_more, need, doubled, "new_cap");
+    builder.build_store(cap_ptr, new_cap);
+    builder.build_conditional_branch(heap, blk_realloc, blk_spill);
+
+    builder.position_at_end(blk_realloc);
+    let new_data = ret_as_bv!(builder.build_call(fn_realloc, &[data.into(), new_cap.into()], ""));
+    build_abort_if_null(module, &builder, new_data.into_pointer_value());
+    builder.build_store(data_ptr, new_data);
+    builder.build_unconditional_branch(blk_done);
+
+    // buffer on stack moves to heap
+    builder.position_at_end(blk_spill);
+    let new_data = ret_as_bv!(builder.build_call(fn_malloc, &[new_cap.into()], ""));
+    build_abort_if_null(module, &builder, new_data.into_pointer_value());
+    let used = builder.build_int_add(len, one, "");
+    builder.build_call(fn_memcpy, &[new_data.into(), data.into(), used.into()], "");
+    builder.build_store(data_ptr, new_data);
+    builder.build_store(heap_ptr, tys.i1_t.const_int(1, false));
+    builder.build_unconditional_branch(blk_done);
+
+    builder.position_at_end(blk_done);
+    builder.build_return(None);
+
+    ///////////////////////////////////
+    //// push
+
+    let (fn_push, builder) = add_helper(
+        module,
+        "strbuf.push",
+        tys.void_t.fn_type(&[buf_ptr_t.into(), tys.i8ptr_t.into(), tys.size_t.into()], false),
+    );
+    let buf = fn_push.get_nth_param(0).unwrap().into_pointer_value();
+    let src = fn_push.get_nth_param(1).unwrap().into_pointer_value();
+    let n = fn_push.get_nth_param(2).unwrap().into_int_value();
+
+    builder.build_call(fn_reserve, &[buf.into(), n.into()], "");
+    let (_, data) = load_data(&builder, buf);
+    let (len_ptr, len) = load_int_field(&builder, buf, "len");
+
+    let dst = unsafe { builder.build_in_bounds_gep(data, &[len], "dst") };
+    builder.build_call(fn_memcpy, &[dst.into(), src.into(), n.into()], "");
+
+    let len = builder.build_int_add(len, n, "");
+    builder.build_store(len_ptr, len);
+    let end = unsafe { builder.build_in_bounds_gep(data, &[len], "end") };
+    builder.build_store(end, tys.i8_t.const_zero());
+    builder.build_return(None);
+
+    ///////////////////////////////////
+    //// push_int, digits are written backward into a local buffer
+
+    let (fn_push_int, builder) = add_helper(
+        module,
+        "strbuf.push_int",
+        tys.void_t.fn_type(
+            &[buf_ptr_t.into(), tys.i64_t.into(), tys.i64_t.into(), tys.i1_t.into()],
+            false,
+        ),
+    );
+    let buf = fn_push_int.get_nth_param(0).unwrap().into_pointer_value();
+    let value = fn_push_int.get_nth_param(1).unwrap().into_int_value();
+    let base = fn_push_int.get_nth_param(2).unwrap().into_int_value();
+    let signed = fn_push_int.get_nth_param(3).unwrap().into_int_value();
+
+    let digits = ctx.const_string(DIGITS, false);
+    let digits_gv = module.add_global(digits.get_type(), None, "strbuf.digits");
+    digits_gv.set_initializer(&digits);
+    digits_gv.set_constant(true);
+    digits_gv.set_linkage(Linkage::Private);
+    let digits = builder
+        .build_bitcast(digits_gv.as_pointer_value(), tys.i8ptr_t, "digits")
+        .into_pointer_value();
+
+    let int_buf = builder.build_alloca(tys.i8_t.array_type(INT_BUF_LEN as u32), "int_buf");
+    let int_buf = builder
+        .build_bitcast(int_buf, tys.i8ptr_t, "")
+        .into_pointer_value();
+
+    let is_neg = builder.build_and(
+        signed,
+        builder.build_int_compare(IntPredicate::SLT, value, tys.i64_t.const_zero(), ""),
+        "is_neg",
+    );
+    // `-i64::MIN` wraps to itself, which is right as unsigned
+    let magnitude = builder
+        .build_select(is_neg, builder.build_int_neg(value, ""), value, "")
+        .into_int_value();
+
+    let blk_entry = builder.get_insert_block().unwrap();
+    let blk_loop = ctx.append_basic_block(fn_push_int, "loop");
+    let blk_sign = ctx.append_basic_block(fn_push_int, "sign");
+    let blk_minus = ctx.append_basic_block(fn_push_int, "minus");
+    let blk_push = ctx.append_basic_block(fn_push_int, "push");
+
+    let end = tys.i64_t.const_int(INT_BUF_LEN, false);
+    let i64_one = tys.i64_t.const_int(1, false);
+    builder.build_unconditional_branch(blk_loop);
+
+    builder.position_at_end(blk_loop);
+    let pos_phi = builder.build_phi(tys.i64_t, "pos");
+    let rest_phi = builder.build_phi(tys.i64_t, "rest");
+    let pos = pos_phi.as_basic_value().into_int_value();
+    let rest = rest_phi.as_basic_value().into_int_value();
+
+    let digit_idx = builder.build_int_unsigned_rem(rest, base, "");
+    let digit = builder.build_load(unsafe { builder.build_in_bounds_gep(digits, &[digit_idx], "") }, "");
+    let pos_nxt = builder.build_int_sub(pos, i64_one, "");
+    builder.build_store(unsafe { builder.build_in_bounds_gep(int_buf, &[pos_nxt], "") }, digit);
+    let rest_nxt = builder.build_int_unsigned_div(rest, base, "");
+
+    pos_phi.add_incoming(&[(&end, blk_entry), (&pos_nxt, blk_loop)]);
+    rest_phi.add_incoming(&[(&magnitude, blk_entry), (&rest_nxt, blk_loop)]);
+
+    let has_more = builder.build_int_compare(IntPredicate::NE, rest_nxt, tys.i64_t.const_zero(), "");
+    builder.build_conditional_branch(has_more, blk_loop, blk_sign);
+
+    builder.position_at_end(blk_sign);
+    builder.build_conditional_branch(is_neg, blk_minus, blk_push);
+
+    builder.position_at_end(blk_minus);
+    let pos_minus = builder.build_int_sub(pos_nxt, i64_one, "");
+    builder.build_store(
+        unsafe { builder.build_in_bounds_gep(int_buf, &[pos_minus], "") },
+        tys.i8_t.const_int(b'-' as u64, false),
+    );
+    builder.build_unconditional_branch(blk_push);
+
+    builder.position_at_end(blk_push);
+    let start_phi = builder.build_phi(tys.i64_t, "start");
+    start_phi.add_incoming(&[(&pos_nxt, blk_sign), (&pos_minus, blk_minus)]);
+    let start = start_phi.as_basic_value().into_int_value();
+
+    let src = unsafe { builder.build_in_bounds_gep(int_buf, &[start], "") };
+    let n = builder.build_int_cast(builder.build_int_sub(end, start, ""), tys.size_t, "");
+    builder.build_call(fn_push, &[buf.into(), src.into(), n.into()], "");
+    builder.build_return(None);
+
+    ///////////////////////////////////
+    //// push_float
+
+    let (fn_push_float, builder) = add_helper(
+        module,
+        "strbuf.push_float",
+        tys.void_t.fn_type(&[buf_ptr_t.into(), tys.f64_t.into(), tys.i32_t.into()], false),
+    );
+    let buf = fn_push_float.get_nth_param(0).unwrap().into_pointer_value();
+    let value = fn_push_float.get_nth_param(1).unwrap();
+    let precision = fn_push_float.get_nth_param(2).unwrap();
+
+    let float_buf = builder.build_alloca(tys.i8_t.array_type(FLOAT_BUF_LEN as u32), "float_buf");
+    let float_buf = builder.build_bitcast(float_buf, tys.i8ptr_t, "");
+    let fmt = builder
+        .build_global_string_ptr("%.*f", "strbuf.float_fmt")
+        .as_pointer_value();
+
+    let n = ret_as_bv!(builder.build_call(
+        fn_snprintf,
+        &[
+            float_buf.into(),
+            tys.size_t.const_int(FLOAT_BUF_LEN, false).into(),
+            fmt.into(),
+            precision.into(),
+            value.into(),
+        ],
+        "",
+    ))
+    .into_int_value();
+
+    let max_n = tys.i32_t.const_int(FLOAT_BUF_LEN - 1, false);
+    let is_truncated = builder.build_int_compare(IntPredicate::SGT, n, max_n, "");
+    let n = builder.build_select(is_truncated, max_n, n, "").into_int_value();
+    let n = builder.build_int_z_extend_or_bit_cast(n, tys.size_t, "");
+    builder.build_call(fn_push, &[buf.into(), float_buf.into(), n.into()], "");
+    builder.build_return(None);
+
+    ///////////////////////////////////
+    //// free
+
+    let (fn_buf_free, builder) = add_helper(
+        module,
+        "strbuf.free",
+        tys.void_t.fn_type(&[buf_ptr_t.into()], false),
+    );
+    let buf = fn_buf_free.get_nth_param(0).unwrap().into_pointer_value();
+
+    let blk_free = ctx.append_basic_block(fn_buf_free, "free");
+    let blk_done = ctx.append_basic_block(fn_buf_free, "done");
+
+    let (_, data) = load_data(&builder, buf);
+    let (_, heap) = load_int_field(&builder, buf, "heap");
+    builder.build_conditional_branch(heap, blk_free, blk_done);
+
+    builder.position_at_end(blk_free);
+    builder.build_call(fn_free, &[data.into()], "");
+    builder.build_unconditional_branch(blk_done);
+
+    builder.position_at_end(blk_done);
+    builder.build_return(None);
+}
+
+
+///////////////////////////////////////////////////////////////////////////
+//// String Buffer
+
+/// Builder at the start of current function, so the buffer in loop doesn't grow stack
+fn entry_builder<'ctx>(vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>) -> Builder<'ctx> {
+    let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+
+    let entry_builder = vmmod.builder();
+    builder_position_at_start(&entry_builder, fn_val.get_first_basic_block().unwrap());
+
+    entry_builder
+}
+
+/// Pointer to `%strbuf` in the entry block of current function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrBuf<'ctx> {
+    pub ptr: PointerValue<'ctx>,
+}
+
+impl<'ctx> StrBuf<'ctx> {
+    /// Buffer on heap of `cap` bytes initially (at least 1)
+    pub fn build_heap(vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, cap: IntValue<'ctx>) -> Self {
+        VMMod::include_stdlib(&vmmod.module);
+        let fn_malloc = vmmod.module.get_function("malloc").unwrap();
+
+        let usize_one = vmmod.usize(1);
+        let too_small = builder.build_int_compare(IntPredicate::ULT, cap, usize_one, "");
+        let cap = builder
+            .build_select(too_small, usize_one, cap, "")
+            .into_int_value();
+        let data = ret_as_bv!(builder.build_call(fn_malloc, &[cap.into()], "")).into_pointer_value();
+        build_abort_if_null(&vmmod.module, builder, data);
+
+        Self::build_init(vmmod, builder, data, cap, true)
+    }
+
+    /// Buffer on stack of `cap` bytes (at least 1), it moves to heap once it's full.
+    pub fn build_stack(vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, cap: u32) -> Self {
+        let cap = cap.max(1);
+
+        let data_t = vmmod.tys.i8_t.array_type(cap);
+        let data = entry_builder(vmmod, builder).build_alloca(data_t, "strbuf_data");
+        let data = builder
+            .build_bitcast(data, vmmod.tys.i8ptr_t, "")
+            .into_pointer_value();
+
+        Self::build_init(vmmod, builder, data, vmmod.usize(cap as usize), false)
+    }
+
+    fn build_init(
+        vmmod: &VMMod<'ctx>,
+        builder: &Builder<'ctx>,
+        data: PointerValue<'ctx>,
+        cap: IntValue<'ctx>,
+        heap: bool,
+    ) -> Self {
+        let ptr = entry_builder(vmmod, builder).build_alloca(strbuf_type(&vmmod.module), "strbuf");
+
+        builder.build_store(builder.build_struct_gep(ptr, 0, "").unwrap(), data);
+        builder.build_store(builder.build_struct_gep(ptr, 1, "").unwrap(), vmmod.usize(0));
+        builder.build_store(builder.build_struct_gep(ptr, 2, "").unwrap(), cap);
+        builder.build_store(
+            builder.build_struct_gep(ptr, 3, "").unwrap(),
+            vmmod.tys.i1_t.const_int(heap as u64, false),
+        );
+        builder.build_store(data, vmmod.tys.i8_t.const_zero());
+
+        Self { ptr }
+    }
+
+    pub fn build_append_str(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, s: StrValue<'ctx>) {
+        let fn_push = get_helper(&vmmod.module, "strbuf.push");
+
+        builder.build_call(fn_push, &[self.ptr.into(), s.ptr.into(), s.len.into()], "");
+    }
+
+    /// Append NUL-terminated `s`
+    pub fn build_append_cstr(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, s: PointerValue<'ctx>) {
+        VMMod::include_string(&vmmod.module);
+        let fn_strlen = vmmod.module.get_function("strlen").unwrap();
+
+        let len = ret_as_bv!(builder.build_call(fn_strlen, &[s.into()], ""));
+
+        self.build_append_str(vmmod, builder, StrValue(SliceValue::new(s, len.into_int_value())));
+    }
+
+    /// Append integer (at most 64 bits) in `base` of 2..=36, lowercase digits
+    pub fn build_append_int(
+        &self,
+        vmmod: &VMMod<'ctx>,
+        builder: &Builder<'ctx>,
+        value: IntValue<'ctx>,
+        base: u32,
+        signed: bool,
+    ) {
+        assert!((2..=36).contains(&base), "base {} isn't in 2..=36", base);
+        let fn_push_int = get_helper(&vmmod.module, "strbuf.push_int");
+
+        let value = builder.build_int_cast_sign_flag(value, vmmod.tys.i64_t, signed, "");
+
+        builder.build_call(
+            fn_push_int,
+            &[
+                self.ptr.into(),
+                value.into(),
+                vmmod.tys.i64_t.const_int(base as u64, false).into(),
+                vmmod.tys.i1_t.const_int(signed as u64, false).into(),
+            ],
+            "",
+        );
+    }
+
+    /// Append float in `%.<precision>f`
+    pub fn build_append_float(
+        &self,
+        vmmod: &VMMod<'ctx>,
+        builder: &Builder<'ctx>,
+        value: FloatValue<'ctx>,
+        precision: u32,
+    ) {
+        let fn_push_float = get_helper(&vmmod.module, "strbuf.push_float");
+
+        let value = builder.build_float_cast(value, vmmod.tys.f64_t, "");
+
+        builder.build_call(
+            fn_push_float,
+            &[self.ptr.into(), value.into(), vmmod.i32(precision as i32).into()],
+            "",
+        );
+    }
+
+    /// Append content of `other`, which may be `self`
+    pub fn build_concat(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>, other: &Self) {
+        let fn_reserve = get_helper(&vmmod.module, "strbuf.reserve");
+
+        // grow first, the data of `self` moves then
+        let (_, len) = load_int_field(builder, other.ptr, "len");
+        builder.build_call(fn_reserve, &[self.ptr.into(), len.into()], "");
+        let s = other.build_as_str(builder);
+
+        self.build_append_str(vmmod, builder, s);
+    }
+
+    /// Append `snprintf(fmt, values..)`, `values` are checked like `VMMod::build_call_printf_checked`.
+    ///
+    /// Return `i1` false if `snprintf` fails (e.g. invalid wide char), then nothing is appended.
+    pub fn build_format(
+        &self,
+        vmmod: &VMMod<'ctx>,
+        builder: &Builder<'ctx>,
+        fmt: &str,
+        values: &[BasicMetadataValueEnum<'ctx>],
+    ) -> CompileResult<IntValue<'ctx>> {
+        check_format(&vmmod.tys, fmt, values)?;
+
+        let fn_reserve = get_helper(&vmmod.module, "strbuf.reserve");
+        let fn_snprintf = vmmod.module.get_function("snprintf").unwrap();
+        let (fmt_ptr, _) = vmmod.build_local_str(builder, fmt);
+
+        // measure, reserve, then print
+        let mut args = vec![
+            vmmod.tys.i8ptr_t.const_null().into(),
+            vmmod.usize(0).into(),
+            fmt_ptr.into(),
+        ];
+        args.extend_from_slice(values);
+
+        let n = ret_as_bv!(builder.build_call(fn_snprintf, &args, "")).into_int_value();
+
+        // negative on error, it'd be a huge size once extended
+        let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+        let blk_print = vmmod.ctx.append_basic_block(fn_val, "format.print");
+        let blk_done = vmmod.ctx.append_basic_block(fn_val, "format.done");
+
+        let ok = builder.build_int_compare(IntPredicate::SGE, n, n.get_type().const_zero(), "ok");
+        builder.build_conditional_branch(ok, blk_print, blk_done);
+
+        builder.position_at_end(blk_print);
+        let n = builder.build_int_z_extend_or_bit_cast(n, vmmod.tys.size_t, "");
+        builder.build_call(fn_reserve, &[self.ptr.into(), n.into()], "");
+
+        let (_, data) = load_data(builder, self.ptr);
+        let (len_ptr, len) = load_int_field(builder, self.ptr, "len");
+
+        args[0] = unsafe { builder.build_in_bounds_gep(data, &[len], "") }.into();
+        args[1] = builder.build_int_add(n, vmmod.usize(1), "").into();
+        builder.build_call(fn_snprintf, &args, "");
+        builder.build_store(len_ptr, builder.build_int_add(len, n, ""));
+        builder.build_unconditional_branch(blk_done);
+
+        builder.position_at_end(blk_done);
+        Ok(ok)
+    }
+
+    /// Content as fat string, valid until the next append
+    pub fn build_as_str(&self, builder: &Builder<'ctx>) -> StrValue<'ctx> {
+        let (_, data) = load_data(builder, self.ptr);
+        let (_, len) = load_int_field(builder, self.ptr, "len");
+
+        StrValue(SliceValue::new(data, len))
+    }
+
+    /// Content as NUL-terminated `*i8`, valid until the next append
+    pub fn build_as_cstr(&self, builder: &Builder<'ctx>) -> PointerValue<'ctx> {
+        load_data(builder, self.ptr).1
+    }
+
+    /// Free the buffer if it's on heap
+    pub fn build_free(&self, vmmod: &VMMod<'ctx>, builder: &Builder<'ctx>) {
+        let fn_buf_free = get_helper(&vmmod.module, "strbuf.free");
+
+        builder.build_call(fn_buf_free, &[self.ptr.into()], "");
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, values::BasicMetadataValueEnum, AddressSpace};
+
+    use super::{check_format, StrBuf};
+    use crate::{
+        tests::{jit, test_fn},
+        CommonTypes, VMMod,
+    };
+
+    #[test]
+    fn test_check_format() {
+        let ctx = Context::create();
+        let tys = CommonTypes::get(&ctx);
+
+        let i8_v: BasicMetadataValueEnum = tys.i8_t.const_int(1, false).into();
+        let i32_v: BasicMetadataValueEnum = tys.i32_t.const_int(1, false).into();
+        let i64_v: BasicMetadataValueEnum = tys.i64_t.const_int(1, false).into();
+        let size_v: BasicMetadataValueEnum = tys.size_t.const_int(1, false).into();
+        let f64_v: BasicMetadataValueEnum = tys.f64_t.const_float(1.0).into();
+        let f32_v: BasicMetadataValueEnum = tys.f32_t.const_float(1.0).into();
+        let ptr_v: BasicMetadataValueEnum = tys.i8ptr_t.const_null().into();
+
+        for (fmt, values) in [
+            ("plain 100%%", vec![]),
+            ("%d %i %u %x %c", vec![i32_v, i32_v, i32_v, i32_v, i32_v]),
+            ("%hhd %hd", vec![i32_v, i32_v]),
+            ("%ld %lld %jx", vec![i64_v, i64_v, i64_v]),
+            ("%zu %td", vec![size_v, size_v]),
+            ("%-08.3f %e %lg", vec![f64_v, f64_v, f64_v]),
+            ("%*d %.*s", vec![i32_v, i32_v, i32_v, ptr_v]),
+            ("%s %p", vec![ptr_v, ptr_v]),
+        ] {
+            assert!(check_format(&tys, fmt, &values).is_ok(), "{}", fmt);
+        }
+
+        for (fmt, values, err) in [
+            ("%d", vec![i64_v], "expect i32"),
+            ("%d", vec![i8_v], "expect i32"),
+            ("%ld", vec![i32_v], "expect i64"),
+            ("%f", vec![f32_v], "expect double"),
+            ("%s", vec![i32_v], "expect pointer"),
+            ("%*d", vec![i64_v, i32_v], "expect i32 of `*`"),
+            ("%d %d", vec![i32_v], "missing argument"),
+            ("%d", vec![i32_v, i32_v], "too many arguments"),
+            ("%lhd", vec![i32_v], "unknown length"),
+            ("%Lf", vec![f64_v], "unsupported"),
+            ("%n", vec![ptr_v], "unsupported"),
+            ("%ls", vec![ptr_v], "unsupported"),
+            ("50%", vec![], "dangling"),
+        ] {
+            let e = check_format(&tys, fmt, &values).unwrap_err().to_string();
+            assert!(e.contains(err), "{}: {}", fmt, e);
+        }
+    }
+
+    #[test]
+    fn test_strbuf() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_strbuf");
+        let tys = vmmod.tys;
+
+        // (out*) -> len, -1 if the format failed
+        let (fn_val, builder) = test_fn(
+            &vmmod,
+            "test_strbuf",
+            tys.i64_t.fn_type(&[tys.i8_t.ptr_type(AddressSpace::Generic).into()], false),
+        );
+        let out = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+
+        // spills to heap
+        let buf = StrBuf::build_stack(&vmmod, &builder, 4);
+        buf.build_append_str(&vmmod, &builder, vmmod.build_local_str_value(&builder, "x="));
+        buf.build_append_int(&vmmod, &builder, tys.i32_t.const_int(-42i64 as u64, true), 10, true);
+        buf.build_append_cstr(&vmmod, &builder, vmmod.build_local_str(&builder, ",").0);
+        buf.build_append_int(&vmmod, &builder, tys.i8_t.const_int(255, false), 16, false);
+
+        let (ok_ptr, _) = vmmod.build_local_str(&builder, "ok");
+        let ok = buf
+            .build_format(&vmmod, &builder, " %d|%s|", &[vmmod.i32(7).into(), ok_ptr.into()])
+            .unwrap();
+        buf.build_append_float(&vmmod, &builder, vmmod.f64(1.5), 2);
+
+        let other = StrBuf::build_heap(&vmmod, &builder, vmmod.usize(0));
+        other.build_append_str(&vmmod, &builder, vmmod.build_local_str_value(&builder, "!"));
+        buf.build_concat(&vmmod, &builder, &other);
+        other.build_free(&vmmod, &builder);
+
+        let s = buf.build_as_str(&builder);
+        let size = builder.build_int_add(s.len, vmmod.usize(1), "");
+        vmmod.build_memcpy(&builder, out, buf.build_as_cstr(&builder), size, 1).unwrap();
+        buf.build_free(&vmmod, &builder);
+
+        let len = builder.build_int_z_extend_or_bit_cast(s.len, tys.i64_t, "");
+        let res = builder.build_select(ok, len, tys.i64_t.const_all_ones(), "");
+        builder.build_return(Some(&res));
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn(*mut u8) -> i64>("test_strbuf") }.unwrap();
+
+        let expect = b"x=-42,ff 7|ok|1.50!\0";
+        let mut out = [0xffu8; 64];
+        let len = unsafe { f.call(out.as_mut_ptr()) };
+
+        assert_eq!(len, expect.len() as i64 - 1);
+        assert_eq!(&out[..expect.len()], expect);
+    }
+
+    #[test]
+    fn test_concat_self() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_concat_self");
+        let tys = vmmod.tys;
+
+        // (out*) -> len
+        let (fn_val, builder) = test_fn(
+            &vmmod,
+            "test_concat_self",
+            tys.i64_t.fn_type(&[tys.i8_t.ptr_type(AddressSpace::Generic).into()], false),
+        );
+        let out = fn_val.get_nth_param(0).unwrap().into_pointer_value();
+
+        // both spill from stack and realloc on heap
+        let buf = StrBuf::build_stack(&vmmod, &builder, 4);
+        buf.build_append_str(&vmmod, &builder, vmmod.build_local_str_value(&builder, "abc"));
+        buf.build_concat(&vmmod, &builder, &buf);
+        buf.build_concat(&vmmod, &builder, &buf);
+
+        let s = buf.build_as_str(&builder);
+        let size = builder.build_int_add(s.len, vmmod.usize(1), "");
+        vmmod.build_memcpy(&builder, out, buf.build_as_cstr(&builder), size, 1).unwrap();
+        buf.build_free(&vmmod, &builder);
+
+        builder.build_return(Some(&builder.build_int_z_extend_or_bit_cast(s.len, tys.i64_t, "")));
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn(*mut u8) -> i64>("test_concat_self") }.unwrap();
+
+        let expect = b"abcabcabcabc\0";
+        let mut out = [0xffu8; 32];
+        let len = unsafe { f.call(out.as_mut_ptr()) };
+
+        assert_eq!(len, expect.len() as i64 - 1);
+        assert_eq!(&out[..expect.len()], expect);
+    }
+}
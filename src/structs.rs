@@ -0,0 +1,342 @@
+//! Named struct descriptor: field access by name, packed/aligned layouts
+//! and size/offset queries from the target's `TargetData`.
+
+use inkwell::{
+    builder::Builder,
+    targets::TargetData,
+    types::{BasicTypeEnum, StructType},
+    values::{BasicValue, BasicValueEnum, PointerValue, StructValue},
+};
+
+use crate::{compiler::CompileResult, VMMod};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructLayout {
+    /// C layout
+    Natural,
+    /// No padding between fields, alignment 1
+    Packed,
+    /// C layout, but aligned to (power of two) bytes at least,
+    /// the size is padded to a multiple of it.
+    ///
+    /// A leading `[0 x <N x i8>]` raises the alignment of the LLVM type,
+    /// so it holds in arrays, nested structs and heap allocations too.
+    Aligned(u32),
+}
+
+pub struct StructDesc<'ctx> {
+    pub name: String,
+    pub struct_t: StructType<'ctx>,
+    pub layout: StructLayout,
+    pub fields: Vec<String>,
+    /// Index of the first field in `struct_t`, 1 after the leading member of `Aligned`
+    first: u32,
+    /// Alignment of load/store of each field, None for the ABI one
+    field_aligns: Vec<Option<u32>>,
+    /// Alignment of the whole struct, None for the ABI one
+    align: Option<u32>,
+}
+
+/// Largest power of two dividing `n` (`0` is divided by anything)
+fn pow2_factor(n: u64, max: u32) -> u32 {
+    if n == 0 {
+        max
+    } else {
+        (1u64 << n.trailing_zeros()).min(max as u64) as u32
+    }
+}
+
+impl<'ctx> StructDesc<'ctx> {
+    /// Define named struct `name` in the context of `vmmod`,
+    /// same definition can be made repeatedly.
+    pub fn define(
+        vmmod: &VMMod<'ctx>,
+        target_data: &TargetData,
+        name: &str,
+        fields: &[(&str, BasicTypeEnum<'ctx>)],
+        layout: StructLayout,
+    ) -> CompileResult<Self> {
+        for (i, (fname, _)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(prev, _)| prev == fname) {
+                return Err(format!("struct {}: duplicate field {}", name, fname).into());
+            }
+        }
+
+        let mut field_tys = fields.iter().map(|(_, ty)| *ty).collect::<Vec<_>>();
+        let packed = layout == StructLayout::Packed;
+        let mut first = 0;
+
+        if let StructLayout::Aligned(align) = layout {
+            if !align.is_power_of_two() {
+                return Err(format!("struct {}: alignment {} isn't power of two", name, align).into());
+            }
+
+            // vector of i8 is aligned to its size, and the array of none takes no room
+            let align_t = vmmod.tys.i8_t.vec_type(align).array_type(0);
+            if target_data.get_abi_alignment(&align_t) != align {
+                return Err(format!("struct {}: alignment {} isn't supported by the target", name, align).into());
+            }
+
+            field_tys.insert(0, align_t.into());
+            first = 1;
+        }
+
+        let struct_t = vmmod.define_struct(name, &field_tys, packed)?;
+
+        let (field_aligns, align) = match layout {
+            StructLayout::Natural => (vec![None; fields.len()], None),
+            StructLayout::Packed => (vec![Some(1); fields.len()], Some(1)),
+            StructLayout::Aligned(align) => {
+                let align = align.max(target_data.get_abi_alignment(&struct_t));
+                let field_aligns = fields
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, ty))| {
+                        let offset = target_data.offset_of_element(&struct_t, first + i as u32).unwrap();
+
+                        Some(pow2_factor(offset, align).max(target_data.get_abi_alignment(ty)))
+                    })
+                    .collect();
+
+                (field_aligns, Some(align))
+            }
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            struct_t,
+            layout,
+            fields: fields.iter().map(|(fname, _)| fname.to_string()).collect(),
+            first,
+            field_aligns,
+            align,
+        })
+    }
+
+    /// Index of field `field` in `struct_t`, panic if there's none.
+    pub fn index(&self, field: &str) -> u32 {
+        self.fields
+            .iter()
+            .position(|fname| fname == field)
+            .unwrap_or_else(|| panic!("struct {} has no field {}", self.name, field))
+            as u32
+            + self.first
+    }
+
+    pub fn field_type(&self, field: &str) -> BasicTypeEnum<'ctx> {
+        self.struct_t.get_field_type_at_index(self.index(field)).unwrap()
+    }
+
+    ///////////////////////////////////
+    //// Layout
+
+    pub fn size_of(&self, target_data: &TargetData) -> u64 {
+        target_data.get_abi_size(&self.struct_t)
+    }
+
+    pub fn align_of(&self, target_data: &TargetData) -> u32 {
+        self.align
+            .unwrap_or_else(|| target_data.get_abi_alignment(&self.struct_t))
+    }
+
+    pub fn offset_of(&self, target_data: &TargetData, field: &str) -> u64 {
+        target_data
+            .offset_of_element(&self.struct_t, self.index(field))
+            .unwrap()
+    }
+
+    ///////////////////////////////////
+    //// Build
+
+    pub fn build_alloca(&self, builder: &Builder<'ctx>, name: &str) -> PointerValue<'ctx> {
+        let var = builder.build_alloca(self.struct_t, name);
+
+        if let Some(align) = self.align {
+            var.as_instruction_value().unwrap().set_alignment(align).unwrap();
+        }
+
+        var
+    }
+
+    pub fn build_gep(
+        &self,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        field: &str,
+    ) -> PointerValue<'ctx> {
+        builder
+            .build_struct_gep(ptr, self.index(field), field)
+            .unwrap()
+    }
+
+    pub fn build_load(
+        &self,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        field: &str,
+    ) -> BasicValueEnum<'ctx> {
+        let field_ptr = self.build_gep(builder, ptr, field);
+        let value = builder.build_load(field_ptr, "");
+
+        if let Some(align) = self.field_aligns[(self.index(field) - self.first) as usize] {
+            value.as_instruction_value().unwrap().set_alignment(align).unwrap();
+        }
+
+        value
+    }
+
+    pub fn build_store<V: BasicValue<'ctx>>(
+        &self,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        field: &str,
+        value: V,
+    ) {
+        let field_ptr = self.build_gep(builder, ptr, field);
+        let store = builder.build_store(field_ptr, value);
+
+        if let Some(align) = self.field_aligns[(self.index(field) - self.first) as usize] {
+            store.set_alignment(align).unwrap();
+        }
+    }
+
+    /// Constant struct from fields by name in any order, all fields are required.
+    pub fn const_value(
+        &self,
+        values: &[(&str, BasicValueEnum<'ctx>)],
+    ) -> CompileResult<StructValue<'ctx>> {
+        let mut ordered = vec![None; self.fields.len()];
+
+        for (field, value) in values {
+            let i = match self.fields.iter().position(|fname| fname == field) {
+                Some(i) => i,
+                None => {
+                    return Err(format!("struct {} has no field {}", self.name, field).into())
+                }
+            };
+
+            if value.get_type() != self.struct_t.get_field_type_at_index(self.first + i as u32).unwrap() {
+                return Err(format!("struct {}: mismatched type of field {}", self.name, field).into());
+            }
+            if ordered[i].replace(*value).is_some() {
+                return Err(format!("struct {}: field {} is given twice", self.name, field).into());
+            }
+        }
+
+        let mut ordered = ordered
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| {
+                value.ok_or_else(|| format!("struct {}: missing field {}", self.name, self.fields[i]))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // leading member of `Aligned`
+        if self.first > 0 {
+            let align_t = self.struct_t.get_field_type_at_index(0).unwrap();
+            ordered.insert(0, align_t.into_array_type().const_zero().into());
+        }
+
+        Ok(self.struct_t.const_named_struct(&ordered))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, types::BasicTypeEnum};
+
+    use super::{StructDesc, StructLayout};
+    use crate::{
+        compiler::host_target_data,
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_layouts() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_layouts");
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+
+        let fields: [(&str, BasicTypeEnum); 3] =
+            [("a", tys.i8_t.into()), ("b", tys.i32_t.into()), ("c", tys.i64_t.into())];
+
+        for (name, layout, size, align, offsets) in [
+            ("natural", StructLayout::Natural, 16, 8, [0, 4, 8]),
+            ("packed", StructLayout::Packed, 13, 1, [0, 1, 5]),
+            ("aligned", StructLayout::Aligned(32), 32, 32, [0, 4, 8]),
+        ] {
+            let desc = StructDesc::define(&vmmod, &target_data, name, &fields, layout).unwrap();
+
+            assert_eq!(desc.size_of(&target_data), size, "{}", name);
+            assert_eq!(desc.align_of(&target_data), align, "{}", name);
+            assert_eq!(target_data.get_abi_alignment(&desc.struct_t), align, "{}", name);
+            for ((field, _), offset) in fields.iter().zip(offsets) {
+                assert_eq!(desc.offset_of(&target_data, field), offset, "{}.{}", name, field);
+            }
+
+            // defining again is fine
+            StructDesc::define(&vmmod, &target_data, name, &fields, layout).unwrap();
+        }
+
+        // the alignment holds in arrays and nested structs
+        let aligned_t = vmmod.get_struct("aligned").unwrap();
+        assert_eq!(target_data.get_abi_size(&aligned_t.array_type(3)), 3 * 32);
+
+        let outer_t = ctx.struct_type(&[tys.i8_t.into(), aligned_t.into()], false);
+        assert_eq!(target_data.offset_of_element(&outer_t, 1), Some(32));
+
+        for layout in [StructLayout::Aligned(24), StructLayout::Packed] {
+            assert!(StructDesc::define(&vmmod, &target_data, "aligned", &fields, layout).is_err());
+        }
+    }
+
+    #[test]
+    fn test_struct_desc() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_struct_desc");
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+
+        let desc = StructDesc::define(
+            &vmmod,
+            &target_data,
+            "point",
+            &[("x", tys.i32_t.into()), ("y", tys.i64_t.into())],
+            StructLayout::Aligned(64),
+        )
+        .unwrap();
+
+        // () -> y of a constant stored to an alloca, then x of the last element of
+        // a heap array, plus its misalignment to 64 bytes
+        let (_, builder) = test_fn(&vmmod, "test_struct_desc", tys.i64_t.fn_type(&[], false));
+
+        let value = desc
+            .const_value(&[("y", tys.i64_t.const_int(300, false).into()), ("x", vmmod.i32(2).into())])
+            .unwrap();
+        let var = desc.build_alloca(&builder, "var");
+        builder.build_store(var, value);
+        let y = desc.build_load(&builder, var, "y").into_int_value();
+
+        let arr = vmmod.build_array_malloc(&builder, &target_data, desc.struct_t.into(), vmmod.usize(3), "arr");
+        let last = unsafe { builder.build_in_bounds_gep(arr, &[vmmod.usize(2)], "") };
+        desc.build_store(&builder, last, "x", vmmod.i32(40));
+        let x = desc.build_load(&builder, last, "x").into_int_value();
+        let x = builder.build_int_s_extend(x, tys.i64_t, "");
+
+        let addr = builder.build_ptr_to_int(last, tys.i64_t, "");
+        let misalign = builder.build_and(addr, tys.i64_t.const_int(63, false), "");
+        vmmod.build_free(&builder, arr);
+
+        let res = builder.build_int_add(builder.build_int_add(y, x, ""), misalign, "");
+        builder.build_return(Some(&res));
+
+        let ee = jit(&vmmod);
+        let f = unsafe { ee.get_function::<unsafe extern "C" fn() -> i64>("test_struct_desc") }.unwrap();
+
+        assert_eq!(unsafe { f.call() }, 340);
+    }
+}
@@ -0,0 +1,445 @@
+//! Rust-like enums lowered to `{ tag, payload }` structs
+//!
+//! The payload is a struct of the most aligned field type padded with bytes
+//! to the largest variant, variants are viewed through pointer casts.
+
+use inkwell::{
+    attributes::{Attribute, AttributeLoc},
+    basic_block::BasicBlock,
+    builder::Builder,
+    module::{Linkage, Module},
+    targets::TargetData,
+    types::{BasicTypeEnum, FunctionType, IntType, StructType},
+    values::{
+        BasicMetadataValueEnum, BasicValueEnum, FunctionValue, IntValue,
+        PointerValue, StructValue,
+    },
+    AddressSpace,
+};
+
+use crate::{get_or_create_struct_type, let_module_ctx, ret_as_bv};
+
+
+pub struct Variant<'ctx> {
+    pub name: String,
+    /// literal struct of the fields
+    pub payload_t: StructType<'ctx>,
+}
+
+/// Tagged union named `name` with the generated helpers in module:
+///
+/// - `<name>.new.<Variant>(fields..) -> %<name>`
+/// - `<name>.tag(%<name>*) -> tag`
+/// - `<name>.as.<Variant>(%<name>*) -> { fields.. }*`
+pub struct TaggedUnion<'ctx> {
+    pub name: String,
+    pub struct_t: StructType<'ctx>,
+    pub tag_t: IntType<'ctx>,
+    pub variants: Vec<Variant<'ctx>>,
+}
+
+impl<'ctx> TaggedUnion<'ctx> {
+    /// Define the type and its helpers, it's fine to define the same enum again.
+    /// Panic if `name` is a struct of other fields.
+    ///
+    /// Tag of each variant is its index in `variants`.
+    pub fn define(
+        module: &Module<'ctx>,
+        target_data: &TargetData,
+        name: &str,
+        variants: &[(&str, &[BasicTypeEnum<'ctx>])],
+    ) -> Self {
+        let_module_ctx!(ctx = module);
+
+        let tag_t = match variants.len() {
+            0..=0x100 => ctx.i8_type(),
+            0x101..=0x10000 => ctx.i16_type(),
+            _ => ctx.i32_type(),
+        };
+
+        let variants = variants
+            .iter()
+            .map(|(vname, fields)| Variant {
+                name: vname.to_string(),
+                payload_t: ctx.struct_type(fields, false),
+            })
+            .collect::<Vec<_>>();
+
+        let size = variants
+            .iter()
+            .map(|v| target_data.get_abi_size(&v.payload_t))
+            .max()
+            .unwrap_or(0);
+
+        // an integer as wide as the alignment may be less aligned (i128 is 8 on x86_64)
+        let align_t = variants
+            .iter()
+            .flat_map(|v| v.payload_t.get_field_types())
+            .max_by_key(|field_t| target_data.get_abi_alignment(field_t));
+
+        let payload_t = match align_t {
+            Some(align_t) => {
+                let pad = size - target_data.get_abi_size(&align_t);
+                ctx.struct_type(&[align_t, ctx.i8_type().array_type(pad as u32).into()], false)
+            }
+            None => ctx.struct_type(&[], false),
+        };
+
+        let struct_t = get_or_create_struct_type(
+            ctx,
+            module,
+            name,
+            Some(&[tag_t.into(), payload_t.into()]),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let tagged = Self {
+            name: name.to_owned(),
+            struct_t,
+            tag_t,
+            variants,
+        };
+
+        if module.get_function(&tagged.tag_fn_name()).is_none() {
+            tagged.gen_helpers(module);
+        }
+
+        tagged
+    }
+
+    fn tag_fn_name(&self) -> String {
+        format!("{}.tag", self.name)
+    }
+
+    fn new_fn_name(&self, variant: usize) -> String {
+        format!("{}.new.{}", self.name, self.variants[variant].name)
+    }
+
+    fn as_fn_name(&self, variant: usize) -> String {
+        format!("{}.as.{}", self.name, self.variants[variant].name)
+    }
+
+    fn add_helper_fn(
+        &self,
+        module: &Module<'ctx>,
+        name: &str,
+        fn_t: FunctionType<'ctx>,
+    ) -> (FunctionValue<'ctx>, Builder<'ctx>) {
+        let_module_ctx!(ctx = module);
+
+        let fn_val = module.add_function(name, fn_t, Some(Linkage::Internal));
+        fn_val.add_attribute(
+            AttributeLoc::Function,
+            ctx.create_enum_attribute(Attribute::get_named_enum_kind_id("alwaysinline"), 0),
+        );
+
+        let builder = ctx.create_builder();
+        builder.position_at_end(ctx.append_basic_block(fn_val, "entry"));
+
+        (fn_val, builder)
+    }
+
+    fn gen_helpers(&self, module: &Module<'ctx>) {
+        let ptr_t = self.struct_t.ptr_type(AddressSpace::Generic);
+
+        // tag
+        let (fn_tag, builder) = self.add_helper_fn(
+            module,
+            &self.tag_fn_name(),
+            self.tag_t.fn_type(&[ptr_t.into()], false),
+        );
+        let this = fn_tag.get_nth_param(0).unwrap().into_pointer_value();
+        let tag_ptr = builder.build_struct_gep(this, 0, "tag_ptr").unwrap();
+        builder.build_return(Some(&builder.build_load(tag_ptr, "tag")));
+
+        for (i, variant) in self.variants.iter().enumerate() {
+            let payload_ptr_t = variant.payload_t.ptr_type(AddressSpace::Generic);
+
+            // projection
+            let (fn_as, builder) = self.add_helper_fn(
+                module,
+                &self.as_fn_name(i),
+                payload_ptr_t.fn_type(&[ptr_t.into()], false),
+            );
+            let this = fn_as.get_nth_param(0).unwrap().into_pointer_value();
+            let payload = builder.build_struct_gep(this, 1, "payload").unwrap();
+            let payload = builder.build_bitcast(payload, payload_ptr_t, "");
+            builder.build_return(Some(&payload));
+
+            // constructor
+            let field_tys = variant
+                .payload_t
+                .get_field_types()
+                .into_iter()
+                .map(|field_t| field_t.into())
+                .collect::<Vec<_>>();
+            let (fn_new, builder) = self.add_helper_fn(
+                module,
+                &self.new_fn_name(i),
+                self.struct_t.fn_type(&field_tys, false),
+            );
+
+            let var = builder.build_alloca(self.struct_t, "var");
+            let tag_ptr = builder.build_struct_gep(var, 0, "tag_ptr").unwrap();
+            builder.build_store(tag_ptr, self.tag_t.const_int(i as u64, false));
+
+            let payload = ret_as_bv!(builder.build_call(fn_as, &[var.into()], "payload"))
+                .into_pointer_value();
+            for (j, param) in fn_new.get_param_iter().enumerate() {
+                let field_ptr = builder.build_struct_gep(payload, j as u32, "").unwrap();
+                builder.build_store(field_ptr, param);
+            }
+
+            builder.build_return(Some(&builder.build_load(var, "")));
+        }
+    }
+
+    /// Index of the variant named `name`, panic if there's none.
+    pub fn variant_index(&self, name: &str) -> usize {
+        self.variants
+            .iter()
+            .position(|v| v.name == name)
+            .unwrap_or_else(|| panic!("enum {} has no variant {}", self.name, name))
+    }
+
+    fn helper(&self, module: &Module<'ctx>, name: &str) -> FunctionValue<'ctx> {
+        module
+            .get_function(name)
+            .unwrap_or_else(|| panic!("{} isn't defined in module", name))
+    }
+
+    /// Construct variant `variant` by value
+    pub fn build_new(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        variant: &str,
+        fields: &[BasicValueEnum<'ctx>],
+    ) -> StructValue<'ctx> {
+        let i = self.variant_index(variant);
+        let fn_new = self.helper(module, &self.new_fn_name(i));
+
+        let args = fields
+            .iter()
+            .map(|field| (*field).into())
+            .collect::<Vec<BasicMetadataValueEnum>>();
+
+        ret_as_bv!(builder.build_call(fn_new, &args, "")).into_struct_value()
+    }
+
+    pub fn build_tag(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        let fn_tag = self.helper(module, &self.tag_fn_name());
+
+        ret_as_bv!(builder.build_call(fn_tag, &[ptr.into()], "tag")).into_int_value()
+    }
+
+    /// Pointer to the payload struct of `variant`, doesn't check the tag
+    pub fn build_payload(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        variant: &str,
+    ) -> PointerValue<'ctx> {
+        let i = self.variant_index(variant);
+        let fn_as = self.helper(module, &self.as_fn_name(i));
+
+        ret_as_bv!(builder.build_call(fn_as, &[ptr.into()], "")).into_pointer_value()
+    }
+
+    /// Pointer to the `idx`th field of `variant`, doesn't check the tag
+    pub fn build_payload_field(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        variant: &str,
+        idx: u32,
+    ) -> PointerValue<'ctx> {
+        let payload = self.build_payload(module, builder, ptr, variant);
+
+        builder.build_struct_gep(payload, idx, "").unwrap()
+    }
+
+    /// `switch` over the tag of `ptr`, `arm` is called with each variant index
+    /// and its payload pointer in the arm's own block.
+    ///
+    /// Unterminated arms fall to the returned block, builder is positioned at it.
+    pub fn build_switch<F>(
+        &self,
+        module: &Module<'ctx>,
+        builder: &Builder<'ctx>,
+        ptr: PointerValue<'ctx>,
+        mut arm: F,
+    ) -> BasicBlock<'ctx>
+    where
+        F: FnMut(&Builder<'ctx>, usize, PointerValue<'ctx>),
+    {
+        let_module_ctx!(ctx = module);
+        let fn_val = builder.get_insert_block().unwrap().get_parent().unwrap();
+
+        let tag = self.build_tag(module, builder, ptr);
+
+        let blk_end = ctx.append_basic_block(fn_val, "match.end");
+        let blk_else = ctx.append_basic_block(fn_val, "match.unreachable");
+
+        let arms = self
+            .variants
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let blk = ctx.append_basic_block(fn_val, &format!("match.{}", v.name));
+                blk_end.move_after(blk).unwrap();
+
+                (self.tag_t.const_int(i as u64, false), blk)
+            })
+            .collect::<Vec<_>>();
+
+        builder.build_switch(tag, blk_else, &arms);
+
+        builder.position_at_end(blk_else);
+        builder.build_unreachable();
+
+        for (i, (_, blk)) in arms.into_iter().enumerate() {
+            builder.position_at_end(blk);
+            let payload = self.build_payload(module, builder, ptr, &self.variants[i].name);
+
+            arm(builder, i, payload);
+
+            if builder.get_insert_block().unwrap().get_terminator().is_none() {
+                builder.build_unconditional_branch(blk_end);
+            }
+        }
+
+        builder.position_at_end(blk_end);
+        blk_end
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inkwell::{context::Context, types::BasicTypeEnum, values::BasicValueEnum};
+
+    use super::TaggedUnion;
+    use crate::{
+        compiler::host_target_data,
+        tests::{jit, test_fn},
+        VMMod,
+    };
+
+    #[test]
+    fn test_payload_align() {
+        let ctx = Context::create();
+        let module = ctx.create_module("test_tagged");
+        let target_data = host_target_data().unwrap();
+
+        let i8_t: BasicTypeEnum = ctx.i8_type().into();
+        let i128_t: BasicTypeEnum = ctx.i128_type().into();
+        let f64_t: BasicTypeEnum = ctx.f64_type().into();
+        let v4_t: BasicTypeEnum = ctx.i32_type().vec_type(4).into();
+
+        let tagged = TaggedUnion::define(
+            &module,
+            &target_data,
+            "Value",
+            &[
+                ("Byte", &[i8_t]),
+                ("Wide", &[i8_t, i128_t]),
+                ("Pair", &[f64_t, v4_t]),
+                ("Unit", &[]),
+            ],
+        );
+
+        let payload_t = tagged.struct_t.get_field_type_at_index(1).unwrap();
+        let align = target_data.get_abi_alignment(&payload_t);
+
+        for v in tagged.variants.iter() {
+            assert!(target_data.get_abi_alignment(&v.payload_t) <= align);
+            assert!(target_data.get_abi_size(&v.payload_t) <= target_data.get_abi_size(&payload_t));
+
+            for field_t in v.payload_t.get_field_types() {
+                assert!(target_data.get_abi_alignment(&field_t) <= align);
+            }
+        }
+        assert_eq!(target_data.get_abi_alignment(&v4_t), align);
+
+        // no fields at all
+        let empty = TaggedUnion::define(&module, &target_data, "Empty", &[("A", &[]), ("B", &[])]);
+        let payload_t = empty.struct_t.get_field_type_at_index(1).unwrap();
+        assert_eq!(target_data.get_abi_size(&payload_t), 0);
+
+        module.verify().unwrap();
+    }
+
+    #[test]
+    fn test_tagged() {
+        let ctx = Context::create();
+        let vmmod = VMMod::new_in(&ctx, "test_tagged");
+        let module = &vmmod.module;
+        let tys = vmmod.tys;
+        let target_data = host_target_data().unwrap();
+
+        let tagged = TaggedUnion::define(
+            module,
+            &target_data,
+            "Shape",
+            &[
+                ("Int", &[tys.i64_t.into()]),
+                ("Pair", &[tys.i32_t.into(), tys.f64_t.into()]),
+                ("None", &[]),
+            ],
+        );
+
+        // construct the variant, then match it: Int(v) => v, Pair(a, b) => a + b, None => -1
+        for (name, variant) in [("test_int", "Int"), ("test_pair", "Pair"), ("test_none", "None")] {
+            let (_, builder) = test_fn(&vmmod, name, tys.i64_t.fn_type(&[], false));
+
+            let fields: Vec<BasicValueEnum> = match variant {
+                "Int" => vec![tys.i64_t.const_int(42, false).into()],
+                "Pair" => vec![vmmod.i32(3).into(), vmmod.f64(4.0).into()],
+                _ => vec![],
+            };
+            let value = tagged.build_new(module, &builder, variant, &fields);
+            let ptr = builder.build_alloca(tagged.struct_t, "value");
+            builder.build_store(ptr, value);
+
+            let res = builder.build_alloca(tys.i64_t, "res");
+            tagged.build_switch(module, &builder, ptr, |builder, i, payload| {
+                let value = match tagged.variants[i].name.as_str() {
+                    "Int" => {
+                        let v = builder.build_struct_gep(payload, 0, "").unwrap();
+                        builder.build_load(v, "").into_int_value()
+                    }
+                    "Pair" => {
+                        let a = tagged.build_payload_field(module, builder, ptr, "Pair", 0);
+                        let b = tagged.build_payload_field(module, builder, ptr, "Pair", 1);
+                        let a = builder.build_int_s_extend(builder.build_load(a, "").into_int_value(), tys.i64_t, "");
+                        let b = builder.build_float_to_signed_int(builder.build_load(b, "").into_float_value(), tys.i64_t, "");
+                        builder.build_int_add(a, b, "")
+                    }
+                    _ => tys.i64_t.const_all_ones(),
+                };
+                builder.build_store(res, value);
+            });
+
+            let tag = builder.build_int_z_extend(tagged.build_tag(module, &builder, ptr), tys.i64_t, "");
+            let res = builder.build_load(res, "").into_int_value();
+            let res = builder.build_int_add(builder.build_int_mul(res, tys.i64_t.const_int(10, false), ""), tag, "");
+            builder.build_return(Some(&res));
+        }
+
+        let ee = jit(&vmmod);
+
+        for (name, expect) in [("test_int", 420), ("test_pair", 71), ("test_none", -8)] {
+            let f = unsafe { ee.get_function::<unsafe extern "C" fn() -> i64>(name) }.unwrap();
+
+            assert_eq!(unsafe { f.call() }, expect, "{}", name);
+        }
+    }
+}